@@ -0,0 +1,72 @@
+// Runs a few collectives one after another and then shuts the switch down
+// gracefully, which lets everything in flight finish first.
+extern crate futures;
+extern crate mpi;
+extern crate mpi_futures;
+extern crate synchrotron;
+
+use futures::Future;
+use futures::future::Either;
+use mpi::topology::Communicator;
+use mpi_futures::link::LinkExt;
+use mpi_futures::operation::SystemOperation;
+use mpi_futures::switch::Switch;
+
+fn main() {
+    let universe = mpi::initialize().unwrap();
+    let world = universe.world();
+    let mut core = synchrotron::Core::default();
+    let switch = Switch::default();
+    let link = switch.link();
+    let handle = core.handle();
+    let my_rank = world.rank();
+    let comm_size = world.size();
+    let root = 0;
+    handle.spawn(switch);
+    core.run(
+        link.barrier(world)
+            .and_then(|()| {
+                link.allreduce(world, SystemOperation::sum(),
+                               vec![my_rank], vec![0])
+            })
+            .and_then(|(_, sum)| {
+                println!("{}: sum of all ranks is {}", my_rank, sum[0]);
+                // the process of rank i gets i + 1 copies of our rank
+                let send = (0 .. comm_size)
+                    .map(|i| vec![my_rank; i as usize + 1])
+                    .collect();
+                link.alltoallv(world, send)
+            })
+            .and_then(|received| {
+                println!("{}: received {:?} from everyone",
+                         my_rank, received.data());
+                let send = vec![my_rank; my_rank as usize];
+                if my_rank == root {
+                    Either::A(link.gatherv(world, send).map(|(_, gathered)| {
+                        println!("{}: gathered {} parts at offsets {:?}",
+                                 my_rank,
+                                 gathered.len(),
+                                 gathered.offsets());
+                    }))
+                } else {
+                    Either::B(link.gatherv_to(world, root, send).map(|_| ()))
+                }
+            })
+            .and_then(|()| {
+                if my_rank == root {
+                    // the process of rank i gets the numbers below i
+                    let send = (0 .. comm_size)
+                        .map(|i| (0 .. i).collect())
+                        .collect();
+                    link.scatterv_from(world, send)
+                } else {
+                    link.scatterv(world, root)
+                }
+            })
+            .map(|chunk: Vec<i32>| {
+                println!("{}: scattered chunk {:?}", my_rank, chunk);
+            })
+    ).unwrap();
+    core.run(link.shutdown()).unwrap();
+    println!("{}: switch has shut down", my_rank);
+}
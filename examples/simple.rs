@@ -5,6 +5,8 @@ extern crate synchrotron;
 
 use futures::{Future, Stream};
 use mpi::topology::Communicator;
+use mpi_futures::error;
+use mpi_futures::link::LinkExt;
 use mpi_futures::switch::Switch;
 use mpi_futures::codec::U8Codec;
 
@@ -12,6 +14,9 @@ fn main() {
     let universe = mpi::initialize().unwrap();
     let world = universe.world();
     let comm = world.duplicate();
+    // created before the switch, so it doesn't inherit the error handler
+    // that the switch installs on MPI_COMM_WORLD
+    error::set_errors_return(&comm).unwrap();
     let mut core = synchrotron::Core::default();
     let switch = Switch::default();
     let link = switch.link();
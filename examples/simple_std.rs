@@ -0,0 +1,49 @@
+// Variant of 'simple.rs' that drives everything through std::future using
+// the futures 0.3 executor.
+extern crate futures03;
+extern crate mpi;
+extern crate mpi_futures;
+
+use futures03::{executor, future, FutureExt, StreamExt, TryStreamExt};
+use mpi::topology::Communicator;
+use mpi_futures::codec::U8Codec;
+use mpi_futures::error;
+use mpi_futures::link::LinkExt;
+use mpi_futures::std_future::{IntoStdFuture, IntoStdStream};
+use mpi_futures::switch::Switch;
+
+fn main() {
+    let universe = mpi::initialize().unwrap();
+    let world = universe.world();
+    let comm = world.duplicate();
+    // created before the switch, so it doesn't inherit the error handler
+    // that the switch installs on MPI_COMM_WORLD
+    error::set_errors_return(&comm).unwrap();
+    let switch: Switch<()> = Switch::default();
+    let link = switch.link();
+    let my_rank = comm.rank();
+    let comm_size = comm.size();
+    let target_rank = (my_rank + 1) % comm_size;
+    let send = link.send(U8Codec,
+                         comm.process_at_rank(target_rank),
+                         Vec::from(b"hello world" as &[u8]))
+        .into_std()
+        .map(move |_| {
+            println!("{}: sent to {}!", my_rank, target_rank)
+        });
+    let recv = link.incoming(U8Codec, comm.any_process())
+        .into_std()
+        .try_buffered(1)
+        .into_future()
+        .map(|(item, _)| {
+            let (status, msg) = item.unwrap().unwrap();
+            println!("{}: received {:?} from {}",
+                     my_rank,
+                     String::from_utf8(msg).unwrap(),
+                     status.source_rank());
+            link.close();
+        });
+    let (result, (), ()) =
+        executor::block_on(future::join3(switch.into_std(), send, recv));
+    result.unwrap();
+}
@@ -7,6 +7,7 @@ extern crate tokio_core;
 
 use futures::{Future, Stream};
 use mpi::topology::Communicator;
+use mpi_futures::link::LinkExt;
 use mpi_futures::switch::Switch;
 use mpi_futures::codec::U8Codec;
 
@@ -0,0 +1,72 @@
+//! Waking up a switch that is blocked in `MPI_Waitsome`.
+//!
+//! A blocked switch only returns once one of its requests completes, so the
+//! links of a thread-safe switch complete an alarm (a generalized request)
+//! whenever they hand it something new to do.
+
+use std::{marker, mem, ptr};
+use std::sync::Mutex;
+use libc;
+use mpi;
+use mpi::datatype::Equivalence;
+use mpi::raw::AsRaw;
+use super::request_poll::{OrAbort, RequestPoll};
+
+unsafe extern "C" fn alarm_query(_: *mut libc::c_void,
+                                 status: *mut mpi::ffi::MPI_Status)
+                                 -> libc::c_int {
+    let datatype = u8::equivalent_datatype().as_raw();
+    mpi::ffi::MPI_Status_set_elements(status, datatype, 0);
+    mpi::ffi::MPI_Status_set_cancelled(status, 0)
+}
+
+unsafe extern "C" fn alarm_free(_: *mut libc::c_void) -> libc::c_int {
+    0
+}
+
+unsafe extern "C" fn alarm_cancel(_: *mut libc::c_void, _: libc::c_int)
+                                  -> libc::c_int {
+    0
+}
+
+/// A generalized request that can be completed from any thread in order to
+/// wake up a switch while it's blocked in `MPI_Waitsome`.
+#[derive(Debug, Default)]
+pub(crate) struct Alarm(Mutex<Option<mpi::ffi::MPI_Request>>);
+
+// Safe because MPI_THREAD_MULTIPLE allows the request to be completed from
+// any thread, and the mutex ensures it is completed at most once.
+unsafe impl marker::Send for Alarm {}
+unsafe impl marker::Sync for Alarm {}
+
+impl Alarm {
+    /// Insert a fresh request into the `RequestPoll` unless the previous one
+    /// is still pending.
+    pub fn arm(&self, request_poll: &mut RequestPoll) {
+        let mut slot = self.0.lock().unwrap();
+        if slot.is_some() {
+            return;
+        }
+        request_poll.reserve_one();     // may panic
+        unsafe {
+            let mut request = mem::uninitialized();
+            mpi::ffi::MPI_Grequest_start(Some(alarm_query),
+                                         Some(alarm_free),
+                                         Some(alarm_cancel),
+                                         ptr::null_mut(),
+                                         &mut request).or_abort();
+            request_poll.insert_internal(request, |_| ());
+            *slot = Some(request);
+        }
+    }
+
+    /// Complete the pending request, if any.
+    pub fn ring(&self) {
+        let request = self.0.lock().unwrap().take();
+        if let Some(request) = request {
+            unsafe {
+                mpi::ffi::MPI_Grequest_complete(request).or_abort();
+            }
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! Management of the buffer used by buffered-mode sends (`MPI_Ibsend`).
+//!
+//! MPI allows only one such buffer per process, so at most one switch can
+//! own one at any time.
+
+use std::{fmt, mem};
+use libc;
+use mpi;
+use super::error::{MpiError, OrError};
+
+/// A buffer attached via `MPI_Buffer_attach`, which is detached when
+/// dropped.
+pub(crate) struct AttachBuffer(Vec<u8>);
+
+impl fmt::Debug for AttachBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("AttachBuffer")
+            .field(&self.0.len())
+            .finish()
+    }
+}
+
+impl AttachBuffer {
+    /// Allocate and attach a buffer of `size` bytes, which fails with
+    /// `MPI_ERR_COUNT` if `size` doesn't fit in a `c_int`.
+    pub fn attach(size: usize) -> Result<Self, MpiError> {
+        if size > libc::c_int::max_value() as usize {
+            let code = mpi::ffi::MPI_ERR_COUNT as libc::c_int;
+            return Err(MpiError::from_code(code).context(
+                "buffer is too large to attach"));
+        }
+        let mut buffer = vec![0u8; size];
+        unsafe {
+            mpi::ffi::MPI_Buffer_attach(buffer.as_mut_ptr() as *mut _,
+                                        size as libc::c_int).or_error()?;
+        }
+        Ok(AttachBuffer(buffer))
+    }
+}
+
+impl Drop for AttachBuffer {
+    fn drop(&mut self) {
+        // this blocks until every buffered message has been transmitted
+        let mut ptr: *mut libc::c_void = self.0.as_mut_ptr() as *mut _;
+        let mut len = 0;
+        let result = unsafe {
+            mpi::ffi::MPI_Buffer_detach(
+                &mut ptr as *mut *mut libc::c_void as *mut libc::c_void,
+                &mut len).or_error()
+        };
+        if result.is_err() {
+            // MPI may still be using it, so it's safer to leak it
+            mem::forget(mem::replace(&mut self.0, Vec::new()));
+        }
+    }
+}
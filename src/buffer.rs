@@ -3,6 +3,20 @@ use std::rc::Rc;
 use std::sync::Arc;
 use mpi::datatype::{Buffer, BufferMut, Equivalence};
 
+/// Borrow the contents of an owned buffer for an arbitrary lifetime, so that
+/// they can be handed to a request while the buffer itself is moved into the
+/// callback that runs once the request completes.
+///
+/// # Safety
+///
+/// The reference must not be used after `b` is dropped or operated on in any
+/// way beyond moves (see the invariant of `OwnedBuffer`).
+pub(crate) unsafe fn unbind_buffer<'a, B>(b: &B) -> &'a B::Buffer
+    where B: OwnedBuffer + ?Sized,
+{
+    mem::transmute(b.as_buffer())
+}
+
 /// An owned buffer that can be read from.
 ///
 /// # Unsafe invariant
@@ -0,0 +1,166 @@
+//! Cancellation of individual requests.
+//!
+//! Every request started on behalf of a `Send`, a `Preposted` stream or a
+//! persistent `Round` is associated with a `CancelToken`.  Requesting its
+//! cancellation makes the switch issue `MPI_Cancel` the next time the
+//! `RequestPoll` is tested, and the request (along with its buffer) stays in
+//! the poll until the cancellation completes.  Dropping a `Preposted` stream
+//! or a receiving `Round` cancels its pending receives.  A send is only ever
+//! cancelled explicitly (through `Send::cancel` or `Round::cancel`) or when
+//! its `Timeout` expires, since dropping a `Send` merely leaves it to
+//! complete in the background.
+//!
+//! The receive of a message matched by an `Incoming` stream can't be
+//! cancelled at all, as MPI does not support cancelling `MPI_Imrecv`.  Such
+//! receives always complete, so the way to stop receiving is to drop the
+//! stream before it matches any more messages.
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use futures::{Async, Future, Poll};
+use futures::sync::oneshot;
+use libc;
+use mpi;
+use super::alarm::Alarm;
+use super::error::{MpiError, OrError};
+
+#[derive(Debug, Default)]
+struct Report {
+    finished: bool,
+    sender: Option<oneshot::Sender<Result<bool, MpiError>>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    requested: AtomicBool,
+    issued: AtomicBool,
+    report: Mutex<Report>,
+    // rung upon cancellation, in case the switch is blocked
+    alarm: Mutex<Option<Arc<Alarm>>>,
+}
+
+/// Used to request the cancellation of a single request, possibly from a
+/// different thread than the one that owns the `RequestPoll`.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<State>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request the cancellation of the request.  This has no effect if the
+    /// request has already completed.
+    pub fn cancel(&self) {
+        self.0.requested.store(true, Ordering::SeqCst);
+        if let Some(ref alarm) = *self.0.alarm.lock().unwrap() {
+            alarm.ring();
+        }
+    }
+
+    /// Request the cancellation of the request, returning a future that
+    /// reports whether the cancellation took effect.
+    pub fn cancel_with_report(&self) -> Cancellation {
+        let (sender, receiver) = oneshot::channel();
+        {
+            let mut report = self.0.report.lock().unwrap();
+            if report.finished {
+                let _ = sender.send(Ok(false));
+            } else {
+                report.sender = Some(sender);
+            }
+        }
+        self.cancel();
+        Cancellation(receiver)
+    }
+
+    /// Whether cancellation has been requested.
+    pub fn is_cancel_requested(&self) -> bool {
+        self.0.requested.load(Ordering::SeqCst)
+    }
+
+    /// Wake up the switch through `alarm` whenever cancellation is
+    /// requested from now on.
+    pub(crate) fn set_alarm(&self, alarm: Arc<Alarm>) {
+        *self.0.alarm.lock().unwrap() = Some(alarm);
+    }
+
+    /// Issue `MPI_Cancel` if cancellation was requested and has not been
+    /// issued yet.
+    pub(crate) unsafe fn issue(&self, request: &mut mpi::ffi::MPI_Request) {
+        if !self.is_cancel_requested()
+            || self.0.issued.load(Ordering::SeqCst) {
+            return;
+        }
+        match mpi::ffi::MPI_Cancel(request).or_error() {
+            Ok(()) => self.0.issued.store(true, Ordering::SeqCst),
+            // give up on cancelling; the request will complete normally
+            Err(err) => self.send_report(Err(err)),
+        }
+    }
+
+    /// Called once the request has completed with the given status.
+    pub(crate) unsafe fn finish(&self, status: &mpi::ffi::MPI_Status) {
+        let outcome = if self.0.issued.load(Ordering::SeqCst) {
+            let mut flag: libc::c_int = 0;
+            mpi::ffi::MPI_Test_cancelled(status, &mut flag)
+                .or_error()
+                .map(|()| flag != 0)
+        } else {
+            Ok(false)
+        };
+        let sender = {
+            let mut report = self.0.report.lock().unwrap();
+            report.finished = true;
+            report.sender.take()
+        };
+        if let Some(sender) = sender {
+            let _ = sender.send(outcome);
+        }
+    }
+
+    fn send_report(&self, outcome: Result<bool, MpiError>) {
+        let sender = self.0.report.lock().unwrap().sender.take();
+        if let Some(sender) = sender {
+            let _ = sender.send(outcome);
+        }
+    }
+}
+
+/// Futures whose request can be cancelled without consuming them, as done by
+/// `Timeout` upon expiry.
+pub trait Cancel {
+    /// Request the cancellation of the underlying request, if one has been
+    /// started.  This has no effect if the request has already completed.
+    fn request_cancel(&mut self);
+}
+
+/// Future returned by the `cancel` methods, which resolves to whether the
+/// request was actually cancelled (as determined by `MPI_Test_cancelled`).
+///
+/// If the switch shuts down before the request completes, the outcome is
+/// unknown and `false` is reported.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Cancellation(oneshot::Receiver<Result<bool, MpiError>>);
+
+impl Cancellation {
+    /// A `Cancellation` with a predetermined outcome.
+    pub(crate) fn done(cancelled: bool) -> Self {
+        let (sender, receiver) = oneshot::channel();
+        let _ = sender.send(Ok(cancelled));
+        Cancellation(receiver)
+    }
+}
+
+impl Future for Cancellation {
+    type Item = bool;
+    type Error = MpiError;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.0.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(result)) => result.map(Async::Ready),
+            Err(oneshot::Canceled) => Ok(Async::Ready(false)),
+        }
+    }
+}
@@ -3,13 +3,16 @@
 //! to be mapped into an MPI datatype and vice versa.
 
 use std::ops::DerefMut;
-use conv::ValueInto;
 use futures::Future;
 use mpi::datatype::Equivalence;
 use mpi::point_to_point::Status;
-use void::Void;
+use mpi::raw::AsRaw;
 use super::buffer::{OwnedBuffer, Unanchor};
+use super::error::MpiError;
 use super::incoming::FutureBuffer;
+use super::large;
+use super::request_poll::SendMode;
+use super::tag::Tag;
 
 // This trait is not unsafe to implement nor use.  Although the `Status` must
 // be correctly associated with the message, this is meaningless in isolation
@@ -35,8 +38,11 @@ pub trait RecvInto<'a>: Sized {
     /// Convenience function if all you want is a simple `Vec`.
     fn recv_into_vec<T: Equivalence + 'a>(self) -> (Self::Output,
                                                     FutureBuffer<Vec<T>>) {
-        let len = self.status()
-            .count(T::equivalent_datatype()).value_into().unwrap();
+        // if the length is unknown, the receive fails with a truncation
+        // error instead
+        let datatype = T::equivalent_datatype().as_raw();
+        let len = large::message_len(&self.status().as_raw(), datatype)
+            .unwrap_or(0);
         let mut buf = Vec::<T>::with_capacity(len);
         unsafe {
             buf.set_len(len);
@@ -48,12 +54,26 @@ pub trait RecvInto<'a>: Sized {
 pub trait SendFrom<'a> {
     type Output;
 
-    fn send_from<B>(self, buffer: B, tag: u16) -> Self::Output
+    /// Send the buffer in the mode requested by whoever initiated the send
+    /// (e.g. `SendMode::Synchronous` for `LinkExt::send_sync`).
+    fn send_from<B>(self, buffer: B, tag: Tag) -> Self::Output
         where B: OwnedBuffer + 'a;
+
+    /// Send the buffer in the given mode, regardless of what was requested.
+    ///
+    /// By default the mode is ignored and the buffer is sent via
+    /// `send_from`, so that existing implementors keep working.
+    fn send_from_with_mode<B>(self, buffer: B, tag: Tag, _mode: SendMode)
+                              -> Self::Output
+        where Self: Sized,
+              B: OwnedBuffer + 'a,
+    {
+        self.send_from(buffer, tag)
+    }
 }
 
 pub trait Decoder<'a> {
-    type FutureMessage: Future<Error=Void>;
+    type FutureMessage: Future<Error=MpiError>;
 
     fn decode<R: RecvInto<'a>>(&mut self, r: R)
                                -> (R::Output, Self::FutureMessage);
@@ -68,6 +88,19 @@ impl<'a, T: DerefMut<Target=U>, U: Decoder<'a>> Decoder<'a> for T {
     }
 }
 
+/// A `Decoder` that can be used with a thread-safe switch.
+///
+/// # Unsafe invariant
+///
+/// Every buffer passed to `RecvInto::recv_into` must be `Send`, because it
+/// (or rather its anchor) may be released on a different thread.
+pub unsafe trait SyncDecoder<'a>: Decoder<'a> {}
+
+unsafe impl<'a, T, U> SyncDecoder<'a> for T
+    where T: DerefMut<Target=U>,
+          U: SyncDecoder<'a>,
+{}
+
 pub trait Encoder<'a> {
     /// Type of each message produced by `Incoming` and consumed by `send`.
     ///
@@ -78,6 +111,14 @@ pub trait Encoder<'a> {
     fn encode<S: SendFrom<'a>>(self, msg: Self::Message, s: S) -> S::Output;
 }
 
+/// An `Encoder` that can be used with a thread-safe switch.
+///
+/// # Unsafe invariant
+///
+/// Every buffer passed to `SendFrom::send_from` must be `Send`, because it
+/// may be released on a different thread.
+pub unsafe trait SyncEncoder<'a>: Encoder<'a> {}
+
 /// Simple codec that simply treats every message as an array of octets and
 /// always sets the tag to zero.
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
@@ -92,10 +133,14 @@ impl<'a> Decoder<'a> for U8Codec {
     }
 }
 
+unsafe impl<'a> SyncDecoder<'a> for U8Codec {}
+
 impl<'a> Encoder<'a> for U8Codec {
     type Message = Vec<u8>;
 
     fn encode<S: SendFrom<'a>>(self, msg: Self::Message, s: S) -> S::Output {
-        s.send_from(msg, 0)
+        s.send_from(msg, Tag::default())
     }
 }
+
+unsafe impl<'a> SyncEncoder<'a> for U8Codec {}
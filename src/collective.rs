@@ -0,0 +1,1615 @@
+//! Non-blocking collective operations.
+//!
+//! Each operation is started by the switch through the corresponding
+//! `MPI_I*` function and then completes like any other request of its
+//! `RequestPoll`, so it overlaps with point-to-point traffic handled by other
+//! tasks.
+//!
+//! Unlike most futures in this crate, collective operations are submitted to
+//! the switch as soon as they are created rather than when first polled.
+//! MPI requires every process of a communicator to start its collective
+//! operations in the same order, which is thus simply the order of the
+//! calls.  Collective operations cannot be cancelled, so dropping the future
+//! merely ignores the outcome.
+//!
+//! Arguments that are inconsistent on one process alone (e.g. counts that
+//! don't match the buffers) fail the future with `MPI_ERR_COUNT` without
+//! starting the operation.  The other processes may then wait in it forever,
+//! so such an error is best treated as a bug.  Failures that only surface
+//! once a staged operation is under way (such as counts received from other
+//! processes that don't fit) fail the future too, but the calling process
+//! still completes the remaining stage with nothing to receive, so the
+//! other processes aren't left waiting.  The data they sent to it is lost.
+
+use std::{fmt, marker, mem, ptr};
+use futures::{Async, Future, Poll};
+use futures::sync::oneshot;
+use libc;
+use mpi;
+use mpi::datatype::{Equivalence, Pointer, PointerMut};
+use mpi::raw::AsRaw;
+use mpi::topology::{Communicator, Rank};
+use super::buffer::{OwnedBuffer, OwnedBufferMut, Unanchor, unbind_buffer};
+use super::error::{MpiError, OrError, switch_gone};
+use super::large;
+use super::operation::Operation;
+use super::request_poll::RequestPoll;
+use super::switch::{Job, Submit, SyncJob};
+
+/// Sends the outcome of a collective operation to its future.
+pub type Sender<T> = oneshot::Sender<Result<T, MpiError>>;
+
+/// A collective operation that can be started by a switch.
+pub trait Collective<'a> {
+    /// What the operation resolves to, e.g. the buffers it used.
+    type Output;
+
+    /// Check the arguments of the calling process before the operation is
+    /// submitted.  If this fails, the operation is never started.
+    fn check(&self) -> Result<(), MpiError> {
+        Ok(())
+    }
+
+    /// Start the operation, arranging for its outcome to be sent through
+    /// `sender` once it completes (or fails to start).
+    fn start(self, request_poll: &mut RequestPoll<'a>,
+             sender: Sender<Self::Output>);
+}
+
+/// A `Collective` that can be started by a thread-safe switch.
+///
+/// # Unsafe invariant
+///
+/// Every buffer that the operation hands to the `RequestPoll` must be `Send`,
+/// because it may be released on a different thread.
+pub unsafe trait SyncCollective<'a>: Collective<'a> {}
+
+/// Future of a collective operation.
+///
+/// It fails if the switch was gone before the operation could be started.
+#[must_use = "futures do nothing unless polled"]
+pub struct FutureCollective<T>(oneshot::Receiver<Result<T, MpiError>>);
+
+impl<T: fmt::Debug> fmt::Debug for FutureCollective<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("FutureCollective")
+            .field(&self.0)
+            .finish()
+    }
+}
+
+impl<T> Future for FutureCollective<T> {
+    type Item = T;
+    type Error = MpiError;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.0.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(result)) => result.map(Async::Ready),
+            // the job was dropped without running
+            Err(oneshot::Canceled) => Err(switch_gone()),
+        }
+    }
+}
+
+/// Submit a collective operation to the switch behind `link` right away,
+/// unless its arguments are invalid (see `Collective::check`).
+pub fn start<'a, O, L>(link: &L, op: O) -> FutureCollective<O::Output>
+    where O: Collective<'a>,
+          L: Submit<'a, CollectiveJob<'a, O>>,
+{
+    let (sender, receiver) = oneshot::channel();
+    if let Err(err) = op.check() {
+        let _ = sender.send(Err(err));
+        return FutureCollective(receiver);
+    }
+    link.submit(CollectiveJob {
+        op: op,
+        sender: sender,
+    });
+    FutureCollective(receiver)
+}
+
+/// The `Job` submitted to start a collective operation.
+pub struct CollectiveJob<'a, O: Collective<'a>> {
+    op: O,
+    sender: Sender<O::Output>,
+}
+
+impl<'a, O: Collective<'a>> Job<'a> for CollectiveJob<'a, O> {
+    fn run(self, request_poll: &mut RequestPoll<'a>) {
+        self.op.start(request_poll, self.sender);
+    }
+}
+
+// the callbacks only hold on to the (thread-safe) sender and the buffers,
+// which are Send as guaranteed by SyncCollective
+unsafe impl<'a, O> SyncJob<'a> for CollectiveJob<'a, O>
+    where O: SyncCollective<'a>
+{}
+
+/// Barrier (`MPI_Ibarrier`) over the communicator `C`.
+#[derive(Clone, Copy, Debug)]
+pub struct Barrier<C>(pub C);
+
+impl<'a, C: Communicator> Collective<'a> for Barrier<C> {
+    type Output = ();
+
+    fn start(self, request_poll: &mut RequestPoll<'a>,
+             sender: Sender<Self::Output>) {
+        let comm = self.0.as_raw();
+        unsafe {
+            request_poll.start_collective(comm, |request| {
+                mpi::ffi::MPI_Ibarrier(comm, request).or_error()
+            }, move |result| {
+                let _ = sender.send(result);
+            });
+        }
+    }
+}
+
+unsafe impl<'a, C: Communicator> SyncCollective<'a> for Barrier<C> {}
+
+/// Broadcast (`MPI_Ibcast`) of a buffer from the process `root` of the
+/// communicator `C`, which is received into the same buffer everywhere
+/// else.
+#[derive(Debug)]
+pub struct Broadcast<C, B> {
+    pub comm: C,
+    pub root: Rank,
+    pub buffer: B,
+}
+
+impl<'a, C, B> Collective<'a> for Broadcast<C, B>
+    where C: Communicator,
+          B: Unanchor + 'a,
+{
+    type Output = B;
+
+    fn start(self, request_poll: &mut RequestPoll<'a>,
+             sender: Sender<Self::Output>) {
+        let comm = self.comm.as_raw();
+        let root = self.root;
+        unsafe {
+            let (anchor, buf) = self.buffer.into_buffer_mut();
+            request_poll.start_collective(comm, |request| {
+                let layout = large::layout(buf)?;
+                mpi::ffi::MPI_Ibcast(buf.pointer_mut(), layout.count,
+                                     layout.datatype, root, comm, request)
+                    .or_error()
+            }, move |result| {
+                // the buffer must be unanchored even if the broadcast failed
+                let buf = B::unanchor(anchor);
+                let _ = sender.send(result.map(|()| buf));
+            });
+        }
+    }
+}
+
+// the buffer is only ever moved to the thread that completes the request
+unsafe impl<'a, C, B> SyncCollective<'a> for Broadcast<C, B>
+    where C: Communicator,
+          B: Unanchor + marker::Send + 'a,
+{}
+
+/// Broadcast (`MPI_Ibcast`) of a buffer from the calling process, which
+/// only needs to be readable.  The other processes use `Broadcast`.
+#[derive(Debug)]
+pub struct BroadcastFrom<C, B> {
+    pub comm: C,
+    pub buffer: B,
+}
+
+impl<'a, C, B> Collective<'a> for BroadcastFrom<C, B>
+    where C: Communicator,
+          B: OwnedBuffer + 'a,
+{
+    type Output = B;
+
+    fn start(self, request_poll: &mut RequestPoll<'a>,
+             sender: Sender<Self::Output>) {
+        let comm = self.comm.as_raw();
+        let buffer = self.buffer;
+        unsafe {
+            let buf: &B::Buffer = unbind_buffer(&buffer);
+            request_poll.start_collective(comm, |request| {
+                let mut root = mem::uninitialized();
+                mpi::ffi::MPI_Comm_rank(comm, &mut root).or_error()?;
+                let layout = large::layout(buf)?;
+                // MPI_Ibcast only reads from the buffer of the root
+                mpi::ffi::MPI_Ibcast(buf.pointer() as *mut _, layout.count,
+                                     layout.datatype, root, comm, request)
+                    .or_error()
+            }, move |result| {
+                let _ = sender.send(result.map(|()| buffer));
+            });
+        }
+    }
+}
+
+unsafe impl<'a, C, B> SyncCollective<'a> for BroadcastFrom<C, B>
+    where C: Communicator,
+          B: OwnedBuffer + marker::Send + 'a,
+{}
+
+// an invalid count on this process (see the module documentation)
+fn invalid_count(context: &str) -> MpiError {
+    MpiError::from_code(mpi::ffi::MPI_ERR_COUNT as libc::c_int)
+        .context(context)
+}
+
+// the layout of the parts received in a later stage of an operation, or an
+// empty one along with the error if the counts are invalid, since the stage
+// must be completed regardless (see the module documentation)
+fn received_layout(counts: &[usize], size: usize)
+                   -> ((Vec<usize>, Vec<libc::c_int>, Vec<libc::c_int>),
+                       Option<MpiError>) {
+    match variable_layout(counts, size) {
+        Ok(layout) => (layout, None),
+        Err(err) => ((vec![0; size + 1], vec![0; size], vec![0; size]),
+                     Some(err)),
+    }
+}
+
+// the number of elements to reduce, which must fit into the receive buffer
+fn reduce_count(send: usize, recv: Option<usize>)
+                -> Result<libc::c_int, MpiError> {
+    if recv.map(|recv| recv < send).unwrap_or(false) {
+        return Err(invalid_count("receive buffer is smaller than the send \
+                                  buffer"));
+    }
+    to_count(send)
+}
+
+/// Reduction (`MPI_Ireduce`) of the `send` buffers of every process of the
+/// communicator `C` into the `recv` buffer of the calling process.  The
+/// other processes use `ReduceTo`.
+#[derive(Debug)]
+pub struct Reduce<C, O, S, R> {
+    pub comm: C,
+    pub op: O,
+    pub send: S,
+    pub recv: R,
+}
+
+impl<'a, C, O, S, R, T> Collective<'a> for Reduce<C, O, S, R>
+    where C: Communicator,
+          T: Equivalence,
+          O: Operation<T> + 'a,
+          S: OwnedBuffer<Buffer=[T]> + 'a,
+          R: Unanchor<BufferMut=[T]> + 'a,
+{
+    type Output = (S, R);
+
+    fn start(self, request_poll: &mut RequestPoll<'a>,
+             sender: Sender<Self::Output>) {
+        let comm = self.comm.as_raw();
+        let (op, send) = (self.op, self.send);
+        let (raw_op, datatype) = (op.op(), op.datatype());
+        unsafe {
+            let sendbuf: &[T] = unbind_buffer(&send);
+            let (anchor, recvbuf) = self.recv.into_buffer_mut();
+            request_poll.start_collective(comm, |request| {
+                let count = reduce_count(sendbuf.len(),
+                                         Some(recvbuf.len()))?;
+                let mut root = mem::uninitialized();
+                mpi::ffi::MPI_Comm_rank(comm, &mut root).or_error()?;
+                mpi::ffi::MPI_Ireduce(sendbuf.pointer(),
+                                      recvbuf.pointer_mut(), count,
+                                      datatype, raw_op, root, comm, request)
+                    .or_error()
+            }, move |result| {
+                // the operation must outlive the reduction
+                drop(op);
+                let recv = R::unanchor(anchor);
+                let _ = sender.send(result.map(|()| (send, recv)));
+            });
+        }
+    }
+}
+
+unsafe impl<'a, C, O, S, R, T> SyncCollective<'a> for Reduce<C, O, S, R>
+    where C: Communicator,
+          T: Equivalence,
+          O: Operation<T> + marker::Send + 'a,
+          S: OwnedBuffer<Buffer=[T]> + marker::Send + 'a,
+          R: Unanchor<BufferMut=[T]> + marker::Send + 'a,
+{}
+
+/// Contribution of the `send` buffer of the calling process to a reduction
+/// (`MPI_Ireduce`) into the process `root` of the communicator `C`.
+#[derive(Debug)]
+pub struct ReduceTo<C, O, S> {
+    pub comm: C,
+    pub root: Rank,
+    pub op: O,
+    pub send: S,
+}
+
+impl<'a, C, O, S, T> Collective<'a> for ReduceTo<C, O, S>
+    where C: Communicator,
+          T: Equivalence,
+          O: Operation<T> + 'a,
+          S: OwnedBuffer<Buffer=[T]> + 'a,
+{
+    type Output = S;
+
+    fn start(self, request_poll: &mut RequestPoll<'a>,
+             sender: Sender<Self::Output>) {
+        let comm = self.comm.as_raw();
+        let root = self.root;
+        let (op, send) = (self.op, self.send);
+        let (raw_op, datatype) = (op.op(), op.datatype());
+        unsafe {
+            let sendbuf: &[T] = unbind_buffer(&send);
+            request_poll.start_collective(comm, |request| {
+                let count = reduce_count(sendbuf.len(), None)?;
+                // the receive buffer is only significant at the root
+                mpi::ffi::MPI_Ireduce(sendbuf.pointer(), ptr::null_mut(),
+                                      count, datatype, raw_op, root, comm,
+                                      request)
+                    .or_error()
+            }, move |result| {
+                // the operation must outlive the reduction
+                drop(op);
+                let _ = sender.send(result.map(|()| send));
+            });
+        }
+    }
+}
+
+unsafe impl<'a, C, O, S, T> SyncCollective<'a> for ReduceTo<C, O, S>
+    where C: Communicator,
+          T: Equivalence,
+          O: Operation<T> + marker::Send + 'a,
+          S: OwnedBuffer<Buffer=[T]> + marker::Send + 'a,
+{}
+
+/// Reduction (`MPI_Iallreduce`) of the `send` buffers of every process of
+/// the communicator `C` into the `recv` buffers of every process.
+#[derive(Debug)]
+pub struct AllReduce<C, O, S, R> {
+    pub comm: C,
+    pub op: O,
+    pub send: S,
+    pub recv: R,
+}
+
+impl<'a, C, O, S, R, T> Collective<'a> for AllReduce<C, O, S, R>
+    where C: Communicator,
+          T: Equivalence,
+          O: Operation<T> + 'a,
+          S: OwnedBuffer<Buffer=[T]> + 'a,
+          R: Unanchor<BufferMut=[T]> + 'a,
+{
+    type Output = (S, R);
+
+    fn start(self, request_poll: &mut RequestPoll<'a>,
+             sender: Sender<Self::Output>) {
+        let comm = self.comm.as_raw();
+        let (op, send) = (self.op, self.send);
+        let (raw_op, datatype) = (op.op(), op.datatype());
+        unsafe {
+            let sendbuf: &[T] = unbind_buffer(&send);
+            let (anchor, recvbuf) = self.recv.into_buffer_mut();
+            request_poll.start_collective(comm, |request| {
+                let count = reduce_count(sendbuf.len(),
+                                         Some(recvbuf.len()))?;
+                mpi::ffi::MPI_Iallreduce(sendbuf.pointer(),
+                                         recvbuf.pointer_mut(), count,
+                                         datatype, raw_op, comm, request)
+                    .or_error()
+            }, move |result| {
+                // the operation must outlive the reduction
+                drop(op);
+                let recv = R::unanchor(anchor);
+                let _ = sender.send(result.map(|()| (send, recv)));
+            });
+        }
+    }
+}
+
+unsafe impl<'a, C, O, S, R, T> SyncCollective<'a> for AllReduce<C, O, S, R>
+    where C: Communicator,
+          T: Equivalence,
+          O: Operation<T> + marker::Send + 'a,
+          S: OwnedBuffer<Buffer=[T]> + marker::Send + 'a,
+          R: Unanchor<BufferMut=[T]> + marker::Send + 'a,
+{}
+
+/// Data gathered from every process of a communicator, stored contiguously
+/// in the order of the ranks.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Gathered<T> {
+    data: Vec<T>,
+    // the part of each rank starts at its offset and ends at the next one
+    offsets: Vec<usize>,
+}
+
+impl<T> Gathered<T> {
+    /// The number of processes that contributed.
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    /// Whether there are no contributions at all.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The part contributed by the process `rank`.
+    pub fn get(&self, rank: Rank) -> Option<&[T]> {
+        let rank = rank as usize;
+        if rank >= self.len() {
+            return None;
+        }
+        Some(&self.data[self.offsets[rank] .. self.offsets[rank + 1]])
+    }
+
+    /// The contributions of all processes, one after another.
+    pub fn data(&self) -> &[T] {
+        &self.data
+    }
+
+    /// Where the part of each process begins, followed by the total length.
+    pub fn offsets(&self) -> &[usize] {
+        &self.offsets
+    }
+
+    /// Obtain the data and offsets as they are.
+    pub fn into_flat(self) -> (Vec<T>, Vec<usize>) {
+        (self.data, self.offsets)
+    }
+
+    /// Split the data into the parts contributed by each process.
+    pub fn into_vecs(self) -> Vec<Vec<T>> {
+        let mut data = self.data;
+        let mut parts = Vec::with_capacity(self.offsets.len() - 1);
+        for &offset in self.offsets[.. self.offsets.len() - 1].iter().rev() {
+            parts.push(data.split_off(offset));
+        }
+        parts.reverse();
+        parts
+    }
+}
+
+// convert a number of elements into an MPI count
+fn to_count(len: usize) -> Result<libc::c_int, MpiError> {
+    if len > libc::c_int::max_value() as usize {
+        return Err(invalid_count("count does not fit into a C int"));
+    }
+    Ok(len as libc::c_int)
+}
+
+// there must be exactly one count or chunk per process
+fn check_parts(parts: usize, size: usize) -> Result<(), MpiError> {
+    if parts != size {
+        return Err(invalid_count("not one part per process"));
+    }
+    Ok(())
+}
+
+// the offsets, counts and displacements of variably sized parts
+fn variable_layout(counts: &[usize], size: usize)
+                   -> Result<(Vec<usize>, Vec<libc::c_int>,
+                              Vec<libc::c_int>), MpiError> {
+    check_parts(counts.len(), size)?;
+    let mut offsets = Vec::with_capacity(size + 1);
+    let mut mpi_counts = Vec::with_capacity(size);
+    let mut displs = Vec::with_capacity(size);
+    let mut offset = 0;
+    for &count in counts {
+        offsets.push(offset);
+        mpi_counts.push(to_count(count)?);
+        displs.push(to_count(offset)?);
+        offset += count;
+    }
+    offsets.push(offset);
+    Ok((offsets, mpi_counts, displs))
+}
+
+// the counts must add up to the length of the send buffer
+fn check_total(counts: &[usize], size: usize, len: usize)
+               -> Result<(), MpiError> {
+    let (offsets, _, _) = variable_layout(counts, size)?;
+    if offsets[size] != len {
+        return Err(invalid_count("counts do not add up to the length of \
+                                  the send buffer"));
+    }
+    Ok(())
+}
+
+// a buffer to be filled by MPI, which is fine because Equivalence types are
+// plain data
+unsafe fn uninitialized_vec<T: Equivalence>(len: usize) -> Vec<T> {
+    let mut vec = Vec::with_capacity(len);
+    vec.set_len(len);
+    vec
+}
+
+// a duplicate of a communicator on which the later stages of an operation
+// run, freed once it is dropped
+struct Dup(mpi::ffi::MPI_Comm);
+
+impl Drop for Dup {
+    fn drop(&mut self) {
+        unsafe {
+            // nothing sensible to do if this fails
+            let _ = mpi::ffi::MPI_Comm_free(&mut self.0).or_error();
+        }
+    }
+}
+
+// start an operation in stages by duplicating `comm` (`MPI_Comm_idup`),
+// passing the duplicate on to `next` once it exists.  Since nothing else
+// uses the duplicate, the later stages need not be ordered with respect to
+// other collective operations.
+unsafe fn start_dup<'a, F>(request_poll: &mut RequestPoll<'a>,
+                           comm: mpi::ffi::MPI_Comm, next: F)
+    where F: FnOnce(Result<Dup, MpiError>, &mut RequestPoll<'a>) + 'a
+{
+    // MPI writes the handle of the duplicate upon completion
+    let mut dup = Box::new(mpi::ffi::RSMPI_COMM_NULL);
+    let dup_ptr: *mut mpi::ffi::MPI_Comm = &mut *dup;
+    request_poll.start_collective_then(comm, |request| {
+        mpi::ffi::MPI_Comm_idup(comm, dup_ptr, request).or_error()
+    }, move |result, request_poll| {
+        next(result.map(|()| Dup(*dup)), request_poll);
+    });
+}
+
+/// Gather (`MPI_Igather`) of the equally long `send` buffers of every
+/// process of the communicator `C` into the calling process.  The other
+/// processes use `GatherTo`.
+#[derive(Debug)]
+pub struct Gather<C, S> {
+    pub comm: C,
+    pub send: S,
+}
+
+impl<'a, C, S, T> Collective<'a> for Gather<C, S>
+    where C: Communicator,
+          T: Equivalence + 'a,
+          S: OwnedBuffer<Buffer=[T]> + 'a,
+{
+    type Output = (S, Gathered<T>);
+
+    fn start(self, request_poll: &mut RequestPoll<'a>,
+             sender: Sender<Self::Output>) {
+        let comm = self.comm.as_raw();
+        let (size, root) = (self.comm.size() as usize, self.comm.rank());
+        let datatype = T::equivalent_datatype().as_raw();
+        let send = self.send;
+        unsafe {
+            let sendbuf: &[T] = unbind_buffer(&send);
+            let len = sendbuf.len();
+            let offsets = (0 .. size + 1).map(|rank| rank * len).collect();
+            let (anchor, recvbuf) =
+                uninitialized_vec::<T>(size * len).into_buffer_mut();
+            request_poll.start_collective(comm, |request| {
+                let count = to_count(len)?;
+                mpi::ffi::MPI_Igather(sendbuf.pointer(), count, datatype,
+                                      recvbuf.pointer_mut(), count, datatype,
+                                      root, comm, request)
+                    .or_error()
+            }, move |result| {
+                let gathered = Gathered {
+                    data: Vec::unanchor(anchor),
+                    offsets: offsets,
+                };
+                let _ = sender.send(result.map(|()| (send, gathered)));
+            });
+        }
+    }
+}
+
+unsafe impl<'a, C, S, T> SyncCollective<'a> for Gather<C, S>
+    where C: Communicator,
+          T: Equivalence + marker::Send + 'a,
+          S: OwnedBuffer<Buffer=[T]> + marker::Send + 'a,
+{}
+
+/// Contribution of the `send` buffer of the calling process to a gather
+/// (`MPI_Igather`) into the process `root` of the communicator `C`.
+#[derive(Debug)]
+pub struct GatherTo<C, S> {
+    pub comm: C,
+    pub root: Rank,
+    pub send: S,
+}
+
+impl<'a, C, S, T> Collective<'a> for GatherTo<C, S>
+    where C: Communicator,
+          T: Equivalence,
+          S: OwnedBuffer<Buffer=[T]> + 'a,
+{
+    type Output = S;
+
+    fn start(self, request_poll: &mut RequestPoll<'a>,
+             sender: Sender<Self::Output>) {
+        let comm = self.comm.as_raw();
+        let root = self.root;
+        let datatype = T::equivalent_datatype().as_raw();
+        let send = self.send;
+        unsafe {
+            let sendbuf: &[T] = unbind_buffer(&send);
+            request_poll.start_collective(comm, |request| {
+                let count = to_count(sendbuf.len())?;
+                // the receive arguments are only significant at the root
+                mpi::ffi::MPI_Igather(sendbuf.pointer(), count, datatype,
+                                      ptr::null_mut(), 0, datatype, root,
+                                      comm, request)
+                    .or_error()
+            }, move |result| {
+                let _ = sender.send(result.map(|()| send));
+            });
+        }
+    }
+}
+
+unsafe impl<'a, C, S, T> SyncCollective<'a> for GatherTo<C, S>
+    where C: Communicator,
+          T: Equivalence,
+          S: OwnedBuffer<Buffer=[T]> + marker::Send + 'a,
+{}
+
+/// Gather (`MPI_Igatherv`) of the variably long `send` buffers of every
+/// process of the communicator `C` into the calling process.  The other
+/// processes use `GathervTo`.
+///
+/// The operation first duplicates the communicator (`MPI_Comm_idup`), and
+/// then gathers the counts (`MPI_Igather`) and finally the data on the
+/// duplicate, like `AllToAllvFlat`, so the counts need not be known in
+/// advance.
+#[derive(Debug)]
+pub struct Gatherv<C, S> {
+    pub comm: C,
+    pub send: S,
+}
+
+impl<'a, C, S, T> Collective<'a> for Gatherv<C, S>
+    where C: Communicator,
+          T: Equivalence + 'a,
+          S: OwnedBuffer<Buffer=[T]> + 'a,
+{
+    type Output = (S, Gathered<T>);
+
+    fn check(&self) -> Result<(), MpiError> {
+        to_count(self.send.as_buffer().len()).map(|_| ())
+    }
+
+    fn start(self, request_poll: &mut RequestPoll<'a>,
+             sender: Sender<Self::Output>) {
+        let size = self.comm.size() as usize;
+        let root = self.comm.rank();
+        Collect::start(request_poll, self.comm.as_raw(), root, size,
+                       self.send, move |result| {
+                           let _ = sender.send(result);
+                       });
+    }
+}
+
+unsafe impl<'a, C, S, T> SyncCollective<'a> for Gatherv<C, S>
+    where C: Communicator,
+          T: Equivalence + marker::Send + 'a,
+          S: OwnedBuffer<Buffer=[T]> + marker::Send + 'a,
+{}
+
+/// Contribution of the `send` buffer of the calling process to a gather
+/// (`MPI_Igatherv`) into the process `root` of the communicator `C`, which
+/// uses `Gatherv`.
+#[derive(Debug)]
+pub struct GathervTo<C, S> {
+    pub comm: C,
+    pub root: Rank,
+    pub send: S,
+}
+
+impl<'a, C, S, T> Collective<'a> for GathervTo<C, S>
+    where C: Communicator,
+          T: Equivalence + 'a,
+          S: OwnedBuffer<Buffer=[T]> + 'a,
+{
+    type Output = S;
+
+    fn check(&self) -> Result<(), MpiError> {
+        to_count(self.send.as_buffer().len()).map(|_| ())
+    }
+
+    fn start(self, request_poll: &mut RequestPoll<'a>,
+             sender: Sender<Self::Output>) {
+        // nothing is gathered here
+        Collect::start(request_poll, self.comm.as_raw(), self.root, 0,
+                       self.send, move |result: Result<(S, _), _>| {
+                           let _ = sender.send(result.map(|(send, _)| send));
+                       });
+    }
+}
+
+unsafe impl<'a, C, S, T> SyncCollective<'a> for GathervTo<C, S>
+    where C: Communicator,
+          T: Equivalence + marker::Send + 'a,
+          S: OwnedBuffer<Buffer=[T]> + marker::Send + 'a,
+{}
+
+// what a Gatherv or GathervTo carries from one stage to the next
+struct Collect<S, F> {
+    root: Rank,
+    // the number of processes whose parts are gathered here, which is zero
+    // except at the root
+    parts: usize,
+    send: S,
+    sendcount: libc::c_int,
+    done: F,
+}
+
+impl<'a, S, T, F> Collect<S, F>
+    where T: Equivalence + 'a,
+          S: OwnedBuffer<Buffer=[T]> + 'a,
+          F: FnOnce(Result<(S, Gathered<T>), MpiError>) + 'a,
+{
+    fn start(request_poll: &mut RequestPoll<'a>, comm: mpi::ffi::MPI_Comm,
+             root: Rank, parts: usize, send: S, done: F) {
+        let sendcount = match to_count(send.as_buffer().len()) {
+            Ok(count) => count,
+            Err(err) => return done(Err(err)),
+        };
+        let collect = Collect {
+            root: root,
+            parts: parts,
+            send: send,
+            sendcount: sendcount,
+            done: done,
+        };
+        unsafe {
+            start_dup(request_poll, comm, move |result, request_poll| {
+                match result {
+                    Err(err) => (collect.done)(Err(err)),
+                    Ok(dup) => collect.collect_counts(request_poll, dup),
+                }
+            });
+        }
+    }
+
+    unsafe fn collect_counts(self, request_poll: &mut RequestPoll<'a>,
+                             dup: Dup) {
+        let (comm, root) = (dup.0, self.root);
+        let datatype = libc::c_int::equivalent_datatype().as_raw();
+        // MPI may read and write these until the counts have been gathered
+        let sendcount = Box::new(self.sendcount);
+        let mut recvcounts = vec![0 as libc::c_int; self.parts];
+        let sendcount_ptr: *const libc::c_int = &*sendcount;
+        let recvcounts_ptr = recvcounts.as_mut_ptr();
+        request_poll.start_collective_then(comm, |request| {
+            // the receive buffer is only significant at the root
+            mpi::ffi::MPI_Igather(sendcount_ptr as *const _, 1, datatype,
+                                  recvcounts_ptr as *mut _, 1, datatype,
+                                  root, comm, request)
+                .or_error()
+        }, move |result, request_poll| {
+            drop(sendcount);
+            match result {
+                Err(err) => (self.done)(Err(err)),
+                Ok(()) => self.collect_chunks(request_poll, dup, &recvcounts),
+            }
+        });
+    }
+
+    unsafe fn collect_chunks(self, request_poll: &mut RequestPoll<'a>,
+                             dup: Dup, received: &[libc::c_int]) {
+        let (comm, root, sendcount) = (dup.0, self.root, self.sendcount);
+        let datatype = T::equivalent_datatype().as_raw();
+        let parts = received.len();
+        let counts: Vec<_> =
+            received.iter().map(|&count| count as usize).collect();
+        // the counts come from the other processes, which have already
+        // started the operation
+        let ((offsets, recvcounts, displs), invalid) =
+            received_layout(&counts, parts);
+        let sendbuf: &[T] = unbind_buffer(&self.send);
+        let (anchor, recvbuf) =
+            uninitialized_vec::<T>(offsets[parts]).into_buffer_mut();
+        let (recvcounts_ptr, displs_ptr) =
+            (recvcounts.as_ptr(), displs.as_ptr());
+        let collect = self;
+        request_poll.start_collective(comm, |request| {
+            // the receive arguments are only significant at the root
+            mpi::ffi::MPI_Igatherv(sendbuf.pointer(), sendcount, datatype,
+                                   recvbuf.pointer_mut(), recvcounts_ptr,
+                                   displs_ptr, datatype, root, comm, request)
+                .or_error()
+        }, move |result| {
+            drop((recvcounts, displs, dup));
+            let gathered = Gathered {
+                data: Vec::unanchor(anchor),
+                offsets: offsets,
+            };
+            let Collect { send, done, .. } = collect;
+            done(match invalid {
+                // the parts of the other processes were truncated
+                Some(err) => Err(err),
+                None => result.map(|()| (send, gathered)),
+            });
+        });
+    }
+}
+
+/// Gather (`MPI_Iallgather`) of the equally long `send` buffers of every
+/// process of the communicator `C` into every process.
+#[derive(Debug)]
+pub struct AllGather<C, S> {
+    pub comm: C,
+    pub send: S,
+}
+
+impl<'a, C, S, T> Collective<'a> for AllGather<C, S>
+    where C: Communicator,
+          T: Equivalence + 'a,
+          S: OwnedBuffer<Buffer=[T]> + 'a,
+{
+    type Output = (S, Gathered<T>);
+
+    fn start(self, request_poll: &mut RequestPoll<'a>,
+             sender: Sender<Self::Output>) {
+        let comm = self.comm.as_raw();
+        let size = self.comm.size() as usize;
+        let datatype = T::equivalent_datatype().as_raw();
+        let send = self.send;
+        unsafe {
+            let sendbuf: &[T] = unbind_buffer(&send);
+            let len = sendbuf.len();
+            let offsets = (0 .. size + 1).map(|rank| rank * len).collect();
+            let (anchor, recvbuf) =
+                uninitialized_vec::<T>(size * len).into_buffer_mut();
+            request_poll.start_collective(comm, |request| {
+                let count = to_count(len)?;
+                mpi::ffi::MPI_Iallgather(sendbuf.pointer(), count, datatype,
+                                         recvbuf.pointer_mut(), count,
+                                         datatype, comm, request)
+                    .or_error()
+            }, move |result| {
+                let gathered = Gathered {
+                    data: Vec::unanchor(anchor),
+                    offsets: offsets,
+                };
+                let _ = sender.send(result.map(|()| (send, gathered)));
+            });
+        }
+    }
+}
+
+unsafe impl<'a, C, S, T> SyncCollective<'a> for AllGather<C, S>
+    where C: Communicator,
+          T: Equivalence + marker::Send + 'a,
+          S: OwnedBuffer<Buffer=[T]> + marker::Send + 'a,
+{}
+
+/// Scatter (`MPI_Iscatter`) of equally long chunks from the process `root`
+/// of the communicator `C`, whose chunk for the calling process is received
+/// into `recv`.
+#[derive(Debug)]
+pub struct Scatter<C, R> {
+    pub comm: C,
+    pub root: Rank,
+    pub recv: R,
+}
+
+impl<'a, C, R, T> Collective<'a> for Scatter<C, R>
+    where C: Communicator,
+          T: Equivalence,
+          R: Unanchor<BufferMut=[T]> + 'a,
+{
+    type Output = R;
+
+    fn start(self, request_poll: &mut RequestPoll<'a>,
+             sender: Sender<Self::Output>) {
+        let comm = self.comm.as_raw();
+        let root = self.root;
+        let datatype = T::equivalent_datatype().as_raw();
+        unsafe {
+            let (anchor, recvbuf) = self.recv.into_buffer_mut();
+            request_poll.start_collective(comm, |request| {
+                let count = to_count(recvbuf.len())?;
+                // the send arguments are only significant at the root
+                mpi::ffi::MPI_Iscatter(ptr::null(), 0, datatype,
+                                       recvbuf.pointer_mut(), count, datatype,
+                                       root, comm, request)
+                    .or_error()
+            }, move |result| {
+                let recv = R::unanchor(anchor);
+                let _ = sender.send(result.map(|()| recv));
+            });
+        }
+    }
+}
+
+unsafe impl<'a, C, R, T> SyncCollective<'a> for Scatter<C, R>
+    where C: Communicator,
+          T: Equivalence,
+          R: Unanchor<BufferMut=[T]> + marker::Send + 'a,
+{}
+
+/// Scatter (`MPI_Iscatter`) of equally long chunks from the calling process
+/// to every process of the communicator `C`, where the `i`-th chunk of
+/// `send` goes to the process of rank `i`.  The other processes use
+/// `Scatter`.
+///
+/// There must be one chunk per process.  The chunks are concatenated into a
+/// single buffer before being sent, as described by `ScatterFromFlat`, and
+/// the operation resolves to the chunk of the calling process.
+#[derive(Debug)]
+pub struct ScatterFrom<C, T> {
+    pub comm: C,
+    pub send: Vec<Vec<T>>,
+}
+
+impl<'a, C, T> Collective<'a> for ScatterFrom<C, T>
+    where C: Communicator,
+          T: Equivalence + 'a,
+{
+    type Output = Vec<T>;
+
+    fn check(&self) -> Result<(), MpiError> {
+        check_parts(self.send.len(), self.comm.size() as usize)?;
+        let len = self.send[0].len();
+        if self.send.iter().any(|chunk| chunk.len() != len) {
+            return Err(invalid_count("chunks are not equally long"));
+        }
+        Ok(())
+    }
+
+    fn start(self, request_poll: &mut RequestPoll<'a>,
+             sender: Sender<Self::Output>) {
+        let root = self.comm.rank() as usize;
+        let len = self.send.get(root).map_or(0, Vec::len);
+        let send = self.send.into_iter().flat_map(|chunk| chunk).collect();
+        ScatterFromFlat {
+            comm: self.comm,
+            send: send,
+            recv: unsafe { uninitialized_vec(len) },
+        }.scatter(request_poll, move |result: Result<(Vec<T>, _), _>| {
+            let _ = sender.send(result.map(|(_, recv)| recv));
+        });
+    }
+}
+
+unsafe impl<'a, C, T> SyncCollective<'a> for ScatterFrom<C, T>
+    where C: Communicator,
+          T: Equivalence + marker::Send + 'a,
+{}
+
+/// Scatter (`MPI_Iscatter`) of the `send` buffer of the calling process to
+/// every process of the communicator `C`, in chunks as long as `recv`, which
+/// receives the chunk of the calling process.  The other processes use
+/// `Scatter`.
+#[derive(Debug)]
+pub struct ScatterFromFlat<C, S, R> {
+    pub comm: C,
+    pub send: S,
+    pub recv: R,
+}
+
+impl<'a, C, S, R, T> ScatterFromFlat<C, S, R>
+    where C: Communicator,
+          T: Equivalence,
+          S: OwnedBuffer<Buffer=[T]> + 'a,
+          R: Unanchor<BufferMut=[T]> + 'a,
+{
+    fn scatter<F>(self, request_poll: &mut RequestPoll<'a>, done: F)
+        where F: FnOnce(Result<(S, R), MpiError>) + 'a
+    {
+        let comm = self.comm.as_raw();
+        let (size, root) = (self.comm.size() as usize, self.comm.rank());
+        let datatype = T::equivalent_datatype().as_raw();
+        let send = self.send;
+        unsafe {
+            let sendbuf: &[T] = unbind_buffer(&send);
+            let (anchor, recvbuf) = self.recv.into_buffer_mut();
+            request_poll.start_collective(comm, |request| {
+                // the receive buffer can't be measured before it is split
+                if sendbuf.len() != size * recvbuf.len() {
+                    return Err(invalid_count("send buffer does not consist \
+                                              of one chunk per process"));
+                }
+                let count = to_count(recvbuf.len())?;
+                mpi::ffi::MPI_Iscatter(sendbuf.pointer(), count, datatype,
+                                       recvbuf.pointer_mut(), count, datatype,
+                                       root, comm, request)
+                    .or_error()
+            }, move |result| {
+                let recv = R::unanchor(anchor);
+                done(result.map(|()| (send, recv)));
+            });
+        }
+    }
+}
+
+impl<'a, C, S, R, T> Collective<'a> for ScatterFromFlat<C, S, R>
+    where C: Communicator,
+          T: Equivalence,
+          S: OwnedBuffer<Buffer=[T]> + 'a,
+          R: Unanchor<BufferMut=[T]> + 'a,
+{
+    type Output = (S, R);
+
+    fn start(self, request_poll: &mut RequestPoll<'a>,
+             sender: Sender<Self::Output>) {
+        self.scatter(request_poll, move |result| {
+            let _ = sender.send(result);
+        });
+    }
+}
+
+unsafe impl<'a, C, S, R, T> SyncCollective<'a> for ScatterFromFlat<C, S, R>
+    where C: Communicator,
+          T: Equivalence,
+          S: OwnedBuffer<Buffer=[T]> + marker::Send + 'a,
+          R: Unanchor<BufferMut=[T]> + marker::Send + 'a,
+{}
+
+/// Scatter (`MPI_Iscatterv`) of variably sized chunks from the process
+/// `root` of the communicator `C`, whose chunk for the calling process is
+/// received into a new `Vec`.
+///
+/// The operation first duplicates the communicator (`MPI_Comm_idup`), and
+/// then scatters the counts (`MPI_Iscatter`) and finally the chunks on the
+/// duplicate, like `AllToAllvFlat`, so the length of the chunk need not be
+/// known in advance.
+#[derive(Debug)]
+pub struct Scatterv<C, T> {
+    pub comm: C,
+    pub root: Rank,
+    phantom: marker::PhantomData<fn() -> T>,
+}
+
+impl<C, T> Scatterv<C, T> {
+    /// Receive a chunk of elements of type `T` from `root`.
+    pub fn new(comm: C, root: Rank) -> Self {
+        Scatterv {
+            comm: comm,
+            root: root,
+            phantom: marker::PhantomData,
+        }
+    }
+}
+
+impl<'a, C, T> Collective<'a> for Scatterv<C, T>
+    where C: Communicator,
+          T: Equivalence + 'a,
+{
+    type Output = Vec<T>;
+
+    fn start(self, request_poll: &mut RequestPoll<'a>,
+             sender: Sender<Self::Output>) {
+        let portion = Portion {
+            root: self.root,
+            done: move |result| {
+                let _ = sender.send(result);
+            },
+        };
+        unsafe {
+            start_dup(request_poll, self.comm.as_raw(),
+                      move |result, request_poll| match result {
+                          Err(err) => (portion.done)(Err(err)),
+                          Ok(dup) => portion.receive_count(request_poll, dup),
+                      });
+        }
+    }
+}
+
+unsafe impl<'a, C, T> SyncCollective<'a> for Scatterv<C, T>
+    where C: Communicator,
+          T: Equivalence + marker::Send + 'a,
+{}
+
+// what a Scatterv carries from one stage to the next
+struct Portion<F> {
+    root: Rank,
+    done: F,
+}
+
+impl<'a, F> Portion<F> {
+    unsafe fn receive_count<T>(self, request_poll: &mut RequestPoll<'a>,
+                               dup: Dup)
+        where T: Equivalence + 'a,
+              F: FnOnce(Result<Vec<T>, MpiError>) + 'a,
+    {
+        let (comm, root) = (dup.0, self.root);
+        let datatype = libc::c_int::equivalent_datatype().as_raw();
+        // MPI writes the count upon completion
+        let mut count = Box::new(0 as libc::c_int);
+        let count_ptr: *mut libc::c_int = &mut *count;
+        request_poll.start_collective_then(comm, |request| {
+            // the send arguments are only significant at the root
+            mpi::ffi::MPI_Iscatter(ptr::null(), 0, datatype,
+                                   count_ptr as *mut _, 1, datatype, root,
+                                   comm, request)
+                .or_error()
+        }, move |result, request_poll| match result {
+            Err(err) => (self.done)(Err(err)),
+            Ok(()) => self.receive_chunk(request_poll, dup, *count),
+        });
+    }
+
+    unsafe fn receive_chunk<T>(self, request_poll: &mut RequestPoll<'a>,
+                               dup: Dup, count: libc::c_int)
+        where T: Equivalence + 'a,
+              F: FnOnce(Result<Vec<T>, MpiError>) + 'a,
+    {
+        let (comm, root) = (dup.0, self.root);
+        let datatype = T::equivalent_datatype().as_raw();
+        // the count comes from the root, which has already started the
+        // operation, so an invalid one still requires receiving (nothing)
+        let (count, invalid) = if count < 0 {
+            (0, Some(invalid_count("received a negative count")))
+        } else {
+            (count, None)
+        };
+        let (anchor, recvbuf) =
+            uninitialized_vec::<T>(count as usize).into_buffer_mut();
+        let done = self.done;
+        request_poll.start_collective(comm, |request| {
+            // the send arguments are only significant at the root
+            mpi::ffi::MPI_Iscatterv(ptr::null(), ptr::null(), ptr::null(),
+                                    datatype, recvbuf.pointer_mut(), count,
+                                    datatype, root, comm, request)
+                .or_error()
+        }, move |result| {
+            drop(dup);
+            let recv = Vec::unanchor(anchor);
+            done(match invalid {
+                // the chunk from the root was truncated
+                Some(err) => Err(err),
+                None => result.map(|()| recv),
+            });
+        });
+    }
+}
+
+/// Scatter (`MPI_Iscatterv`) of variably sized chunks from the calling
+/// process to every process of the communicator `C`, where the `i`-th chunk
+/// of `send` goes to the process of rank `i`.  The other processes use
+/// `Scatterv`.
+///
+/// There must be one chunk per process.  The counts and displacements are
+/// computed from the chunks, which are concatenated into a single buffer
+/// before being sent, as described by `ScattervFromFlat`.  The operation
+/// resolves to the chunk of the calling process.
+#[derive(Debug)]
+pub struct ScattervFrom<C, T> {
+    pub comm: C,
+    pub send: Vec<Vec<T>>,
+}
+
+impl<'a, C, T> Collective<'a> for ScattervFrom<C, T>
+    where C: Communicator,
+          T: Equivalence + 'a,
+{
+    type Output = Vec<T>;
+
+    fn check(&self) -> Result<(), MpiError> {
+        let counts: Vec<_> = self.send.iter().map(Vec::len).collect();
+        variable_layout(&counts, self.comm.size() as usize)?;
+        Ok(())
+    }
+
+    fn start(self, request_poll: &mut RequestPoll<'a>,
+             sender: Sender<Self::Output>) {
+        let root = self.comm.rank() as usize;
+        let counts: Vec<_> = self.send.iter().map(Vec::len).collect();
+        let len = counts.get(root).cloned().unwrap_or(0);
+        let send = self.send.into_iter().flat_map(|chunk| chunk).collect();
+        ScattervFromFlat {
+            comm: self.comm,
+            send: send,
+            counts: counts,
+            recv: unsafe { uninitialized_vec(len) },
+        }.scatter(request_poll, move |result: Result<(Vec<T>, _), _>| {
+            let _ = sender.send(result.map(|(_, recv)| recv));
+        });
+    }
+}
+
+unsafe impl<'a, C, T> SyncCollective<'a> for ScattervFrom<C, T>
+    where C: Communicator,
+          T: Equivalence + marker::Send + 'a,
+{}
+
+/// Scatter (`MPI_Iscatterv`) of the `send` buffer of the calling process to
+/// every process of the communicator `C`, where the process of rank `i`
+/// receives the next `counts[i]` elements.  The chunk of the calling process
+/// is received into `recv`, and the other processes use `Scatterv`.
+///
+/// The counts are scattered (`MPI_Iscatter`) before the chunks, on a
+/// duplicate of the communicator, as described by `Scatterv`.
+#[derive(Debug)]
+pub struct ScattervFromFlat<C, S, R> {
+    pub comm: C,
+    pub send: S,
+    pub counts: Vec<usize>,
+    pub recv: R,
+}
+
+impl<'a, C, S, R, T> ScattervFromFlat<C, S, R>
+    where C: Communicator,
+          T: Equivalence,
+          S: OwnedBuffer<Buffer=[T]> + 'a,
+          R: Unanchor<BufferMut=[T]> + 'a,
+{
+    fn scatter<F>(self, request_poll: &mut RequestPoll<'a>, done: F)
+        where F: FnOnce(Result<(S, R), MpiError>) + 'a
+    {
+        let comm = self.comm.as_raw();
+        let (size, root) = (self.comm.size() as usize, self.comm.rank());
+        let (send, counts) = (self.send, self.counts);
+        let (_, sendcounts, displs) = match variable_layout(&counts, size) {
+            Ok(layout) => layout,
+            Err(err) => return done(Err(err)),
+        };
+        // the receive buffer can only be measured once it is split
+        let (recv, len) = unsafe {
+            let (anchor, recvbuf) = self.recv.into_buffer_mut();
+            let len = recvbuf.len();
+            (R::unanchor(anchor), len)
+        };
+        if sendcounts[root as usize] as usize != len {
+            return done(Err(invalid_count("count of the root differs from \
+                                           its receive buffer")));
+        }
+        let distribute = Distribute {
+            root: root,
+            send: send,
+            sendcounts: sendcounts,
+            displs: displs,
+            recv: recv,
+            done: done,
+        };
+        unsafe {
+            start_dup(request_poll, comm, move |result, request_poll| {
+                match result {
+                    Err(err) => (distribute.done)(Err(err)),
+                    Ok(dup) => distribute.scatter_counts(request_poll, dup),
+                }
+            });
+        }
+    }
+}
+
+impl<'a, C, S, R, T> Collective<'a> for ScattervFromFlat<C, S, R>
+    where C: Communicator,
+          T: Equivalence,
+          S: OwnedBuffer<Buffer=[T]> + 'a,
+          R: Unanchor<BufferMut=[T]> + 'a,
+{
+    type Output = (S, R);
+
+    fn check(&self) -> Result<(), MpiError> {
+        check_total(&self.counts, self.comm.size() as usize,
+                    self.send.as_buffer().len())
+    }
+
+    fn start(self, request_poll: &mut RequestPoll<'a>,
+             sender: Sender<Self::Output>) {
+        self.scatter(request_poll, move |result| {
+            let _ = sender.send(result);
+        });
+    }
+}
+
+unsafe impl<'a, C, S, R, T> SyncCollective<'a> for ScattervFromFlat<C, S, R>
+    where C: Communicator,
+          T: Equivalence,
+          S: OwnedBuffer<Buffer=[T]> + marker::Send + 'a,
+          R: Unanchor<BufferMut=[T]> + marker::Send + 'a,
+{}
+
+// what a ScattervFromFlat carries from one stage to the next
+struct Distribute<S, R, F> {
+    root: Rank,
+    send: S,
+    // MPI may read these until the chunks have been scattered
+    sendcounts: Vec<libc::c_int>,
+    displs: Vec<libc::c_int>,
+    recv: R,
+    done: F,
+}
+
+impl<'a, S, R, T, F> Distribute<S, R, F>
+    where T: Equivalence,
+          S: OwnedBuffer<Buffer=[T]> + 'a,
+          R: Unanchor<BufferMut=[T]> + 'a,
+          F: FnOnce(Result<(S, R), MpiError>) + 'a,
+{
+    unsafe fn scatter_counts(self, request_poll: &mut RequestPoll<'a>,
+                             dup: Dup) {
+        let (comm, root) = (dup.0, self.root);
+        let datatype = libc::c_int::equivalent_datatype().as_raw();
+        // MPI writes the count of the root upon completion, which it
+        // already knows
+        let mut count = Box::new(0 as libc::c_int);
+        let count_ptr: *mut libc::c_int = &mut *count;
+        let sendcounts_ptr = self.sendcounts.as_ptr();
+        request_poll.start_collective_then(comm, |request| {
+            mpi::ffi::MPI_Iscatter(sendcounts_ptr as *const _, 1, datatype,
+                                   count_ptr as *mut _, 1, datatype, root,
+                                   comm, request)
+                .or_error()
+        }, move |result, request_poll| {
+            drop(count);
+            match result {
+                Err(err) => (self.done)(Err(err)),
+                Ok(()) => self.scatter_chunks(request_poll, dup),
+            }
+        });
+    }
+
+    unsafe fn scatter_chunks(self, request_poll: &mut RequestPoll<'a>,
+                             dup: Dup) {
+        let (comm, root) = (dup.0, self.root);
+        let datatype = T::equivalent_datatype().as_raw();
+        let recvcount = self.sendcounts[root as usize];
+        let Distribute { send, sendcounts, displs, recv, done, .. } = self;
+        let sendbuf: &[T] = unbind_buffer(&send);
+        let (anchor, recvbuf) = recv.into_buffer_mut();
+        let (sendcounts_ptr, displs_ptr) =
+            (sendcounts.as_ptr(), displs.as_ptr());
+        request_poll.start_collective(comm, |request| {
+            mpi::ffi::MPI_Iscatterv(sendbuf.pointer(), sendcounts_ptr,
+                                    displs_ptr, datatype,
+                                    recvbuf.pointer_mut(), recvcount,
+                                    datatype, root, comm, request)
+                .or_error()
+        }, move |result| {
+            drop((sendcounts, displs, dup));
+            let recv = R::unanchor(anchor);
+            done(result.map(|()| (send, recv)));
+        });
+    }
+}
+
+/// Exchange (`MPI_Ialltoall`) of equally long chunks between every pair of
+/// processes of the communicator `C`, where the `i`-th chunk of `send` goes
+/// to the process of rank `i`.
+#[derive(Debug)]
+pub struct AllToAll<C, S> {
+    pub comm: C,
+    pub send: S,
+}
+
+impl<'a, C, S, T> Collective<'a> for AllToAll<C, S>
+    where C: Communicator,
+          T: Equivalence + 'a,
+          S: OwnedBuffer<Buffer=[T]> + 'a,
+{
+    type Output = (S, Gathered<T>);
+
+    fn check(&self) -> Result<(), MpiError> {
+        if self.send.as_buffer().len() % self.comm.size() as usize != 0 {
+            return Err(invalid_count("send buffer does not consist of one \
+                                      chunk per process"));
+        }
+        Ok(())
+    }
+
+    fn start(self, request_poll: &mut RequestPoll<'a>,
+             sender: Sender<Self::Output>) {
+        let comm = self.comm.as_raw();
+        let size = self.comm.size() as usize;
+        let datatype = T::equivalent_datatype().as_raw();
+        let send = self.send;
+        unsafe {
+            let sendbuf: &[T] = unbind_buffer(&send);
+            let len = sendbuf.len() / size;
+            let offsets = (0 .. size + 1).map(|rank| rank * len).collect();
+            let (anchor, recvbuf) =
+                uninitialized_vec::<T>(size * len).into_buffer_mut();
+            request_poll.start_collective(comm, |request| {
+                let count = to_count(len)?;
+                mpi::ffi::MPI_Ialltoall(sendbuf.pointer(), count, datatype,
+                                        recvbuf.pointer_mut(), count,
+                                        datatype, comm, request)
+                    .or_error()
+            }, move |result| {
+                let gathered = Gathered {
+                    data: Vec::unanchor(anchor),
+                    offsets: offsets,
+                };
+                let _ = sender.send(result.map(|()| (send, gathered)));
+            });
+        }
+    }
+}
+
+unsafe impl<'a, C, S, T> SyncCollective<'a> for AllToAll<C, S>
+    where C: Communicator,
+          T: Equivalence + marker::Send + 'a,
+          S: OwnedBuffer<Buffer=[T]> + marker::Send + 'a,
+{}
+
+/// Exchange (`MPI_Ialltoallv`) of variably sized chunks between every pair
+/// of processes of the communicator `C`, where the `i`-th chunk of `send`
+/// goes to the process of rank `i`.
+///
+/// There must be one chunk per process.  The chunks are concatenated into a
+/// single buffer before being sent, as described by `AllToAllvFlat`.
+#[derive(Debug)]
+pub struct AllToAllv<C, T> {
+    pub comm: C,
+    pub send: Vec<Vec<T>>,
+}
+
+impl<'a, C, T> Collective<'a> for AllToAllv<C, T>
+    where C: Communicator,
+          T: Equivalence + 'a,
+{
+    type Output = Gathered<T>;
+
+    fn check(&self) -> Result<(), MpiError> {
+        let counts: Vec<_> = self.send.iter().map(Vec::len).collect();
+        variable_layout(&counts, self.comm.size() as usize)?;
+        Ok(())
+    }
+
+    fn start(self, request_poll: &mut RequestPoll<'a>,
+             sender: Sender<Self::Output>) {
+        let counts = self.send.iter().map(Vec::len).collect();
+        let send = self.send.into_iter().flat_map(|chunk| chunk).collect();
+        AllToAllvFlat {
+            comm: self.comm,
+            send: send,
+            counts: counts,
+        }.exchange(request_poll, move |result: Result<(Vec<T>, _), _>| {
+            let _ = sender.send(result.map(|(_, gathered)| gathered));
+        });
+    }
+}
+
+unsafe impl<'a, C, T> SyncCollective<'a> for AllToAllv<C, T>
+    where C: Communicator,
+          T: Equivalence + marker::Send + 'a,
+{}
+
+/// Exchange (`MPI_Ialltoallv`) of variably sized chunks between every pair
+/// of processes of the communicator `C`, where the next `counts[i]`
+/// elements of `send` go to the process of rank `i`.
+///
+/// The operation first duplicates the communicator (`MPI_Comm_idup`), and
+/// then exchanges the counts (`MPI_Ialltoall`) and finally the chunks on
+/// the duplicate, each stage being started by the switch as soon as the
+/// previous one completes.  Since nothing else uses the duplicate, the later
+/// stages need not be ordered with respect to other collective operations.
+#[derive(Debug)]
+pub struct AllToAllvFlat<C, S> {
+    pub comm: C,
+    pub send: S,
+    pub counts: Vec<usize>,
+}
+
+impl<'a, C, S, T> AllToAllvFlat<C, S>
+    where C: Communicator,
+          T: Equivalence + 'a,
+          S: OwnedBuffer<Buffer=[T]> + 'a,
+{
+    fn exchange<F>(self, request_poll: &mut RequestPoll<'a>, done: F)
+        where F: FnOnce(Result<(S, Gathered<T>), MpiError>) + 'a
+    {
+        let comm = self.comm.as_raw();
+        let size = self.comm.size() as usize;
+        let (_, sendcounts, sdispls) =
+            match variable_layout(&self.counts, size) {
+                Ok(layout) => layout,
+                Err(err) => return done(Err(err)),
+            };
+        let exchange = Exchange {
+            send: self.send,
+            sendcounts: sendcounts,
+            sdispls: sdispls,
+            done: done,
+        };
+        unsafe {
+            start_dup(request_poll, comm, move |result, request_poll| {
+                match result {
+                    Err(err) => (exchange.done)(Err(err)),
+                    Ok(dup) => exchange.exchange_counts(request_poll, dup),
+                }
+            });
+        }
+    }
+}
+
+impl<'a, C, S, T> Collective<'a> for AllToAllvFlat<C, S>
+    where C: Communicator,
+          T: Equivalence + 'a,
+          S: OwnedBuffer<Buffer=[T]> + 'a,
+{
+    type Output = (S, Gathered<T>);
+
+    fn check(&self) -> Result<(), MpiError> {
+        check_total(&self.counts, self.comm.size() as usize,
+                    self.send.as_buffer().len())
+    }
+
+    fn start(self, request_poll: &mut RequestPoll<'a>,
+             sender: Sender<Self::Output>) {
+        self.exchange(request_poll, move |result| {
+            let _ = sender.send(result);
+        });
+    }
+}
+
+unsafe impl<'a, C, S, T> SyncCollective<'a> for AllToAllvFlat<C, S>
+    where C: Communicator,
+          T: Equivalence + marker::Send + 'a,
+          S: OwnedBuffer<Buffer=[T]> + marker::Send + 'a,
+{}
+
+// what an AllToAllvFlat carries from one stage to the next
+struct Exchange<S, F> {
+    send: S,
+    // MPI may read these until the chunks have been exchanged
+    sendcounts: Vec<libc::c_int>,
+    sdispls: Vec<libc::c_int>,
+    done: F,
+}
+
+impl<'a, S, T, F> Exchange<S, F>
+    where T: Equivalence + 'a,
+          S: OwnedBuffer<Buffer=[T]> + 'a,
+          F: FnOnce(Result<(S, Gathered<T>), MpiError>) + 'a,
+{
+    unsafe fn exchange_counts(self, request_poll: &mut RequestPoll<'a>,
+                              dup: Dup) {
+        let comm = dup.0;
+        let datatype = libc::c_int::equivalent_datatype().as_raw();
+        let mut recvcounts = vec![0 as libc::c_int; self.sendcounts.len()];
+        let (sendcounts_ptr, recvcounts_ptr) =
+            (self.sendcounts.as_ptr(), recvcounts.as_mut_ptr());
+        request_poll.start_collective_then(comm, |request| {
+            mpi::ffi::MPI_Ialltoall(sendcounts_ptr as *const _, 1, datatype,
+                                    recvcounts_ptr as *mut _, 1, datatype,
+                                    comm, request)
+                .or_error()
+        }, move |result, request_poll| match result {
+            Err(err) => (self.done)(Err(err)),
+            Ok(()) => self.exchange_chunks(request_poll, dup, &recvcounts),
+        });
+    }
+
+    unsafe fn exchange_chunks(self, request_poll: &mut RequestPoll<'a>,
+                              dup: Dup, received: &[libc::c_int]) {
+        let comm = dup.0;
+        let datatype = T::equivalent_datatype().as_raw();
+        let size = received.len();
+        let counts: Vec<_> =
+            received.iter().map(|&count| count as usize).collect();
+        // the counts come from the other processes, which have already
+        // started the operation
+        let ((offsets, recvcounts, rdispls), invalid) =
+            received_layout(&counts, size);
+        let sendbuf: &[T] = unbind_buffer(&self.send);
+        let (anchor, recvbuf) =
+            uninitialized_vec::<T>(offsets[size]).into_buffer_mut();
+        let (sendcounts_ptr, sdispls_ptr, recvcounts_ptr, rdispls_ptr) =
+            (self.sendcounts.as_ptr(), self.sdispls.as_ptr(),
+             recvcounts.as_ptr(), rdispls.as_ptr());
+        let exchange = self;
+        request_poll.start_collective(comm, |request| {
+            mpi::ffi::MPI_Ialltoallv(sendbuf.pointer(), sendcounts_ptr,
+                                     sdispls_ptr, datatype,
+                                     recvbuf.pointer_mut(), recvcounts_ptr,
+                                     rdispls_ptr, datatype, comm, request)
+                .or_error()
+        }, move |result| {
+            drop((recvcounts, rdispls, dup));
+            let gathered = Gathered {
+                data: Vec::unanchor(anchor),
+                offsets: offsets,
+            };
+            let Exchange { send, done, .. } = exchange;
+            done(match invalid {
+                // the chunks of the other processes were truncated
+                Some(err) => Err(err),
+                None => result.map(|()| (send, gathered)),
+            });
+        });
+    }
+}
@@ -0,0 +1,427 @@
+//! Centralized probing for incoming messages on behalf of `Incoming` streams.
+//!
+//! Rather than having every stream probe for itself (which would cause
+//! overlapping streams to split messages nondeterministically), each stream
+//! subscribes to the switch with a `Filter`.  The switch probes on behalf of
+//! the streams that are waiting and delivers each matched message to the
+//! most specific subscription that matches it, i.e. the one that names the
+//! most of source and tag rather than leaving them as wildcards.  If that
+//! subscription goes away before taking its message, the message is handed
+//! to the next subscription that matches it and has no message pending, and
+//! only thrown away if there is none.
+//!
+//! A subscription may also merely peek at messages (via `MPI_Iprobe`), in
+//! which case it is told about a matching message without claiming it.
+//! Peeks go first so they see messages before these get claimed.
+
+use std::{cmp, fmt, mem};
+use std::sync::{Arc, Mutex};
+use futures::task::{self, Task};
+use libc;
+use mpi;
+use mpi::point_to_point::{Source, Status};
+use mpi::raw::AsRaw;
+use mpi::topology::Rank;
+use super::buffer::OwnedBufferMut;
+use super::cancel::CancelToken;
+use super::error::{MpiError, OrError};
+use super::request_poll::RequestPoll;
+use super::tag::Tag;
+
+/// A matched message along with its status.
+pub(crate) type Matched = (mpi::ffi::MPI_Message, Status);
+
+fn any_source() -> Rank {
+    unsafe { mpi::ffi::RSMPI_ANY_SOURCE }
+}
+
+fn any_tag() -> mpi::Tag {
+    unsafe { mpi::ffi::RSMPI_ANY_TAG }
+}
+
+/// Describes the messages of interest: those on the communicator `comm`
+/// from `source` with `tag`, either of which may be a wildcard.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Filter {
+    comm: mpi::ffi::MPI_Comm,
+    source: Rank,
+    tag: mpi::Tag,
+}
+
+// Safe because the communicator is merely used as a handle, and the
+// thread-safe switches require MPI_THREAD_MULTIPLE anyway.
+unsafe impl Send for Filter {}
+unsafe impl Sync for Filter {}
+
+impl Filter {
+    /// Match messages from `source` with the given `tag` (or any tag if
+    /// `None`).
+    pub fn new<S: Source>(source: &S, tag: Option<Tag>) -> Self {
+        Filter {
+            comm: source.as_communicator().as_raw(),
+            source: source.source_rank(),
+            tag: tag.map_or_else(any_tag, Tag::value),
+        }
+    }
+
+    pub fn specificity(&self) -> u8 {
+        (self.source != any_source()) as u8 + (self.tag != any_tag()) as u8
+    }
+
+    fn matches(&self, comm: mpi::ffi::MPI_Comm, status: &Status) -> bool {
+        self.comm == comm
+            && (self.source == any_source()
+                || self.source == status.source_rank())
+            && (self.tag == any_tag() || self.tag == status.tag())
+    }
+
+    /// Match a pending message, if any.
+    pub fn probe(&self) -> Result<Option<Matched>, MpiError> {
+        unsafe {
+            let mut flag: libc::c_int = mem::uninitialized();
+            let mut msg = mem::uninitialized();
+            let mut status = mem::uninitialized();
+            mpi::ffi::MPI_Improbe(self.source, self.tag, self.comm,
+                                  &mut flag, &mut msg, &mut status)
+                .or_error()?;
+            if flag == 0 {
+                Ok(None)
+            } else {
+                Ok(Some((msg, Status::from_raw(status))))
+            }
+        }
+    }
+
+    /// Check for a pending message without matching it.
+    pub fn peek(&self) -> Result<Option<Status>, MpiError> {
+        unsafe {
+            let mut flag: libc::c_int = mem::uninitialized();
+            let mut status = mem::uninitialized();
+            mpi::ffi::MPI_Iprobe(self.source, self.tag, self.comm,
+                                 &mut flag, &mut status).or_error()?;
+            if flag == 0 {
+                Ok(None)
+            } else {
+                Ok(Some(Status::from_raw(status)))
+            }
+        }
+    }
+
+    /// Post a receive via `MPI_Irecv` for a message that passes the filter.
+    /// See `RequestPoll::irecv_raw`.
+    pub fn post<'a, B, F>(&self, request_poll: &mut RequestPoll<'a>, buf: B,
+                          token: Option<CancelToken>, callback: F)
+        where B: OwnedBufferMut,
+              B::Anchor: 'a,
+              F: FnOnce(B::Anchor, Result<Status, MpiError>) + 'a,
+    {
+        unsafe {
+            request_poll.irecv_raw(self.comm, self.source, self.tag, buf,
+                                   token, callback);
+        }
+    }
+}
+
+/// Receive a matched message into an empty buffer and throw it away.
+///
+/// The message gets truncated, but that is all the sender needs to complete
+/// its send.  Nobody wants the message, so errors (including the truncation
+/// itself) are ignored.
+pub(crate) fn discard(request_poll: &mut RequestPoll,
+                      message: Result<Matched, MpiError>) {
+    if let Ok((msg, status)) = message {
+        unsafe {
+            request_poll.mrecv_raw(msg, &status, Vec::<u8>::new(),
+                                   |_, _| ());
+        }
+    }
+}
+
+#[derive(Default)]
+struct Mailbox {
+    // either a matched message or the error that occurred while probing
+    // (peeks get a null message handle)
+    message: Option<Result<Matched, MpiError>>,
+    // set if messages are only peeked at
+    peek: bool,
+    task: Option<Task>,
+    // set by the stream when it goes away
+    closed: bool,
+    // set by the switch when it stops dispatching
+    ended: bool,
+}
+
+// Safe because the message is merely used as a handle, and the thread-safe
+// switches require MPI_THREAD_MULTIPLE anyway.
+unsafe impl Send for Mailbox {}
+
+impl Mailbox {
+    fn is_waiting(&self) -> bool {
+        self.task.is_some() && self.is_free()
+    }
+
+    // whether a message can be delivered, even if nobody is waiting for it
+    // right now
+    fn is_free(&self) -> bool {
+        self.message.is_none() && !self.closed && !self.ended
+    }
+
+    fn deliver(&mut self, message: Result<Matched, MpiError>) {
+        self.message = Some(message);
+        self.task.take().map(|task| task.unpark());
+    }
+
+    /// Throw away the undelivered message, if any.
+    fn clear(&mut self, request_poll: &mut RequestPoll) {
+        if let Some(message) = self.message.take() {
+            // a peeked message belongs to someone else
+            if !self.peek {
+                discard(request_poll, message);
+            }
+        }
+    }
+}
+
+/// The receiving end of a subscription, owned by an `Incoming` stream (or
+/// anything else waiting for messages).
+pub(crate) struct Subscription(Arc<Mutex<Mailbox>>);
+
+impl fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Subscription")
+    }
+}
+
+impl Subscription {
+    /// Take the message delivered to this subscription, or else arrange for
+    /// the current task to be notified once one is delivered.
+    pub fn take(&self) -> Option<Result<Matched, MpiError>> {
+        let mut mailbox = self.0.lock().unwrap();
+        let message = mailbox.message.take();
+        if message.is_none() && !mailbox.ended {
+            mailbox.task = Some(task::park());
+        }
+        message
+    }
+
+    /// Whether the switch will no longer deliver messages.
+    pub fn is_ended(&self) -> bool {
+        self.0.lock().unwrap().ended
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // the switch will clean up on its next round
+        self.0.lock().unwrap().closed = true;
+    }
+}
+
+/// Ability to subscribe to the messages dispatched by a switch.
+pub(crate) trait Subscribe {
+    /// Register interest in messages that pass the `filter`, if the switch
+    /// is still alive and accepting work.  See `Dispatcher::subscribe`.
+    fn subscribe(&self, filter: Filter, peek: bool) -> Option<Subscription>;
+}
+
+/// Keeps track of the subscriptions of a switch.
+#[derive(Default)]
+pub(crate) struct Dispatcher {
+    // in order of subscription
+    entries: Vec<(Filter, Arc<Mutex<Mailbox>>)>,
+}
+
+impl fmt::Debug for Dispatcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let filters: Vec<_> = self.entries.iter().map(|e| e.0).collect();
+        f.debug_struct("Dispatcher")
+            .field("filters", &filters)
+            .finish()
+    }
+}
+
+impl Dispatcher {
+    /// Subscribe to messages that pass the `filter`.  If `peek` is set,
+    /// the messages are only peeked at and left to be matched by others.
+    pub fn subscribe(&mut self, filter: Filter, peek: bool) -> Subscription {
+        let mailbox = Arc::new(Mutex::new(Mailbox {
+            peek: peek,
+            ..Mailbox::default()
+        }));
+        self.entries.push((filter, mailbox.clone()));
+        Subscription(mailbox)
+    }
+
+    /// Whether there are no subscriptions at all, waiting or not.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Probe for messages on behalf of the subscriptions that are waiting.
+    /// Returns whether any subscription was waiting.
+    pub fn dispatch(&mut self, request_poll: &mut RequestPoll) -> bool {
+        let mut orphans = Vec::new();
+        self.entries.retain(|&(filter, ref mailbox)| {
+            let mut mailbox = mailbox.lock().unwrap();
+            // a peeked message belongs to someone else
+            if mailbox.closed && !mailbox.peek {
+                if let Some(Ok((msg, status))) = mailbox.message.take() {
+                    orphans.push((filter.comm, msg, status));
+                }
+            }
+            !mailbox.closed
+        });
+        // the stream is gone, but another one may still want the message
+        for (comm, msg, status) in orphans {
+            match self.recipient(comm, &status, Mailbox::is_free) {
+                Some(mailbox) =>
+                    mailbox.lock().unwrap().deliver(Ok((msg, status))),
+                None => discard(request_poll, Ok((msg, status))),
+            }
+        }
+        let mut peeking = false;
+        let mut filters = Vec::new();
+        for &(filter, ref mailbox) in &self.entries {
+            let mut mailbox = mailbox.lock().unwrap();
+            if !mailbox.is_waiting() {
+                continue;
+            }
+            if mailbox.peek {
+                peeking = true;
+                match filter.peek() {
+                    Ok(None) => {}
+                    Ok(Some(status)) => {
+                        let msg = unsafe { mpi::ffi::RSMPI_MESSAGE_NULL };
+                        mailbox.deliver(Ok((msg, status)));
+                    }
+                    Err(err) => mailbox.deliver(Err(err)),
+                }
+            } else if !filters.contains(&filter) {
+                filters.push(filter);
+            }
+        }
+        if filters.is_empty() {
+            return peeking;
+        }
+        // probe with the most specific filters first so that wildcard
+        // subscriptions don't steal messages meant for more specific ones
+        filters.sort_by_key(|filter| cmp::Reverse(filter.specificity()));
+        for filter in filters {
+            while self.is_wanted(&filter) {
+                match filter.probe() {
+                    Ok(None) => break,
+                    Ok(Some((msg, status))) =>
+                        self.deliver(request_poll, filter.comm, msg, status),
+                    Err(err) => self.fail(&filter, err),
+                }
+            }
+        }
+        true
+    }
+
+    fn is_wanted(&self, filter: &Filter) -> bool {
+        self.entries.iter().any(|&(f, ref mailbox)| {
+            let mailbox = mailbox.lock().unwrap();
+            f == *filter && mailbox.is_waiting() && !mailbox.peek
+        })
+    }
+
+    /// Among the subscriptions that match and are `ready` for a message,
+    /// pick the most specific one, then the earliest one.
+    fn recipient(&self, comm: mpi::ffi::MPI_Comm, status: &Status,
+                 ready: fn(&Mailbox) -> bool)
+                 -> Option<&Arc<Mutex<Mailbox>>> {
+        self.entries.iter()
+            .filter(|&&(filter, ref mailbox)| {
+                let mailbox = mailbox.lock().unwrap();
+                filter.matches(comm, status) && ready(&mailbox)
+                    && !mailbox.peek
+            })
+            .min_by_key(|&&(filter, _)| cmp::Reverse(filter.specificity()))
+            .map(|&(_, ref mailbox)| mailbox)
+    }
+
+    fn deliver(&mut self, request_poll: &mut RequestPoll,
+               comm: mpi::ffi::MPI_Comm, msg: mpi::ffi::MPI_Message,
+               status: Status) {
+        // the filter we probed with belonged to a waiting subscription, but
+        // on a thread-safe switch its stream may have gone away since
+        match self.recipient(comm, &status, Mailbox::is_waiting) {
+            Some(mailbox) =>
+                mailbox.lock().unwrap().deliver(Ok((msg, status))),
+            None => discard(request_poll, Ok((msg, status))),
+        }
+    }
+
+    /// Report a probing error to every waiting subscription with `filter`.
+    fn fail(&mut self, filter: &Filter, err: MpiError) {
+        for &(f, ref mailbox) in &self.entries {
+            let mut mailbox = mailbox.lock().unwrap();
+            if f == *filter && mailbox.is_waiting() && !mailbox.peek {
+                mailbox.deliver(Err(err.clone()));
+            }
+        }
+    }
+
+    /// Discard all undelivered messages, end every subscription, and notify
+    /// every waiting task.
+    pub fn close(&mut self, request_poll: &mut RequestPoll) {
+        for (_, mailbox) in self.entries.drain(..) {
+            let mut mailbox = mailbox.lock().unwrap();
+            mailbox.ended = true;
+            mailbox.clear(request_poll);
+            mailbox.task.take().map(|task| task.unpark());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use mpi;
+    use mpi::point_to_point::Status;
+    use mpi::topology::Rank;
+    use super::{any_source, any_tag, Filter};
+
+    // the handles are link-time constants, so MPI needn't be initialized
+    fn world() -> mpi::ffi::MPI_Comm {
+        unsafe { mpi::ffi::RSMPI_COMM_WORLD }
+    }
+
+    fn filter(source: Rank, tag: mpi::Tag) -> Filter {
+        Filter {
+            comm: world(),
+            source: source,
+            tag: tag,
+        }
+    }
+
+    fn status(source: Rank, tag: mpi::Tag) -> Status {
+        let mut status: mpi::ffi::MPI_Status = unsafe { mem::zeroed() };
+        status.MPI_SOURCE = source;
+        status.MPI_TAG = tag;
+        Status::from_raw(status)
+    }
+
+    #[test]
+    fn specificity() {
+        assert_eq!(filter(any_source(), any_tag()).specificity(), 0);
+        assert_eq!(filter(1, any_tag()).specificity(), 1);
+        assert_eq!(filter(any_source(), 2).specificity(), 1);
+        assert_eq!(filter(1, 2).specificity(), 2);
+    }
+
+    #[test]
+    fn matches() {
+        let status = status(1, 2);
+        assert!(filter(any_source(), any_tag()).matches(world(), &status));
+        assert!(filter(1, any_tag()).matches(world(), &status));
+        assert!(filter(any_source(), 2).matches(world(), &status));
+        assert!(filter(1, 2).matches(world(), &status));
+        assert!(!filter(0, any_tag()).matches(world(), &status));
+        assert!(!filter(any_source(), 3).matches(world(), &status));
+        assert!(!filter(1, 3).matches(world(), &status));
+        let other = unsafe { mpi::ffi::RSMPI_COMM_SELF };
+        assert!(!filter(1, 2).matches(other, &status));
+    }
+}
@@ -0,0 +1,146 @@
+//! Errors reported by MPI.
+//!
+//! Creating a switch sets the error handler of `MPI_COMM_WORLD` to
+//! `MPI_ERRORS_RETURN`, so a failed operation results in an `MpiError` being
+//! returned through the corresponding future or stream rather than the whole
+//! job being aborted.  Communicators created from it afterwards inherit the
+//! error handler; any other communicator (e.g. `MPI_COMM_SELF`, or one
+//! created before the switch) needs `set_errors_return` to get the same
+//! behavior.
+
+use std::{error, fmt};
+use libc;
+use mpi;
+use mpi::topology::Communicator;
+
+/// An error reported by MPI.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MpiError {
+    code: libc::c_int,
+    class: libc::c_int,
+    message: String,
+    cancelled: bool,
+}
+
+impl MpiError {
+    /// Look up the class and description of the given error code.
+    pub fn from_code(code: libc::c_int) -> Self {
+        let mut class = code;
+        let mut buf =
+            [0 as libc::c_char; mpi::ffi::MPI_MAX_ERROR_STRING as usize];
+        let mut len = 0;
+        unsafe {
+            if mpi::ffi::MPI_Error_class(code, &mut class) != 0 {
+                class = code;
+            }
+            if mpi::ffi::MPI_Error_string(code, buf.as_mut_ptr(),
+                                          &mut len) != 0 {
+                len = 0;
+            }
+        }
+        let bytes: Vec<u8> =
+            buf[..len as usize].iter().map(|&c| c as u8).collect();
+        MpiError {
+            code: code,
+            class: class,
+            message: String::from_utf8_lossy(&bytes).into_owned(),
+            cancelled: false,
+        }
+    }
+
+    /// The error passed on for a receive that was cancelled before a
+    /// message arrived.
+    pub(crate) fn cancelled() -> Self {
+        let code = mpi::ffi::MPI_ERR_OTHER as libc::c_int;
+        let mut err = MpiError::from_code(code).context("receive cancelled");
+        err.cancelled = true;
+        err
+    }
+
+    /// Prefix the description with some context.
+    pub(crate) fn context(mut self, context: &str) -> Self {
+        self.message = format!("{}: {}", context, self.message);
+        self
+    }
+
+    /// The error code as returned by MPI.
+    pub fn code(&self) -> libc::c_int {
+        self.code
+    }
+
+    /// The error class (one of the `MPI_ERR_*` constants).
+    pub fn class(&self) -> libc::c_int {
+        self.class
+    }
+
+    /// Whether the request was cancelled (e.g. by a shutdown) rather than
+    /// having failed.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled
+    }
+
+    /// The description provided by `MPI_Error_string`.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for MpiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (MPI error class {})", self.message, self.class)
+    }
+}
+
+impl error::Error for MpiError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+pub(crate) trait OrError {
+    fn or_error(self) -> Result<(), MpiError>;
+}
+
+impl OrError for libc::c_int {
+    fn or_error(self) -> Result<(), MpiError> {
+        if self == mpi::ffi::MPI_SUCCESS as libc::c_int {
+            Ok(())
+        } else {
+            Err(MpiError::from_code(self))
+        }
+    }
+}
+
+/// The error with which requests fail if their switch is gone or has shut
+/// down before they could complete.
+pub(crate) fn switch_gone() -> MpiError {
+    let code = mpi::ffi::MPI_ERR_OTHER as libc::c_int;
+    MpiError::from_code(code).context("switch is no longer running")
+}
+
+pub(crate) fn set_errors_return_raw(comm: mpi::ffi::MPI_Comm)
+                                    -> Result<(), MpiError> {
+    unsafe {
+        mpi::ffi::MPI_Comm_set_errhandler(comm,
+                                          mpi::ffi::RSMPI_ERRORS_RETURN)
+            .or_error()
+    }
+}
+
+/// Set the error handler of `MPI_COMM_WORLD` to `MPI_ERRORS_RETURN`, as done
+/// by every switch when it is created.  Communicators derived from it from
+/// now on inherit the error handler.  If this fails, errors simply abort as
+/// they used to.
+pub(crate) fn set_errors_return_world() {
+    let _ = set_errors_return_raw(unsafe { mpi::ffi::RSMPI_COMM_WORLD });
+}
+
+/// Set the error handler of `comm` to `MPI_ERRORS_RETURN`.
+///
+/// This is done automatically for `MPI_COMM_WORLD` (and hence the
+/// communicators later created from it) when a switch is created.  Any other
+/// communicator must be set up with this before it is used with a switch, or
+/// else its errors abort the job.
+pub fn set_errors_return<C: Communicator>(comm: &C) -> Result<(), MpiError> {
+    set_errors_return_raw(comm.as_raw())
+}
@@ -0,0 +1,256 @@
+//! Policies for what a switch does when polling it made no progress.
+
+use std::{cmp, thread};
+use std::time::{Duration, Instant};
+use super::request_poll::RequestPoll;
+
+/// Determines what a switch does when a poll did not complete any requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IdlePolicy {
+    /// Immediately ask to be polled again.  This has the lowest latency but
+    /// keeps a core busy even when nothing is in flight.
+    Spin,
+    /// Yield the thread to the OS before asking to be polled again.
+    Yield,
+    /// Sleep for `min`, doubling the duration on every consecutive idle poll
+    /// up to `max`.  The duration is reset as soon as a request completes or
+    /// a new one is submitted.  If `min` is zero, the doubling starts from a
+    /// microsecond instead.
+    Backoff { min: Duration, max: Duration },
+    /// Block in `RequestPoll::wait` until a request completes.  This only
+    /// happens if requests are pending and no `Incoming` stream or timeout
+    /// is waiting; otherwise, the thread is yielded instead.
+    ///
+    /// Use this only if the executor has nothing else to do in the meantime,
+    /// since the blocking prevents other tasks on the same thread from
+    /// submitting requests.  A thread-safe switch blocks without holding its
+    /// lock, so other threads can still submit requests, but these are only
+    /// noticed once one of the earlier ones completes.
+    ///
+    /// The progress thread of a `progress::Switch` is woken up whenever
+    /// something new is submitted, and blocks only for a short while instead
+    /// of yielding if a stream or timeout is waiting.
+    Block,
+}
+
+impl Default for IdlePolicy {
+    fn default() -> Self {
+        IdlePolicy::Spin
+    }
+}
+
+// the smallest duration that a backoff doubles, so that it grows even if it
+// starts from zero
+fn min_backoff_step() -> Duration {
+    Duration::new(0, 1_000)
+}
+
+/// Counters describing how much time a switch has spent idling.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct IdleStats {
+    /// Number of times the switch was polled.
+    pub polls: u64,
+    /// Number of polls that did not complete any requests.
+    pub idle_polls: u64,
+    /// Total time spent yielding, sleeping, or blocking.
+    pub idle_time: Duration,
+}
+
+/// What a switch does after a poll, as decided by `Idler::plan`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Idle {
+    /// Ask to be polled again right away.
+    Spin,
+    /// Yield the thread to the OS first.
+    Yield,
+    /// Sleep for the given duration first.
+    Sleep(Duration),
+    /// Block until a request of the `RequestPoll` completes.
+    Block,
+}
+
+impl Idle {
+    /// Yield or sleep as planned.  Blocking is left to the caller, who has
+    /// access to the requests.
+    pub fn pause(self) {
+        match self {
+            Idle::Spin | Idle::Block => {}
+            Idle::Yield => thread::yield_now(),
+            Idle::Sleep(duration) => thread::sleep(duration),
+        }
+    }
+}
+
+/// Tracks the state of an `IdlePolicy`.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Idler {
+    policy: IdlePolicy,
+    backoff: Option<Duration>,
+    poked: bool,
+    stats: IdleStats,
+}
+
+impl Idler {
+    pub fn new(policy: IdlePolicy) -> Self {
+        Self {
+            policy: policy,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: IdlePolicy) {
+        self.policy = policy;
+        self.backoff = None;
+    }
+
+    pub fn stats(&self) -> IdleStats {
+        self.stats
+    }
+
+    /// Signal that there is new work, so any backoff should be reset.
+    pub fn poke(&mut self) {
+        self.poked = true;
+    }
+
+    /// Decide what to do after a `RequestPoll::test` that completed
+    /// `completed` requests.  `probing` indicates whether any `Incoming`
+    /// stream is waiting for messages or any timer is pending, and
+    /// `pending` whether there are any requests left.
+    pub fn plan(&mut self, completed: usize, probing: bool, pending: bool)
+                -> Idle {
+        self.stats.polls += 1;
+        if completed != 0 || self.poked {
+            self.backoff = None;
+            self.poked = false;
+            return Idle::Spin;
+        }
+        self.stats.idle_polls += 1;
+        match self.policy {
+            IdlePolicy::Spin => Idle::Spin,
+            IdlePolicy::Yield => Idle::Yield,
+            IdlePolicy::Backoff { min, max } => {
+                let duration = match self.backoff {
+                    None => min,
+                    Some(duration) => {
+                        let step = cmp::max(duration, min_backoff_step());
+                        cmp::min(step.checked_mul(2).unwrap_or(max), max)
+                    }
+                };
+                self.backoff = Some(duration);
+                Idle::Sleep(duration)
+            }
+            IdlePolicy::Block if probing || !pending => Idle::Yield,
+            IdlePolicy::Block => Idle::Block,
+        }
+    }
+
+    /// Account for time spent idling as planned.
+    pub fn record(&mut self, idle_time: Duration) {
+        self.stats.idle_time += idle_time;
+    }
+
+    /// Called after each `RequestPoll::test` to carry out the plan right
+    /// away (see `plan`).
+    pub fn after_test(&mut self, completed: usize, probing: bool,
+                      request_poll: &mut RequestPoll) {
+        let idle = self.plan(completed, probing, !request_poll.is_empty());
+        if idle == Idle::Spin {
+            return;
+        }
+        let start = Instant::now();
+        match idle {
+            Idle::Block => {
+                request_poll.wait();
+            }
+            _ => idle.pause(),
+        }
+        self.record(start.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{Idle, IdlePolicy, Idler};
+
+    fn micros(n: u64) -> Duration {
+        Duration::new(0, (n * 1_000) as u32)
+    }
+
+    #[test]
+    fn spin_and_yield() {
+        let mut idler = Idler::new(IdlePolicy::Spin);
+        assert_eq!(idler.plan(0, false, true), Idle::Spin);
+        idler.set_policy(IdlePolicy::Yield);
+        assert_eq!(idler.plan(0, false, true), Idle::Yield);
+        assert_eq!(idler.plan(1, false, true), Idle::Spin);
+        let stats = idler.stats();
+        assert_eq!(stats.polls, 3);
+        assert_eq!(stats.idle_polls, 2);
+    }
+
+    #[test]
+    fn block_only_without_probing() {
+        let mut idler = Idler::new(IdlePolicy::Block);
+        assert_eq!(idler.plan(0, false, true), Idle::Block);
+        assert_eq!(idler.plan(0, true, true), Idle::Yield);
+        assert_eq!(idler.plan(0, false, false), Idle::Yield);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let mut idler = Idler::new(IdlePolicy::Backoff {
+            min: micros(10),
+            max: micros(50),
+        });
+        assert_eq!(idler.plan(0, false, true), Idle::Sleep(micros(10)));
+        assert_eq!(idler.plan(0, false, true), Idle::Sleep(micros(20)));
+        assert_eq!(idler.plan(0, false, true), Idle::Sleep(micros(40)));
+        assert_eq!(idler.plan(0, false, true), Idle::Sleep(micros(50)));
+        assert_eq!(idler.plan(0, false, true), Idle::Sleep(micros(50)));
+    }
+
+    #[test]
+    fn backoff_from_zero() {
+        let mut idler = Idler::new(IdlePolicy::Backoff {
+            min: Duration::new(0, 0),
+            max: micros(3),
+        });
+        assert_eq!(idler.plan(0, false, true), Idle::Sleep(micros(0)));
+        assert_eq!(idler.plan(0, false, true), Idle::Sleep(micros(2)));
+        assert_eq!(idler.plan(0, false, true), Idle::Sleep(micros(3)));
+    }
+
+    #[test]
+    fn backoff_overflow() {
+        let max = Duration::new(u64::max_value(), 999_999_999);
+        let mut idler = Idler::new(IdlePolicy::Backoff {
+            min: Duration::new(u64::max_value() / 2 + 1, 0),
+            max: max,
+        });
+        idler.plan(0, false, true);
+        assert_eq!(idler.plan(0, false, true), Idle::Sleep(max));
+        assert_eq!(idler.plan(0, false, true), Idle::Sleep(max));
+    }
+
+    #[test]
+    fn backoff_reset() {
+        let mut idler = Idler::new(IdlePolicy::Backoff {
+            min: micros(10),
+            max: micros(1_000),
+        });
+        idler.plan(0, false, true);
+        idler.plan(0, false, true);
+        assert_eq!(idler.plan(1, false, true), Idle::Spin);
+        assert_eq!(idler.plan(0, false, true), Idle::Sleep(micros(10)));
+        assert_eq!(idler.plan(0, false, true), Idle::Sleep(micros(20)));
+        idler.poke();
+        assert_eq!(idler.plan(0, false, true), Idle::Spin);
+        assert_eq!(idler.plan(0, false, true), Idle::Sleep(micros(10)));
+        idler.set_policy(IdlePolicy::Backoff {
+            min: micros(5),
+            max: micros(1_000),
+        });
+        assert_eq!(idler.plan(0, false, true), Idle::Sleep(micros(5)));
+    }
+}
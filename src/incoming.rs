@@ -1,82 +1,195 @@
-use futures::{task, Async, Future, Poll, Stream};
-use futures::unsync::oneshot;
-use mpi::point_to_point::{Message, Source, Status};
-use void::Void;
+use std::marker::PhantomData;
+use std::time::Duration;
+use futures::{Async, Future, Poll, Stream};
+use futures::sync::oneshot;
+use mpi;
+use mpi::point_to_point::{Source, Status};
 use super::buffer::Unanchor;
+use super::cancel::Cancel;
 use super::codec::{Decoder, RecvInto};
+use super::error::{MpiError, switch_gone};
+use super::dispatch::{Filter, Subscribe, Subscription};
 use super::request_poll::RequestPoll;
 use super::switch::Link;
+use super::tag::Tag;
+use super::timeout::{TimeoutStream, Timers};
 
 /// Represents a stream of incoming messages.
 ///
 /// ```ignore
 /// Incoming<Source, Decoder>: Stream<Future<(Status, Message)>>
 /// ```
+///
+/// The stream subscribes to its switch when first polled.  From then on, the
+/// switch probes for messages on its behalf whenever the stream is waiting
+/// for one, so several streams with different filters can safely coexist.
+///
+/// The link type `L` determines which kind of switch the messages are
+/// received through.
 #[derive(Debug)]
 #[must_use = "streams do nothing unless polled"]
-pub struct Incoming<'a, C: Decoder<'a>, S: Source> {
-    link: Link<'a>,
+pub struct Incoming<'a, C: Decoder<'a>, S: Source, L = Link<'a>> {
+    link: L,
     codec: C,
+    // holds on to the communicator for as long as the stream exists
+    #[allow(dead_code)]
     source: S,
+    filter: Filter,
+    subscription: Option<Subscription>,
+    phantom: PhantomData<&'a ()>,
 }
 
-impl<'a, C: Decoder<'a>, S: Source> Incoming<'a, C, S> {
-    pub fn new(link: Link<'a>, codec: C, source: S) -> Self {
+impl<'a, C: Decoder<'a>, S: Source, L> Incoming<'a, C, S, L> {
+    pub fn new(link: L, codec: C, source: S) -> Self {
+        Self::with_filter(link, codec, source, None)
+    }
+
+    /// Create an `Incoming` that only accepts messages with the given tag.
+    pub fn with_tag(link: L, codec: C, source: S, tag: Tag) -> Self {
+        Self::with_filter(link, codec, source, Some(tag))
+    }
+
+    fn with_filter(link: L, codec: C, source: S, tag: Option<Tag>)
+                   -> Self {
         Self {
-            link: link.clone(),
+            filter: Filter::new(&source, tag),
+            link: link,
             codec: codec,
             source: source,
+            subscription: None,
+            phantom: PhantomData,
         }
     }
+
+    /// Fail with `TimeoutError::Elapsed` whenever no message arrives within
+    /// `timeout` of polling for one.  The stream can still be polled after
+    /// a timeout.
+    pub fn timeout(self, timeout: Duration) -> TimeoutStream<Self, L>
+        where L: Timers + Clone
+    {
+        let timers = self.link.clone();
+        TimeoutStream::new(timers, self, timeout)
+    }
+}
+
+/// Start receiving a matched message using `codec`.
+///
+/// The `status` must be the one obtained when `msg` was matched.
+pub(crate) unsafe fn decode_matched<'a, C>(codec: &mut C,
+                                           msg: mpi::ffi::MPI_Message,
+                                           status: Status,
+                                           request_poll: &mut RequestPoll<'a>)
+                                           -> WithStatus<C::FutureMessage>
+    where C: Decoder<'a>
+{
+    let recv_into = RecvIntoImpl {
+        request_poll: request_poll,
+        msg: msg,
+        // unsafe invariant: status must be associated with the correct
+        // message or else recv_into_vec is unsafe
+        status: status,
+    };
+    let ((), fut_msg) = codec.decode(recv_into);
+    WithStatus(status, fut_msg)
 }
 
-impl<'a, C: Decoder<'a>, S: Source> Stream for Incoming<'a, C, S> {
+/// Match a pending message that passes the `filter`, if any, and start
+/// receiving it using `codec`.
+pub(crate) fn receive_matched<'a, C>(codec: &mut C, filter: &Filter,
+                                     request_poll: &mut RequestPoll<'a>)
+    -> Result<Option<WithStatus<C::FutureMessage>>, MpiError>
+    where C: Decoder<'a>
+{
+    Ok(filter.probe()?.map(|(msg, status)| unsafe {
+        decode_matched(codec, msg, status, request_poll)
+    }))
+}
+
+/// Start receiving the message delivered to `subscription`, if any.
+pub(crate) fn poll_matched<'a, C>(codec: &mut C, subscription: &Subscription,
+                                  request_poll: Option<&mut RequestPoll<'a>>)
+    -> Poll<Option<WithStatus<C::FutureMessage>>, MpiError>
+    where C: Decoder<'a>
+{
+    match request_poll {
+        None => Ok(Async::Ready(None)),
+        Some(request_poll) => match subscription.take() {
+            Some(Ok((msg, status))) => Ok(Async::Ready(Some(unsafe {
+                decode_matched(codec, msg, status, request_poll)
+            }))),
+            Some(Err(err)) => Err(err),
+            None if subscription.is_ended() => Ok(Async::Ready(None)),
+            None => Ok(Async::NotReady),
+        },
+    }
+}
+
+/// Ability to receive the messages delivered to a subscription using codecs
+/// of type `C`.  The thread-safe switches only accept `SyncDecoder`s.
+pub(crate) trait PollIncoming<'a, C: Decoder<'a>> {
+    /// Start receiving the message delivered to `subscription`, if any.
+    /// See `poll_matched`.
+    fn poll_incoming(&self, codec: &mut C, subscription: &Subscription)
+                     -> Poll<Option<WithStatus<C::FutureMessage>>, MpiError>;
+}
+
+impl<'a, C, S, L> Stream for Incoming<'a, C, S, L>
+    where C: Decoder<'a>,
+          S: Source,
+          L: Subscribe + PollIncoming<'a, C>,
+{
     type Item = WithStatus<C::FutureMessage>;
-    type Error = Void;
+    type Error = MpiError;
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let codec = &mut self.codec;
-        let source = &self.source;
-        self.link.modify_request_poll(|request_poll| match request_poll {
-            None => Ok(Async::Ready(None)),
-            Some(request_poll) => match source.immediate_matched_probe() {
-                Some((msg, status)) => {
-                    let recv_into = RecvIntoImpl {
-                        request_poll: request_poll,
-                        msg: msg,
-                        // unsafe invariant: status must be associated with
-                        // the correct message or else recv_into_vec is unsafe
-                        status: status,
-                    };
-                    let ((), fut_msg) = codec.decode(recv_into);
-                    Ok(Async::Ready(Some(WithStatus(status, fut_msg))))
-                }
-                None => {
-                    task::park().unpark();
-                    Ok(Async::NotReady)
-                }
-            },
-        })
+        if self.subscription.is_none() {
+            match self.link.subscribe(self.filter, false) {
+                None => return Ok(Async::Ready(None)),
+                subscription => self.subscription = subscription,
+            }
+        }
+        let subscription = self.subscription.as_ref().unwrap();
+        self.link.poll_incoming(&mut self.codec, subscription)
     }
 }
 
 // FutureBuffer needs to be its own concrete type because associated type
 // constructors don't exist yet :(
-pub struct FutureBuffer<B>(oneshot::Receiver<B>);
+//
+/// Future of a buffer being received into.
+///
+/// The message has already been matched by the time this future exists, and
+/// MPI cannot cancel the receive of a matched message, so the receive always
+/// runs to completion, even if the future is dropped.  To stop receiving,
+/// drop the `Incoming` stream instead: messages that have yet to be matched
+/// are then left alone.  Pre-posted and persistent receives (see
+/// `Preposted` and `PersistentChannel::recv_init`) can be cancelled too.
+pub struct FutureBuffer<B> {
+    receiver: oneshot::Receiver<Result<B, MpiError>>,
+}
 
 impl<B> Future for FutureBuffer<B> {
     type Item = B;
-    type Error = Void;
+    type Error = MpiError;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        match self.0.poll() {
-            Err(oneshot::Canceled) => panic!("sender cancelled"),
-            Ok(r) => Ok(r),
+        match self.receiver.poll() {
+            // the request was dropped along with the switch
+            Err(oneshot::Canceled) => Err(switch_gone()),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(result)) => result.map(Async::Ready),
         }
     }
 }
 
+impl<B> Cancel for FutureBuffer<B> {
+    fn request_cancel(&mut self) {
+        // the message is already matched, so the receive can only be left to
+        // complete in the background
+    }
+}
+
 struct RecvIntoImpl<'b, 'a: 'b> {
     request_poll: &'b mut RequestPoll<'a>,
-    msg: Message,
+    msg: mpi::ffi::MPI_Message,
     status: Status,
 }
 
@@ -92,10 +205,19 @@ impl<'b, 'a> RecvInto<'a> for RecvIntoImpl<'b, 'a> {
     fn recv_into<B: Unanchor + 'a>(self, buf: B)
                                    -> (Self::Output, FutureBuffer<B>) {
         let (sender, receiver) = oneshot::channel();
-        self.request_poll.mrecv(self.msg, buf, move |anchor| {
-            let _ = sender.send(B::unanchor(anchor));
-        });
-        ((), FutureBuffer(receiver))
+        // safe because RecvIntoImpl is only ever constructed from a freshly
+        // matched message
+        unsafe {
+            self.request_poll.mrecv_raw(self.msg, &self.status, buf,
+                                        move |anchor, res| {
+                // the buffer must be unanchored even if the receive failed
+                let buf = B::unanchor(anchor);
+                let _ = sender.send(res.map(|()| buf));
+            });
+        }
+        ((), FutureBuffer {
+            receiver: receiver,
+        })
     }
 }
 
@@ -117,3 +239,62 @@ impl<F: Future> Future for WithStatus<F> {
         }
     }
 }
+
+impl<F: Future + Cancel> Cancel for WithStatus<F> {
+    fn request_cancel(&mut self) {
+        self.1.request_cancel()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::mem;
+    use futures::Future;
+    use futures::sync::oneshot;
+    use mpi;
+    use mpi::point_to_point::Status;
+    use super::{FutureBuffer, WithStatus};
+    use super::super::timeout::{Timeout, TimeoutError, Timer, TimerQueue,
+                                Timers};
+
+    struct Queue(RefCell<TimerQueue>);
+
+    impl Timers for Queue {
+        fn timer(&self, deadline: f64) -> Option<Timer> {
+            Some(self.0.borrow_mut().add(deadline))
+        }
+    }
+
+    fn status() -> Status {
+        Status::from_raw(unsafe { mem::zeroed::<mpi::ffi::MPI_Status>() })
+    }
+
+    #[test]
+    fn receive_before_deadline() {
+        let queue = Queue(RefCell::new(TimerQueue::default()));
+        let (sender, receiver) = oneshot::channel();
+        let recv = WithStatus(status(), FutureBuffer { receiver: receiver });
+        let timeout = Timeout::new(&queue, recv, 1.0);
+        sender.send(Ok(vec![1u8, 2])).unwrap();
+        queue.0.borrow_mut().fire_at(1.0);
+        let (_, buf) = timeout.wait().unwrap();
+        assert_eq!(buf, vec![1, 2]);
+    }
+
+    #[test]
+    fn receive_after_deadline() {
+        let queue = Queue(RefCell::new(TimerQueue::default()));
+        let (sender, receiver) = oneshot::channel::<Result<Vec<u8>, _>>();
+        let recv = WithStatus(status(), FutureBuffer { receiver: receiver });
+        let timeout = Timeout::new(&queue, recv, 1.0);
+        assert!(!queue.0.borrow_mut().fire_at(1.0));
+        match timeout.wait() {
+            Err(TimeoutError::Elapsed) => {}
+            _ => panic!("receive did not time out"),
+        }
+        // the future was dropped upon expiry, so the received buffer would
+        // just be thrown away
+        assert!(sender.send(Ok(vec![1, 2])).is_err());
+    }
+}
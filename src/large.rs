@@ -0,0 +1,166 @@
+//! Support for messages with more than `c_int::MAX` elements.
+//!
+//! MPI counts are `c_int`, so a buffer that is too large is instead
+//! described as a single element of a derived datatype that covers the
+//! whole buffer (a vector of maximal blocks followed by the remainder).  The
+//! type signature is the same as that of the individual elements, so either
+//! side may use either description.
+//!
+//! On the receiving side, counts that do not fit into `c_int` are obtained
+//! via `MPI_Get_elements_x`, which only works for predefined datatypes.
+
+use std::mem;
+use libc;
+use mpi;
+use mpi::datatype::{AsDatatype, Collection};
+use mpi::raw::AsRaw;
+use super::error::{MpiError, OrError};
+
+/// A committed derived datatype that is freed when dropped.
+#[derive(Debug)]
+pub(crate) struct LargeType(mpi::ffi::MPI_Datatype);
+
+// the handle can be freed from any thread
+unsafe impl Send for LargeType {}
+
+impl LargeType {
+    /// Create a contiguous datatype consisting of `len` elements of
+    /// `datatype`.
+    pub fn contiguous(len: usize, datatype: mpi::ffi::MPI_Datatype)
+                      -> Result<Self, MpiError> {
+        let block = libc::c_int::max_value() as usize;
+        let (blocks, rest) = (len / block, len % block);
+        let extent = extent_of(datatype)?;
+        unsafe {
+            let mut parts = TypeGuard(Vec::with_capacity(2));
+            let mut chunks = mem::uninitialized();
+            mpi::ffi::MPI_Type_vector(blocks as libc::c_int,
+                                      block as libc::c_int,
+                                      block as libc::c_int,
+                                      datatype,
+                                      &mut chunks).or_error()?;
+            parts.0.push(chunks);
+            let mut remainder = mem::uninitialized();
+            mpi::ffi::MPI_Type_contiguous(rest as libc::c_int, datatype,
+                                          &mut remainder).or_error()?;
+            parts.0.push(remainder);
+            let blocklengths = [1, 1];
+            let displacements =
+                [0, (blocks * block) as mpi::ffi::MPI_Aint * extent];
+            let mut large = mem::uninitialized();
+            mpi::ffi::MPI_Type_create_struct(2,
+                                             blocklengths.as_ptr(),
+                                             displacements.as_ptr(),
+                                             parts.0.as_ptr(),
+                                             &mut large).or_error()?;
+            let mut large = LargeType(large);
+            mpi::ffi::MPI_Type_commit(&mut large.0).or_error()?;
+            Ok(large)
+        }
+    }
+}
+
+impl Drop for LargeType {
+    fn drop(&mut self) {
+        unsafe {
+            // nothing sensible to do if this fails
+            let _ = mpi::ffi::MPI_Type_free(&mut self.0).or_error();
+        }
+    }
+}
+
+// frees the intermediate datatypes of LargeType::contiguous
+struct TypeGuard(Vec<mpi::ffi::MPI_Datatype>);
+
+impl Drop for TypeGuard {
+    fn drop(&mut self) {
+        for datatype in &mut self.0 {
+            unsafe {
+                let _ = mpi::ffi::MPI_Type_free(datatype).or_error();
+            }
+        }
+    }
+}
+
+fn extent_of(datatype: mpi::ffi::MPI_Datatype)
+             -> Result<mpi::ffi::MPI_Aint, MpiError> {
+    let mut lb = 0;
+    let mut extent = 0;
+    unsafe {
+        mpi::ffi::MPI_Type_get_extent(datatype, &mut lb, &mut extent)
+            .or_error()?;
+    }
+    Ok(extent)
+}
+
+/// The count and datatype with which a buffer is transferred.
+#[derive(Debug)]
+pub(crate) struct Layout {
+    pub count: libc::c_int,
+    pub datatype: mpi::ffi::MPI_Datatype,
+    // must outlive the start of the request
+    pub large: Option<LargeType>,
+}
+
+/// Describe the buffer, using a `LargeType` if it has too many elements.
+pub(crate) fn layout<B>(buf: &B) -> Result<Layout, MpiError>
+    where B: Collection + AsDatatype + ?Sized
+{
+    let datatype = buf.as_datatype().as_raw();
+    // Collection::count panics if the number doesn't fit, so the number of
+    // elements is obtained from the size of the buffer instead
+    let extent = extent_of(datatype)?;
+    let len = if extent > 0 {
+        mem::size_of_val(buf) / extent as usize
+    } else {
+        0
+    };
+    if len <= libc::c_int::max_value() as usize {
+        return Ok(Layout {
+            count: buf.count(),
+            datatype: datatype,
+            large: None,
+        });
+    }
+    let large = LargeType::contiguous(len, datatype)?;
+    Ok(Layout {
+        count: 1,
+        datatype: large.0,
+        large: Some(large),
+    })
+}
+
+/// Number of elements of `datatype` in the message described by `status`.
+pub(crate) fn message_len(status: &mpi::ffi::MPI_Status,
+                          datatype: mpi::ffi::MPI_Datatype)
+                          -> Result<usize, MpiError> {
+    unsafe {
+        let mut count = 0;
+        mpi::ffi::MPI_Get_count(status, datatype, &mut count).or_error()?;
+        if count != mpi::ffi::RSMPI_UNDEFINED {
+            return Ok(count as usize);
+        }
+        // either the count doesn't fit or the message isn't made up of
+        // whole elements, which MPI_Get_elements_x can only tell apart for
+        // predefined datatypes
+        let (mut integers, mut addresses, mut datatypes, mut combiner) =
+            (0, 0, 0, 0);
+        mpi::ffi::MPI_Type_get_envelope(datatype, &mut integers,
+                                        &mut addresses, &mut datatypes,
+                                        &mut combiner).or_error()?;
+        let code = mpi::ffi::MPI_ERR_COUNT as libc::c_int;
+        if combiner != mpi::ffi::MPI_COMBINER_NAMED as libc::c_int {
+            return Err(MpiError::from_code(code).context(
+                "cannot count the elements of a derived datatype in a \
+                 message this large"));
+        }
+        let mut elements: mpi::ffi::MPI_Count = 0;
+        mpi::ffi::MPI_Get_elements_x(status, datatype, &mut elements)
+            .or_error()?;
+        if elements < 0 {
+            return Err(MpiError::from_code(code).context(
+                "message does not consist of whole elements"));
+        }
+        Ok(elements as usize)
+    }
+}
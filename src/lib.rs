@@ -1,12 +1,34 @@
 extern crate conv;
 extern crate futures;
+extern crate futures03;
 extern crate libc;
 extern crate mpi;
 extern crate void;
 
+mod alarm;
+mod attach;
 pub mod buffer;
+pub mod cancel;
 pub mod codec;
+pub mod collective;
+mod dispatch;
+pub mod error;
+pub mod idle;
 pub mod incoming;
+mod large;
+pub mod link;
+pub mod metrics;
+pub mod operation;
+pub mod persistent;
+pub mod preposted;
+pub mod probe;
+pub mod progress;
 pub mod request_poll;
 pub mod send;
+pub mod sendrecv;
+pub mod shutdown;
+pub mod std_future;
 pub mod switch;
+pub mod sync_switch;
+pub mod tag;
+pub mod timeout;
@@ -0,0 +1,775 @@
+//! Operations shared by the links of every kind of switch.
+//!
+//! Each switch comes with its own `Link` type, which only implements what is
+//! specific to that switch (e.g. `close` and `shutdown`) and gets everything
+//! else from `LinkExt`, so the trait needs to be in scope.
+
+use std::time::Duration;
+use mpi::datatype::Equivalence;
+use mpi::point_to_point::{Destination, Source};
+use mpi::topology::{Communicator, Rank};
+use super::buffer::{OwnedBuffer, Unanchor};
+use super::codec::{Decoder, Encoder};
+use super::collective::{self, AllGather, AllReduce, AllToAll, AllToAllv,
+                        AllToAllvFlat, Barrier, Broadcast, BroadcastFrom,
+                        CollectiveJob, FutureCollective, Gather, GatherTo,
+                        Gathered, Gatherv, GathervTo, Reduce, ReduceTo,
+                        Scatter, ScatterFrom, ScatterFromFlat, Scatterv,
+                        ScattervFrom, ScattervFromFlat};
+use super::error::MpiError;
+use super::incoming::Incoming;
+use super::operation::Operation;
+use super::persistent::{PersistentChannel, StartJob};
+use super::preposted::{PostJob, Preposted};
+use super::probe::{Matches, Probe};
+use super::request_poll::SendMode;
+use super::send::{Send, SendJob};
+use super::sendrecv::{Packed, ReplaceDecoder, ReplaceEncoder, SendRecv};
+use super::switch::Submit;
+use super::tag::Tag;
+use super::timeout::{self, Timeout, Timers};
+
+/// Used to perform MPI requests through a switch of any kind.
+///
+/// This is implemented by `switch::Link`, `sync_switch::Link`, and
+/// `progress::Link`.  The thread-safe links only accept codecs, buffers, and
+/// operations that can be sent to other threads (e.g. `SyncEncoder` and
+/// `SyncDecoder`).
+pub trait LinkExt<'a>: Clone {
+    /// Obtain a `Stream` of future incoming messages from the given `source`.
+    /// Each message is decoded using the given codec.
+    ///
+    /// ```ignore
+    /// fn incoming(&self, Source) -> Stream<Future<Message>>;
+    /// ```
+    ///
+    /// The stream will keep running until the `Switch` is `close`d, but you
+    /// can stop the `Stream` at any time if you aren't expecting to receive
+    /// messages.  You can even create a new `incoming` stream every time you
+    /// want to receive a message.
+    ///
+    /// Probing is done centrally by the `Switch`, and only on behalf of
+    /// streams that are waiting for a message.  If several waiting streams
+    /// match the same message, it goes to the one with the most specific
+    /// source, and among equally specific ones, to the stream that was polled
+    /// first.  Messages matched for a stream that is dropped before receiving
+    /// them are discarded.
+    fn incoming<D: Decoder<'a>, S: Source>(&self, decoder: D, source: S)
+                                           -> Incoming<'a, D, S, Self> {
+        Incoming::new(self.clone(), decoder, source)
+    }
+
+    /// Obtain a `Stream` of future incoming messages from the given `source`
+    /// that carry the given `tag`.  Messages with other tags are left for
+    /// other streams, which allows several protocols to share a
+    /// communicator.  See `incoming`.
+    ///
+    /// ```ignore
+    /// fn incoming_with_tag(&self, Source, Tag) -> Stream<Future<Message>>;
+    /// ```
+    fn incoming_with_tag<D, S>(&self, decoder: D, source: S, tag: Tag)
+                               -> Incoming<'a, D, S, Self>
+        where D: Decoder<'a>,
+              S: Source,
+    {
+        Incoming::with_tag(self.clone(), decoder, source, tag)
+    }
+
+    /// Obtain a `Stream` of messages from the given `source` (with the given
+    /// `tag`, or any tag if `None`), received into `depth` pre-posted
+    /// buffers of `size` elements each.
+    ///
+    /// ```ignore
+    /// fn incoming_preposted(&self, Source, Option<Tag>, usize, usize)
+    ///                       -> Stream<Received<T>>;
+    /// ```
+    ///
+    /// This avoids the probe that `incoming` needs for every message, which
+    /// helps with small messages of a known maximum size.  Messages larger
+    /// than `size` fail with a truncation error.  The pre-posted receives
+    /// take precedence over `incoming` streams, so their messages should be
+    /// told apart by source or tag.
+    fn incoming_preposted<T, S>(&self, source: S, tag: Option<Tag>,
+                                depth: usize, size: usize)
+                                -> Preposted<T, S, Self>
+        where T: Equivalence + Clone + Default,
+              S: Source,
+              Self: Submit<'a, PostJob<T>>,
+    {
+        Preposted::new(self.clone(), source, tag, depth, size)
+    }
+
+    /// Wait for a message from `source` with the given `tag` (or any tag if
+    /// `None`) to be pending, without receiving it.
+    ///
+    /// ```ignore
+    /// fn probe(&self, Source, Option<Tag>) -> Future<Status>;
+    /// ```
+    ///
+    /// The status tells the size and tag of the message.  Since the message
+    /// is not claimed (`MPI_Iprobe`), an `incoming` stream may receive it at
+    /// any time, possibly before the future has even been polled again.
+    fn probe<S: Source>(&self, source: S, tag: Option<Tag>)
+                        -> Probe<S, Self> {
+        Probe::new(self.clone(), source, tag)
+    }
+
+    /// Obtain a `Stream` of messages from `source` with the given `tag` (or
+    /// any tag if `None`) that have been matched but not yet received.
+    ///
+    /// ```ignore
+    /// fn incoming_matched(&self, Source, Option<Tag>)
+    ///                     -> Stream<PendingMessage>;
+    /// ```
+    ///
+    /// Messages are matched just as for `incoming`, after which each one can
+    /// be received with a decoder of choice, kept around to be received
+    /// later, or discarded.
+    fn incoming_matched<S: Source>(&self, source: S, tag: Option<Tag>)
+                                   -> Matches<'a, S, Self> {
+        Matches::new(self.clone(), source, tag)
+    }
+
+    /// Send a message asynchronously, returning a `Future` that completes
+    /// when the send does.
+    ///
+    /// ```ignore
+    /// fn send(&self, Destination, Message) -> Future<()>;
+    /// ```
+    fn send<E, D>(&self, encoder: E, dest: D, msg: E::Message)
+                  -> Send<'a, E, D, Self>
+        where E: Encoder<'a>,
+              D: Destination,
+              Self: Submit<'a, SendJob<'a, E, D>>,
+    {
+        Send::new(self.clone(), encoder, dest, msg)
+    }
+
+    /// Send a message asynchronously in synchronous mode (`MPI_Issend`),
+    /// returning a `Future` that completes only once the receiver has
+    /// started receiving the message.
+    ///
+    /// ```ignore
+    /// fn send_sync(&self, Destination, Message) -> Future<()>;
+    /// ```
+    ///
+    /// The mode is merely requested from the encoder, which may override it
+    /// via `SendFrom::send_from_with_mode`.
+    fn send_sync<E, D>(&self, encoder: E, dest: D, msg: E::Message)
+                       -> Send<'a, E, D, Self>
+        where E: Encoder<'a>,
+              D: Destination,
+              Self: Submit<'a, SendJob<'a, E, D>>,
+    {
+        Send::with_mode(self.clone(), encoder, dest, msg,
+                        SendMode::Synchronous)
+    }
+
+    /// Send a message asynchronously using the given `SendMode`.
+    ///
+    /// ```ignore
+    /// fn send_with_mode(&self, Destination, Message, SendMode)
+    ///                   -> Future<()>;
+    /// ```
+    ///
+    /// `SendMode::Ready` is only correct if the matching receive is known to
+    /// be posted already.  `SendMode::Buffered` requires an attach buffer
+    /// (see `Switch::attach_buffer`) and fails if it has too little room
+    /// left for the message.  As with `send_sync`, the encoder may override
+    /// the mode.
+    fn send_with_mode<E, D>(&self, encoder: E, dest: D, msg: E::Message,
+                            mode: SendMode) -> Send<'a, E, D, Self>
+        where E: Encoder<'a>,
+              D: Destination,
+              Self: Submit<'a, SendJob<'a, E, D>>,
+    {
+        Send::with_mode(self.clone(), encoder, dest, msg, mode)
+    }
+
+    /// Send a message asynchronously, giving up if it does not complete
+    /// within `timeout` of this call.  Upon expiry, the send is cancelled
+    /// through its `CancelToken` and the future fails with
+    /// `TimeoutError::Elapsed`.  Whether the cancellation takes effect is
+    /// not reported; a send that already matched still completes.
+    ///
+    /// ```ignore
+    /// fn send_timeout(&self, Destination, Message, Duration)
+    ///                 -> Future<(), TimeoutError<MpiError>>;
+    /// ```
+    fn send_timeout<E, D>(&self, encoder: E, dest: D, msg: E::Message,
+                          timeout: Duration)
+                          -> Timeout<Send<'a, E, D, Self>>
+        where E: Encoder<'a>,
+              D: Destination,
+              Self: Submit<'a, SendJob<'a, E, D>> + Timers,
+    {
+        let deadline = timeout::deadline_after(timeout);
+        Timeout::new(self, self.send(encoder, dest, msg), deadline)
+    }
+
+    /// Send a message to `dest` while receiving the first message from
+    /// `source` with the given `tag`, returning a single `Future` that
+    /// completes once both halves have.
+    ///
+    /// ```ignore
+    /// fn sendrecv(&self, Destination, Message, Source, Tag)
+    ///             -> Future<(Status, Message)>;
+    /// ```
+    ///
+    /// The tag of the outgoing message is chosen by the encoder as usual.
+    /// Since both halves progress independently, a pairwise exchange (e.g.
+    /// sending to the left while receiving from the right) cannot deadlock.
+    fn sendrecv<E, D, C, S>(&self, encoder: E, dest: D, msg: E::Message,
+                            decoder: C, source: S, tag: Tag)
+                            -> SendRecv<Send<'a, E, D, Self>,
+                                        Incoming<'a, C, S, Self>>
+        where E: Encoder<'a>,
+              D: Destination,
+              C: Decoder<'a>,
+              S: Source,
+              Self: Submit<'a, SendJob<'a, E, D>>,
+    {
+        SendRecv::new(self.send(encoder, dest, msg),
+                      self.incoming_with_tag(decoder, source, tag))
+    }
+
+    /// Send the contents of `buffer` to `dest` with the given `tag` and
+    /// receive the first message from `source` with the same `tag` into
+    /// `buffer`, which is handed back along with the status.
+    ///
+    /// ```ignore
+    /// fn sendrecv_replace(&self, Destination, B, Source, Tag)
+    ///                     -> Result<Future<(Status, B)>, MpiError>;
+    /// ```
+    ///
+    /// Like `MPI_Sendrecv_replace`, the outgoing message is sent as
+    /// `MPI_PACKED` from a copy packed with `MPI_Pack` (see
+    /// `sendrecv::Packed`), so the receive need not wait for the send.
+    /// Packing happens right away and fails if the copy would be too large.
+    /// The incoming message must fit into `buffer`, whose length is left
+    /// unchanged.
+    fn sendrecv_replace<B, D, S>(&self, dest: D, buffer: B, source: S,
+                                 tag: Tag)
+                                 -> Result<
+                                     SendRecv<Send<'a, ReplaceEncoder, D,
+                                                   Self>,
+                                              Incoming<'a, ReplaceDecoder<B>,
+                                                       S, Self>>,
+                                     MpiError>
+        where B: OwnedBuffer + Unanchor + 'a,
+              D: Destination,
+              S: Source,
+              Self: Submit<'a, SendJob<'a, ReplaceEncoder, D>>,
+    {
+        let packed = Packed::new(buffer.as_buffer(),
+                                 dest.as_communicator())?;
+        Ok(SendRecv::new(self.send(ReplaceEncoder::new(tag), dest, packed),
+                         self.incoming_with_tag(ReplaceDecoder::new(buffer),
+                                                source, tag)))
+    }
+
+    /// Create a persistent send of `buffer` to `dest`, which can then be
+    /// started any number of times without setting up a new request.
+    ///
+    /// ```ignore
+    /// fn send_init(&self, Destination, Vec<T>, Tag)
+    ///              -> Result<PersistentChannel<T>, MpiError>;
+    /// ```
+    ///
+    /// Use `PersistentChannel::send_init_with_mode` for other send modes.
+    fn send_init<T, D>(&self, dest: D, buffer: Vec<T>, tag: Tag)
+                       -> Result<PersistentChannel<T, Self>, MpiError>
+        where T: Equivalence,
+              D: Destination,
+              Self: Submit<'a, StartJob<T>>,
+    {
+        PersistentChannel::send_init(self.clone(), dest, buffer, tag)
+    }
+
+    /// Create a persistent receive into `buffer` from `source`, which can
+    /// then be started any number of times.  If `tag` is `None`, messages of
+    /// any tag are accepted.
+    ///
+    /// Persistent receives bypass the `incoming` streams, so the protocol
+    /// must ensure their messages are not claimed by any of those first.
+    fn recv_init<T, S>(&self, source: S, buffer: Vec<T>,
+                       tag: Option<Tag>)
+                       -> Result<PersistentChannel<T, Self>, MpiError>
+        where T: Equivalence,
+              S: Source,
+              Self: Submit<'a, StartJob<T>>,
+    {
+        PersistentChannel::recv_init(self.clone(), source, buffer, tag)
+    }
+
+    /// Start a barrier (`MPI_Ibarrier`) over `comm`, returning a `Future`
+    /// that completes once every process of `comm` has entered it.
+    ///
+    /// ```ignore
+    /// fn barrier(&self, Communicator) -> Future<()>;
+    /// ```
+    ///
+    /// Like all collective operations, it is started right away rather than
+    /// when first polled (see the `collective` module).
+    fn barrier<C>(&self, comm: C) -> FutureCollective<()>
+        where C: Communicator,
+              Self: Submit<'a, CollectiveJob<'a, Barrier<C>>>,
+    {
+        collective::start(self, Barrier(comm))
+    }
+
+    /// Start a broadcast (`MPI_Ibcast`) from the process `root` of `comm`
+    /// into `buffer`, returning a `Future` of the filled buffer.
+    ///
+    /// ```ignore
+    /// fn broadcast(&self, Communicator, Rank, B) -> Future<B>;
+    /// ```
+    ///
+    /// Every process of `comm` must call this with a buffer of the same
+    /// size, except the root, which may use `broadcast_from` instead.  The
+    /// buffer is anchored until the broadcast completes.
+    fn broadcast<C, B>(&self, comm: C, root: Rank, buffer: B)
+                       -> FutureCollective<B>
+        where C: Communicator,
+              B: Unanchor + 'a,
+              Self: Submit<'a, CollectiveJob<'a, Broadcast<C, B>>>,
+    {
+        collective::start(self, Broadcast {
+            comm: comm,
+            root: root,
+            buffer: buffer,
+        })
+    }
+
+    /// Start a broadcast (`MPI_Ibcast`) of `buffer` from this process to
+    /// every other process of `comm`, returning a `Future` that gives back
+    /// the buffer once it is no longer needed.
+    ///
+    /// ```ignore
+    /// fn broadcast_from(&self, Communicator, B) -> Future<B>;
+    /// ```
+    ///
+    /// The other processes receive it using `broadcast` with this process
+    /// as the root.
+    fn broadcast_from<C, B>(&self, comm: C, buffer: B)
+                            -> FutureCollective<B>
+        where C: Communicator,
+              B: OwnedBuffer + 'a,
+              Self: Submit<'a, CollectiveJob<'a, BroadcastFrom<C, B>>>,
+    {
+        collective::start(self, BroadcastFrom {
+            comm: comm,
+            buffer: buffer,
+        })
+    }
+
+    /// Start a reduction (`MPI_Ireduce`) of the `send` buffers of every
+    /// process of `comm` into `recv`, with this process as the root.
+    ///
+    /// ```ignore
+    /// fn reduce(&self, Communicator, Operation, S, R) -> Future<(S, R)>;
+    /// ```
+    ///
+    /// The other processes contribute using `reduce_to`.  The elements are
+    /// combined using `op`, which is either a `SystemOperation` or a
+    /// `UserOperation`.  Both buffers are anchored until the reduction
+    /// completes, after which the `Future` resolves to them.
+    fn reduce<C, O, S, R, T>(&self, comm: C, op: O, send: S, recv: R)
+                             -> FutureCollective<(S, R)>
+        where C: Communicator,
+              T: Equivalence,
+              O: Operation<T> + 'a,
+              S: OwnedBuffer<Buffer=[T]> + 'a,
+              R: Unanchor<BufferMut=[T]> + 'a,
+              Self: Submit<'a, CollectiveJob<'a, Reduce<C, O, S, R>>>,
+    {
+        collective::start(self, Reduce {
+            comm: comm,
+            op: op,
+            send: send,
+            recv: recv,
+        })
+    }
+
+    /// Start contributing `send` to a reduction (`MPI_Ireduce`) into the
+    /// process `root` of `comm`, returning a `Future` that gives back the
+    /// buffer once it is no longer needed.
+    ///
+    /// ```ignore
+    /// fn reduce_to(&self, Communicator, Rank, Operation, S) -> Future<S>;
+    /// ```
+    fn reduce_to<C, O, S, T>(&self, comm: C, root: Rank, op: O, send: S)
+                             -> FutureCollective<S>
+        where C: Communicator,
+              T: Equivalence,
+              O: Operation<T> + 'a,
+              S: OwnedBuffer<Buffer=[T]> + 'a,
+              Self: Submit<'a, CollectiveJob<'a, ReduceTo<C, O, S>>>,
+    {
+        collective::start(self, ReduceTo {
+            comm: comm,
+            root: root,
+            op: op,
+            send: send,
+        })
+    }
+
+    /// Start a reduction (`MPI_Iallreduce`) of the `send` buffers of every
+    /// process of `comm` into the `recv` buffers of every process.
+    ///
+    /// ```ignore
+    /// fn allreduce(&self, Communicator, Operation, S, R) -> Future<(S, R)>;
+    /// ```
+    ///
+    /// As with `reduce`, both buffers are anchored until the reduction
+    /// completes.
+    fn allreduce<C, O, S, R, T>(&self, comm: C, op: O, send: S,
+                                recv: R)
+                                -> FutureCollective<(S, R)>
+        where C: Communicator,
+              T: Equivalence,
+              O: Operation<T> + 'a,
+              S: OwnedBuffer<Buffer=[T]> + 'a,
+              R: Unanchor<BufferMut=[T]> + 'a,
+              Self: Submit<'a, CollectiveJob<'a, AllReduce<C, O, S, R>>>,
+    {
+        collective::start(self, AllReduce {
+            comm: comm,
+            op: op,
+            send: send,
+            recv: recv,
+        })
+    }
+
+    /// Start gathering the `send` buffers of every process of `comm` into
+    /// this process (`MPI_Igather`), returning a `Future` of the send buffer
+    /// and the gathered data.
+    ///
+    /// ```ignore
+    /// fn gather(&self, Communicator, S) -> Future<(S, Gathered<T>)>;
+    /// ```
+    ///
+    /// Every process must contribute the same number of elements.  The
+    /// other processes use `gather_to`.
+    fn gather<C, S, T>(&self, comm: C, send: S)
+                       -> FutureCollective<(S, Gathered<T>)>
+        where C: Communicator,
+              T: Equivalence + 'a,
+              S: OwnedBuffer<Buffer=[T]> + 'a,
+              Self: Submit<'a, CollectiveJob<'a, Gather<C, S>>>,
+    {
+        collective::start(self, Gather {
+            comm: comm,
+            send: send,
+        })
+    }
+
+    /// Start contributing `send` to a gather (`MPI_Igather`) into the
+    /// process `root` of `comm`, returning a `Future` that gives back the
+    /// buffer once it is no longer needed.
+    ///
+    /// ```ignore
+    /// fn gather_to(&self, Communicator, Rank, S) -> Future<S>;
+    /// ```
+    fn gather_to<C, S, T>(&self, comm: C, root: Rank, send: S)
+                          -> FutureCollective<S>
+        where C: Communicator,
+              T: Equivalence,
+              S: OwnedBuffer<Buffer=[T]> + 'a,
+              Self: Submit<'a, CollectiveJob<'a, GatherTo<C, S>>>,
+    {
+        collective::start(self, GatherTo {
+            comm: comm,
+            root: root,
+            send: send,
+        })
+    }
+
+    /// Start gathering the variably long `send` buffers of every process of
+    /// `comm` into this process (`MPI_Igatherv`), returning a `Future` of
+    /// the send buffer and the gathered data.
+    ///
+    /// ```ignore
+    /// fn gatherv(&self, Communicator, S) -> Future<(S, Gathered<T>)>;
+    /// ```
+    ///
+    /// The counts are gathered first, so the parts are stored one after
+    /// another without having to be known in advance.  The other processes
+    /// use `gatherv_to`.
+    fn gatherv<C, S, T>(&self, comm: C, send: S)
+                        -> FutureCollective<(S, Gathered<T>)>
+        where C: Communicator,
+              T: Equivalence + 'a,
+              S: OwnedBuffer<Buffer=[T]> + 'a,
+              Self: Submit<'a, CollectiveJob<'a, Gatherv<C, S>>>,
+    {
+        collective::start(self, Gatherv {
+            comm: comm,
+            send: send,
+        })
+    }
+
+    /// Start contributing `send` to a gather (`MPI_Igatherv`) into the
+    /// process `root` of `comm`, returning a `Future` that gives back the
+    /// buffer once it is no longer needed.
+    ///
+    /// ```ignore
+    /// fn gatherv_to(&self, Communicator, Rank, S) -> Future<S>;
+    /// ```
+    fn gatherv_to<C, S, T>(&self, comm: C, root: Rank, send: S)
+                           -> FutureCollective<S>
+        where C: Communicator,
+              T: Equivalence + 'a,
+              S: OwnedBuffer<Buffer=[T]> + 'a,
+              Self: Submit<'a, CollectiveJob<'a, GathervTo<C, S>>>,
+    {
+        collective::start(self, GathervTo {
+            comm: comm,
+            root: root,
+            send: send,
+        })
+    }
+
+    /// Start gathering the `send` buffers of every process of `comm` into
+    /// every process (`MPI_Iallgather`).
+    ///
+    /// ```ignore
+    /// fn allgather(&self, Communicator, S) -> Future<(S, Gathered<T>)>;
+    /// ```
+    ///
+    /// Every process must contribute the same number of elements.
+    fn allgather<C, S, T>(&self, comm: C, send: S)
+                          -> FutureCollective<(S, Gathered<T>)>
+        where C: Communicator,
+              T: Equivalence + 'a,
+              S: OwnedBuffer<Buffer=[T]> + 'a,
+              Self: Submit<'a, CollectiveJob<'a, AllGather<C, S>>>,
+    {
+        collective::start(self, AllGather {
+            comm: comm,
+            send: send,
+        })
+    }
+
+    /// Start receiving a chunk of a scatter (`MPI_Iscatter`) from the
+    /// process `root` of `comm` into `recv`, returning a `Future` of the
+    /// filled buffer.
+    ///
+    /// ```ignore
+    /// fn scatter(&self, Communicator, Rank, R) -> Future<R>;
+    /// ```
+    ///
+    /// Every process receives a chunk of the same length, which must be that
+    /// of `recv`.  The root uses `scatter_from` instead.
+    fn scatter<C, R, T>(&self, comm: C, root: Rank, recv: R)
+                        -> FutureCollective<R>
+        where C: Communicator,
+              T: Equivalence,
+              R: Unanchor<BufferMut=[T]> + 'a,
+              Self: Submit<'a, CollectiveJob<'a, Scatter<C, R>>>,
+    {
+        collective::start(self, Scatter {
+            comm: comm,
+            root: root,
+            recv: recv,
+        })
+    }
+
+    /// Start scattering equally long chunks to every process of `comm`
+    /// (`MPI_Iscatter`), the `i`-th chunk of `send` going to the process of
+    /// rank `i`.
+    ///
+    /// ```ignore
+    /// fn scatter_from(&self, Communicator, Vec<Vec<T>>) -> Future<Vec<T>>;
+    /// ```
+    ///
+    /// There must be one chunk per process.  The `Future` resolves to the
+    /// chunk of this process.  The other processes use `scatter`.
+    fn scatter_from<C, T>(&self, comm: C, send: Vec<Vec<T>>)
+                          -> FutureCollective<Vec<T>>
+        where C: Communicator,
+              T: Equivalence + 'a,
+              Self: Submit<'a, CollectiveJob<'a, ScatterFrom<C, T>>>,
+    {
+        collective::start(self, ScatterFrom {
+            comm: comm,
+            send: send,
+        })
+    }
+
+    /// Start scattering a single buffer in equally long chunks to every
+    /// process of `comm` (`MPI_Iscatter`), the process of rank `i` receiving
+    /// the `i`-th chunk.  The chunk for this process is received into
+    /// `recv`.
+    ///
+    /// ```ignore
+    /// fn scatter_from_flat(&self, Communicator, S, R) -> Future<(S, R)>;
+    /// ```
+    ///
+    /// Unlike `scatter_from`, this sends straight from `send`, whose length
+    /// must be that of `recv` times the number of processes.  Both buffers
+    /// are anchored until the scatter completes.
+    fn scatter_from_flat<C, S, R, T>(&self, comm: C, send: S, recv: R)
+                                     -> FutureCollective<(S, R)>
+        where C: Communicator,
+              T: Equivalence,
+              S: OwnedBuffer<Buffer=[T]> + 'a,
+              R: Unanchor<BufferMut=[T]> + 'a,
+              Self: Submit<'a, CollectiveJob<'a, ScatterFromFlat<C, S, R>>>,
+    {
+        collective::start(self, ScatterFromFlat {
+            comm: comm,
+            send: send,
+            recv: recv,
+        })
+    }
+
+    /// Start receiving a chunk of a variably sized scatter
+    /// (`MPI_Iscatterv`) from the process `root` of `comm`, returning a
+    /// `Future` of the chunk.
+    ///
+    /// ```ignore
+    /// fn scatterv(&self, Communicator, Rank) -> Future<Vec<T>>;
+    /// ```
+    ///
+    /// The counts are scattered first, so the length of the chunk need not
+    /// be known in advance.  The root uses `scatterv_from` instead.
+    fn scatterv<C, T>(&self, comm: C, root: Rank)
+                      -> FutureCollective<Vec<T>>
+        where C: Communicator,
+              T: Equivalence + 'a,
+              Self: Submit<'a, CollectiveJob<'a, Scatterv<C, T>>>,
+    {
+        collective::start(self, Scatterv::new(comm, root))
+    }
+
+    /// Start scattering variably sized chunks to every process of `comm`
+    /// (`MPI_Iscatterv`), the `i`-th chunk of `send` going to the process of
+    /// rank `i`.
+    ///
+    /// ```ignore
+    /// fn scatterv_from(&self, Communicator, Vec<Vec<T>>) -> Future<Vec<T>>;
+    /// ```
+    ///
+    /// There must be one chunk per process.  The counts and displacements
+    /// are computed from the chunks, and the `Future` resolves to the chunk
+    /// of this process.  The other processes use `scatterv`.
+    fn scatterv_from<C, T>(&self, comm: C, send: Vec<Vec<T>>)
+                           -> FutureCollective<Vec<T>>
+        where C: Communicator,
+              T: Equivalence + 'a,
+              Self: Submit<'a, CollectiveJob<'a, ScattervFrom<C, T>>>,
+    {
+        collective::start(self, ScattervFrom {
+            comm: comm,
+            send: send,
+        })
+    }
+
+    /// Start scattering a single buffer to every process of `comm`
+    /// (`MPI_Iscatterv`), the process of rank `i` receiving the next
+    /// `counts[i]` elements.  The chunk for this process is received into
+    /// `recv`.
+    ///
+    /// ```ignore
+    /// fn scatterv_from_flat(&self, Communicator, S, Vec<usize>, R)
+    ///                       -> Future<(S, R)>;
+    /// ```
+    ///
+    /// Unlike `scatterv_from`, this sends straight from `send`.  The
+    /// displacements are derived from the counts, which must add up to the
+    /// length of `send`.
+    fn scatterv_from_flat<C, S, R, T>(&self, comm: C, send: S,
+                                      counts: Vec<usize>, recv: R)
+                                      -> FutureCollective<(S, R)>
+        where C: Communicator,
+              T: Equivalence,
+              S: OwnedBuffer<Buffer=[T]> + 'a,
+              R: Unanchor<BufferMut=[T]> + 'a,
+              Self: Submit<'a, CollectiveJob<'a, ScattervFromFlat<C, S, R>>>,
+    {
+        collective::start(self, ScattervFromFlat {
+            comm: comm,
+            send: send,
+            counts: counts,
+            recv: recv,
+        })
+    }
+
+    /// Start exchanging equally long chunks of `send` with every process of
+    /// `comm` (`MPI_Ialltoall`), the `i`-th chunk going to the process of
+    /// rank `i`.
+    ///
+    /// ```ignore
+    /// fn alltoall(&self, Communicator, S) -> Future<(S, Gathered<T>)>;
+    /// ```
+    ///
+    /// The length of `send` must be a multiple of the number of processes.
+    /// The `Future` resolves to the send buffer and the chunks received
+    /// from each process, so e.g. a transpose can proceed while other tasks
+    /// keep computing.
+    fn alltoall<C, S, T>(&self, comm: C, send: S)
+                         -> FutureCollective<(S, Gathered<T>)>
+        where C: Communicator,
+              T: Equivalence + 'a,
+              S: OwnedBuffer<Buffer=[T]> + 'a,
+              Self: Submit<'a, CollectiveJob<'a, AllToAll<C, S>>>,
+    {
+        collective::start(self, AllToAll {
+            comm: comm,
+            send: send,
+        })
+    }
+
+    /// Start exchanging variably sized chunks with every process of `comm`
+    /// (`MPI_Ialltoallv`), the `i`-th chunk of `send` going to the process
+    /// of rank `i`.
+    ///
+    /// ```ignore
+    /// fn alltoallv(&self, Communicator, Vec<Vec<T>>) -> Future<Gathered<T>>;
+    /// ```
+    ///
+    /// There must be one chunk per process.  The counts and displacements
+    /// are computed from the chunks, and the counts are exchanged before the
+    /// chunks themselves, so nobody needs to know in advance how much they
+    /// will receive.  The `Future` resolves to the chunks received from each
+    /// process.
+    fn alltoallv<C, T>(&self, comm: C, send: Vec<Vec<T>>)
+                       -> FutureCollective<Gathered<T>>
+        where C: Communicator,
+              T: Equivalence + 'a,
+              Self: Submit<'a, CollectiveJob<'a, AllToAllv<C, T>>>,
+    {
+        collective::start(self, AllToAllv {
+            comm: comm,
+            send: send,
+        })
+    }
+
+    /// Start exchanging variably sized chunks of a single buffer with every
+    /// process of `comm` (`MPI_Ialltoallv`), the next `counts[i]` elements
+    /// going to the process of rank `i`.
+    ///
+    /// ```ignore
+    /// fn alltoallv_flat(&self, Communicator, S, Vec<usize>)
+    ///                   -> Future<(S, Gathered<T>)>;
+    /// ```
+    ///
+    /// Unlike `alltoallv`, this sends straight from `send`, whose length
+    /// the counts must add up to.  The buffer is anchored until the exchange
+    /// completes.
+    fn alltoallv_flat<C, S, T>(&self, comm: C, send: S,
+                               counts: Vec<usize>)
+                               -> FutureCollective<(S, Gathered<T>)>
+        where C: Communicator,
+              T: Equivalence + 'a,
+              S: OwnedBuffer<Buffer=[T]> + 'a,
+              Self: Submit<'a, CollectiveJob<'a, AllToAllvFlat<C, S>>>,
+    {
+        collective::start(self, AllToAllvFlat {
+            comm: comm,
+            send: send,
+            counts: counts,
+        })
+    }
+}
@@ -0,0 +1,204 @@
+//! Counters and histograms describing the communications of a switch.
+//!
+//! The `RequestPoll` keeps its `Metrics` up to date as requests are started
+//! and completed.  A snapshot can be obtained at any time through the `Link`
+//! of the switch, e.g. to be logged periodically.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use mpi;
+use mpi::Tag as RawTag;
+use mpi::topology::Rank;
+use super::error::OrError;
+use super::large;
+
+/// Number of messages and bytes transferred.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Traffic {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+impl Traffic {
+    fn record(&mut self, bytes: u64) {
+        self.messages += 1;
+        self.bytes += bytes;
+    }
+}
+
+/// Histogram of durations with logarithmic buckets.
+///
+/// Bucket `i` counts the durations `d` with `2^i <= d / 1µs < 2^(i + 1)`,
+/// except that bucket 0 also counts everything shorter than 1µs.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    /// Record a duration given in seconds.
+    pub fn record(&mut self, seconds: f64) {
+        let micros = seconds * 1e6;
+        let i = if micros < 2.0 {
+            0
+        } else {
+            micros.log2().floor() as usize
+        };
+        if self.buckets.len() <= i {
+            self.buckets.resize(i + 1, 0);
+        }
+        self.buckets[i] += 1;
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    /// Number of recorded durations in each bucket.
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    /// Lower bound of bucket `i` in seconds.
+    pub fn bucket_start(i: usize) -> f64 {
+        if i == 0 {
+            0.0
+        } else {
+            (1u64 << i) as f64 * 1e-6
+        }
+    }
+
+    /// Total number of recorded durations.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Sum of all recorded durations in seconds.
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    /// Mean of all recorded durations in seconds, if any.
+    pub fn mean(&self) -> Option<f64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.sum / self.count as f64)
+        }
+    }
+}
+
+/// Snapshot of the metrics of a switch.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metrics {
+    /// Number of requests in the `RequestPoll` that have yet to complete.
+    pub outstanding: usize,
+    /// Messages sent, by destination rank and tag.
+    pub sent: BTreeMap<(Rank, RawTag), Traffic>,
+    /// Messages received, by source rank and tag.
+    pub received: BTreeMap<(Rank, RawTag), Traffic>,
+    /// Time from starting a send until its callback is called.
+    pub send_latency: Histogram,
+    /// Time from starting a receive until its callback is called.
+    pub recv_latency: Histogram,
+    /// Number of calls to `RequestPoll::test`.
+    pub tests: u64,
+    /// Number of calls to `RequestPoll::test` that completed nothing.
+    pub empty_tests: u64,
+}
+
+impl Metrics {
+    /// Total of all messages sent.
+    pub fn total_sent(&self) -> Traffic {
+        total(&self.sent)
+    }
+
+    /// Total of all messages received.
+    pub fn total_received(&self) -> Traffic {
+        total(&self.received)
+    }
+
+    /// Record the successful completion of `op` with the given status.
+    pub(crate) fn record(&mut self, op: Op, latency: f64,
+                         status: &mpi::ffi::MPI_Status) {
+        match op {
+            Op::Send { dest, tag, bytes } => {
+                self.sent.entry((dest, tag)).or_insert_with(Default::default)
+                    .record(bytes);
+                self.send_latency.record(latency);
+            }
+            Op::Recv { datatype } => {
+                // an unknown count just counts as zero
+                let count = large::message_len(status, datatype).unwrap_or(0);
+                let key = (status.MPI_SOURCE, status.MPI_TAG);
+                self.received.entry(key).or_insert_with(Default::default)
+                    .record(size_of(count as u64, datatype));
+                self.recv_latency.record(latency);
+            }
+            Op::Internal => {}
+        }
+    }
+}
+
+fn total(traffic: &BTreeMap<(Rank, RawTag), Traffic>) -> Traffic {
+    traffic.values().fold(Traffic::default(), |acc, t| Traffic {
+        messages: acc.messages + t.messages,
+        bytes: acc.bytes + t.bytes,
+    })
+}
+
+/// Size of `count` elements of `datatype` in bytes.
+pub(crate) fn size_of(count: u64, datatype: mpi::ffi::MPI_Datatype) -> u64 {
+    let mut size: mpi::ffi::MPI_Count = 0;
+    unsafe {
+        // an unknown size just counts as zero
+        let _ = mpi::ffi::MPI_Type_size_x(datatype, &mut size).or_error();
+    }
+    count * cmp::max(size, 0) as u64
+}
+
+/// Information about a request that is needed to record its completion.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Op {
+    Send { dest: Rank, tag: RawTag, bytes: u64 },
+    Recv { datatype: mpi::ffi::MPI_Datatype },
+    // used by the switch itself (e.g. to interrupt a wait), which is left out
+    // of the metrics altogether
+    Internal,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Histogram;
+
+    #[test]
+    fn histogram_buckets() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.mean(), None);
+        for &micros in &[0.0, 0.5, 1.0, 1.9, 2.0, 3.9, 4.0, 1000.0] {
+            histogram.record(micros * 1e-6);
+        }
+        // 1000µs falls into [512µs, 1024µs)
+        let mut expected = vec![0; 10];
+        expected[0] = 4;
+        expected[1] = 2;
+        expected[2] = 1;
+        expected[9] = 1;
+        assert_eq!(histogram.buckets(), &expected[..]);
+        assert_eq!(histogram.count(), 8);
+        assert!((histogram.sum() - 1013.3e-6).abs() < 1e-12);
+    }
+
+    #[test]
+    fn histogram_bucket_start() {
+        assert_eq!(Histogram::bucket_start(0), 0.0);
+        assert_eq!(Histogram::bucket_start(1), 2e-6);
+        assert_eq!(Histogram::bucket_start(2), 4e-6);
+        assert_eq!(Histogram::bucket_start(10), 1024e-6);
+        for i in 1..20 {
+            let mut histogram = Histogram::default();
+            histogram.record(Histogram::bucket_start(i));
+            assert_eq!(histogram.buckets().len(), i + 1);
+        }
+    }
+}
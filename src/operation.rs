@@ -0,0 +1,292 @@
+//! Operations for reductions.
+//!
+//! Besides the predefined operations of `SystemOperation`, a reduction can
+//! use a `UserOperation` created from a Rust closure.  MPI passes nothing to
+//! a user function but the buffers and the datatype, so every
+//! `UserOperation` carries its own duplicate of the datatype, to which the
+//! closure is attached as an attribute.
+
+use std::{fmt, marker, panic, ptr, slice};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicIsize, Ordering};
+use libc;
+use mpi;
+use mpi::datatype::Equivalence;
+use mpi::raw::AsRaw;
+use super::error::{MpiError, OrError};
+use super::request_poll::{self, OrAbort};
+
+/// An operation that combines elements of type `T` in a reduction.
+///
+/// # Unsafe invariant
+///
+/// Both handles must remain valid for as long as `Self` survives, and the
+/// datatype must have the same type signature as `T`.
+pub unsafe trait Operation<T> {
+    /// The raw `MPI_Op`.
+    fn op(&self) -> mpi::ffi::MPI_Op;
+
+    /// The datatype with which elements of type `T` are to be reduced.
+    fn datatype(&self) -> mpi::ffi::MPI_Datatype;
+}
+
+unsafe impl<'b, T, O: Operation<T>> Operation<T> for &'b O {
+    fn op(&self) -> mpi::ffi::MPI_Op {
+        (**self).op()
+    }
+
+    fn datatype(&self) -> mpi::ffi::MPI_Datatype {
+        (**self).datatype()
+    }
+}
+
+unsafe impl<T, O: Operation<T>> Operation<T> for Arc<O> {
+    fn op(&self) -> mpi::ffi::MPI_Op {
+        (**self).op()
+    }
+
+    fn datatype(&self) -> mpi::ffi::MPI_Datatype {
+        (**self).datatype()
+    }
+}
+
+/// One of the operations predefined by MPI.
+///
+/// Note that each of them is only defined for certain kinds of datatypes,
+/// e.g. the logical operations are only defined for integers.
+#[derive(Clone, Copy, Debug)]
+pub struct SystemOperation(mpi::ffi::MPI_Op);
+
+// the predefined handles are constants
+unsafe impl marker::Send for SystemOperation {}
+unsafe impl Sync for SystemOperation {}
+
+impl SystemOperation {
+    /// `MPI_MAX`
+    pub fn max() -> Self {
+        SystemOperation(unsafe { mpi::ffi::RSMPI_MAX })
+    }
+
+    /// `MPI_MIN`
+    pub fn min() -> Self {
+        SystemOperation(unsafe { mpi::ffi::RSMPI_MIN })
+    }
+
+    /// `MPI_SUM`
+    pub fn sum() -> Self {
+        SystemOperation(unsafe { mpi::ffi::RSMPI_SUM })
+    }
+
+    /// `MPI_PROD`
+    pub fn product() -> Self {
+        SystemOperation(unsafe { mpi::ffi::RSMPI_PROD })
+    }
+
+    /// `MPI_LAND`
+    pub fn logical_and() -> Self {
+        SystemOperation(unsafe { mpi::ffi::RSMPI_LAND })
+    }
+
+    /// `MPI_LOR`
+    pub fn logical_or() -> Self {
+        SystemOperation(unsafe { mpi::ffi::RSMPI_LOR })
+    }
+
+    /// `MPI_LXOR`
+    pub fn logical_xor() -> Self {
+        SystemOperation(unsafe { mpi::ffi::RSMPI_LXOR })
+    }
+
+    /// `MPI_BAND`
+    pub fn bitwise_and() -> Self {
+        SystemOperation(unsafe { mpi::ffi::RSMPI_BAND })
+    }
+
+    /// `MPI_BOR`
+    pub fn bitwise_or() -> Self {
+        SystemOperation(unsafe { mpi::ffi::RSMPI_BOR })
+    }
+
+    /// `MPI_BXOR`
+    pub fn bitwise_xor() -> Self {
+        SystemOperation(unsafe { mpi::ffi::RSMPI_BXOR })
+    }
+}
+
+unsafe impl<T: Equivalence> Operation<T> for SystemOperation {
+    fn op(&self) -> mpi::ffi::MPI_Op {
+        self.0
+    }
+
+    fn datatype(&self) -> mpi::ffi::MPI_Datatype {
+        T::equivalent_datatype().as_raw()
+    }
+}
+
+type Function<T> = Box<Fn(&[T], &mut [T]) + marker::Send + Sync>;
+
+/// An operation defined by a Rust closure (`MPI_Op_create`).
+///
+/// The closure is called with the incoming elements and the accumulated
+/// elements, which it should update in place, i.e. `inout[i] = f(in[i],
+/// inout[i])`.  It may be called on any thread that drives a switch.  If it
+/// panics, the process is aborted.
+pub struct UserOperation<T> {
+    op: mpi::ffi::MPI_Op,
+    datatype: mpi::ffi::MPI_Datatype,
+    // the attribute points to the inner box, which thus must not move
+    function: Box<Function<T>>,
+}
+
+// Safe because the closure is Send + Sync and the handles may be used from
+// any thread.
+unsafe impl<T> marker::Send for UserOperation<T> {}
+unsafe impl<T> Sync for UserOperation<T> {}
+
+impl<T> fmt::Debug for UserOperation<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UserOperation")
+            .field("op", &self.op)
+            .field("datatype", &self.datatype)
+            .finish()
+    }
+}
+
+impl<T: Equivalence> UserOperation<T> {
+    /// Create a commutative operation.  MPI may then combine the
+    /// contributions of the processes in any order.
+    pub fn commutative<F>(function: F) -> Result<Self, MpiError>
+        where F: Fn(&[T], &mut [T]) + marker::Send + Sync + 'static
+    {
+        Self::new(Box::new(function), true)
+    }
+
+    /// Create an operation that is merely associative.  The contributions
+    /// are then combined in the order of the ranks.
+    pub fn associative<F>(function: F) -> Result<Self, MpiError>
+        where F: Fn(&[T], &mut [T]) + marker::Send + Sync + 'static
+    {
+        Self::new(Box::new(function), false)
+    }
+
+    fn new(function: Function<T>, commute: bool)
+           -> Result<Self, MpiError> {
+        let keyval = keyval()?;
+        unsafe {
+            // anything created so far is freed by Drop if this fails midway
+            let mut op = UserOperation {
+                op: mpi::ffi::RSMPI_OP_NULL,
+                datatype: mpi::ffi::RSMPI_DATATYPE_NULL,
+                function: Box::new(function),
+            };
+            mpi::ffi::MPI_Type_dup(T::equivalent_datatype().as_raw(),
+                                   &mut op.datatype).or_error()?;
+            let attr = &*op.function as *const Function<T>;
+            mpi::ffi::MPI_Type_set_attr(op.datatype, keyval,
+                                        attr as *mut libc::c_void)
+                .or_error()?;
+            mpi::ffi::MPI_Op_create(Some(apply::<T>), commute as libc::c_int,
+                                    &mut op.op).or_error()?;
+            Ok(op)
+        }
+    }
+}
+
+unsafe impl<T> Operation<T> for UserOperation<T> {
+    fn op(&self) -> mpi::ffi::MPI_Op {
+        self.op
+    }
+
+    fn datatype(&self) -> mpi::ffi::MPI_Datatype {
+        self.datatype
+    }
+}
+
+impl<T> Drop for UserOperation<T> {
+    fn drop(&mut self) {
+        unsafe {
+            // nothing sensible to do if these fail
+            if self.op != mpi::ffi::RSMPI_OP_NULL {
+                let _ = mpi::ffi::MPI_Op_free(&mut self.op).or_error();
+            }
+            if self.datatype != mpi::ffi::RSMPI_DATATYPE_NULL {
+                let _ = mpi::ffi::MPI_Type_free(&mut self.datatype)
+                    .or_error();
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn apply<T>(invec: *mut libc::c_void,
+                              inoutvec: *mut libc::c_void,
+                              len: *mut libc::c_int,
+                              datatype: *mut mpi::ffi::MPI_Datatype) {
+    let keyval = KEYVAL.load(Ordering::SeqCst) as libc::c_int;
+    let mut function: *const Function<T> = ptr::null();
+    let mut flag = 0;
+    mpi::ffi::MPI_Type_get_attr(
+        *datatype, keyval,
+        &mut function as *mut *const Function<T> as *mut libc::c_void,
+        &mut flag).or_abort();
+    if flag == 0 || function.is_null() {
+        request_poll::abort(mpi::ffi::MPI_ERR_OTHER as libc::c_int);
+    }
+    let len = *len as usize;
+    let input = slice::from_raw_parts(invec as *const T, len);
+    let inout = slice::from_raw_parts_mut(inoutvec as *mut T, len);
+    // unwinding into MPI is undefined behavior
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        (*function)(input, inout)
+    }));
+    if result.is_err() {
+        request_poll::abort(mpi::ffi::MPI_ERR_OTHER as libc::c_int);
+    }
+}
+
+// the closure is owned by the UserOperation, so the attribute is neither
+// copied nor deleted along with the datatype
+unsafe extern "C" fn copy_attr(_: mpi::ffi::MPI_Datatype, _: libc::c_int,
+                               _: *mut libc::c_void, _: *mut libc::c_void,
+                               _: *mut libc::c_void, flag: *mut libc::c_int)
+                               -> libc::c_int {
+    *flag = 0;
+    mpi::ffi::MPI_SUCCESS as libc::c_int
+}
+
+unsafe extern "C" fn delete_attr(_: mpi::ffi::MPI_Datatype, _: libc::c_int,
+                                 _: *mut libc::c_void, _: *mut libc::c_void)
+                                 -> libc::c_int {
+    mpi::ffi::MPI_SUCCESS as libc::c_int
+}
+
+const NO_KEYVAL: isize = isize::min_value();
+
+// NO_KEYVAL means that the keyval hasn't been created yet
+static KEYVAL: AtomicIsize = AtomicIsize::new(NO_KEYVAL);
+
+/// The datatype keyval under which closures are attached, which is created
+/// on the first call.
+fn keyval() -> Result<libc::c_int, MpiError> {
+    let cached = KEYVAL.load(Ordering::SeqCst);
+    if cached != NO_KEYVAL {
+        return Ok(cached as libc::c_int);
+    }
+    let mut keyval = 0;
+    unsafe {
+        mpi::ffi::MPI_Type_create_keyval(Some(copy_attr), Some(delete_attr),
+                                         &mut keyval, ptr::null_mut())
+            .or_error()?;
+    }
+    match KEYVAL.compare_exchange(NO_KEYVAL, keyval as isize,
+                                  Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => Ok(keyval),
+        // another thread got there first
+        Err(other) => {
+            unsafe {
+                let _ = mpi::ffi::MPI_Type_free_keyval(&mut keyval)
+                    .or_error();
+            }
+            Ok(other as libc::c_int)
+        }
+    }
+}
@@ -0,0 +1,343 @@
+//! Persistent point-to-point communication.
+//!
+//! A `PersistentChannel` owns a buffer along with a persistent request
+//! created once via `MPI_Send_init` or `MPI_Recv_init`.  Each round restarts
+//! the request via `MPI_Start`, which avoids setting up a fresh request for
+//! every message when the same buffer is exchanged with the same peer over
+//! and over.
+//!
+//! The channel is moved into the `Round` future while the request is active
+//! and handed back once it completes, so the buffer can only be accessed in
+//! between rounds.
+
+use std::{fmt, marker, mem};
+use futures::{Async, Future, Poll};
+use futures::sync::oneshot;
+use mpi;
+use mpi::datatype::Equivalence;
+use mpi::point_to_point::{Destination, Source};
+use mpi::raw::AsRaw;
+use super::cancel::{Cancel, CancelToken, Cancellation};
+use super::error::{MpiError, OrError, switch_gone};
+use super::large::{self, LargeType};
+use super::metrics::{self, Op};
+use super::request_poll::{RequestPoll, SendMode};
+use super::switch::{Job, Link, Submit, SyncJob};
+use super::tag::Tag;
+
+/// A persistent request along with its buffer.  The request is freed when
+/// dropped, so it must only be dropped while inactive.
+struct Persistent<T> {
+    request: mpi::ffi::MPI_Request,
+    // kept until the request is freed (fields drop after Drop::drop)
+    #[allow(dead_code)]
+    large: Option<LargeType>,
+    buffer: Box<[T]>,
+    op: Op,
+    cancelable: bool,
+}
+
+// the request is only ever used by one thread at a time
+unsafe impl<T: marker::Send> marker::Send for Persistent<T> {}
+
+impl<T> fmt::Debug for Persistent<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Persistent")
+            .field("request", &self.request)
+            .field("buffer", &self.buffer.len())
+            .field("op", &self.op)
+            .finish()
+    }
+}
+
+impl<T> Drop for Persistent<T> {
+    fn drop(&mut self) {
+        unsafe {
+            // nothing sensible to do if this fails
+            let _ = mpi::ffi::MPI_Request_free(&mut self.request).or_error();
+        }
+    }
+}
+
+/// A persistent send or receive request that can be started any number of
+/// times.
+///
+/// ```ignore
+/// PersistentChannel::start(self) -> Future<PersistentChannel>;
+/// ```
+///
+/// The link type `L` determines which kind of switch the rounds are
+/// performed through.  The request is freed when the channel is dropped.
+#[derive(Debug)]
+pub struct PersistentChannel<T, L = Link<'static>> {
+    link: L,
+    persistent: Persistent<T>,
+}
+
+impl<T: Equivalence, L> PersistentChannel<T, L> {
+    /// Create a persistent send of `buffer` to `dest` via `MPI_Send_init`.
+    pub fn send_init<D>(link: L, dest: D, buffer: Vec<T>, tag: Tag)
+                        -> Result<Self, MpiError>
+        where D: Destination
+    {
+        Self::send_init_with_mode(link, dest, buffer, tag, SendMode::Standard)
+    }
+
+    /// Create a persistent send using the given mode (`MPI_Send_init`,
+    /// `MPI_Ssend_init`, `MPI_Rsend_init`, or `MPI_Bsend_init`).
+    pub fn send_init_with_mode<D>(link: L, dest: D, buffer: Vec<T>,
+                                  tag: Tag, mode: SendMode)
+                                  -> Result<Self, MpiError>
+        where D: Destination
+    {
+        let buffer = buffer.into_boxed_slice();
+        let layout = large::layout(&*buffer)?;
+        let comm = dest.as_communicator().as_raw();
+        unsafe {
+            let mut request = mpi::ffi::RSMPI_REQUEST_NULL;
+            mode.init_fn()(buffer.as_ptr() as *const _,
+                           layout.count,
+                           layout.datatype,
+                           dest.destination_rank(),
+                           tag.value(),
+                           comm,
+                           &mut request).or_error()?;
+            Ok(PersistentChannel {
+                link: link,
+                persistent: Persistent {
+                    request: request,
+                    buffer: buffer,
+                    op: Op::Send {
+                        dest: dest.destination_rank(),
+                        tag: tag.value(),
+                        bytes: metrics::size_of(layout.count as u64,
+                                                layout.datatype),
+                    },
+                    large: layout.large,
+                    cancelable: false,
+                },
+            })
+        }
+    }
+
+    /// Create a persistent receive into `buffer` from `source` via
+    /// `MPI_Recv_init`.  If `tag` is `None`, messages of any tag are
+    /// accepted.
+    ///
+    /// Every round must receive a message that fits into `buffer`.
+    pub fn recv_init<S>(link: L, source: S, buffer: Vec<T>,
+                        tag: Option<Tag>) -> Result<Self, MpiError>
+        where S: Source
+    {
+        let mut buffer = buffer.into_boxed_slice();
+        let layout = large::layout(&*buffer)?;
+        let datatype = T::equivalent_datatype().as_raw();
+        let comm = source.as_communicator().as_raw();
+        let tag = match tag {
+            None => unsafe { mpi::ffi::RSMPI_ANY_TAG },
+            Some(tag) => tag.value(),
+        };
+        unsafe {
+            let mut request = mpi::ffi::RSMPI_REQUEST_NULL;
+            mpi::ffi::MPI_Recv_init(buffer.as_mut_ptr() as *mut _,
+                                    layout.count,
+                                    layout.datatype,
+                                    source.source_rank(),
+                                    tag,
+                                    comm,
+                                    &mut request).or_error()?;
+            Ok(PersistentChannel {
+                link: link,
+                persistent: Persistent {
+                    request: request,
+                    buffer: buffer,
+                    op: Op::Recv { datatype: datatype },
+                    large: layout.large,
+                    cancelable: true,
+                },
+            })
+        }
+    }
+}
+
+impl<T, L> PersistentChannel<T, L> {
+    /// The buffer that is sent from or received into.
+    pub fn buffer(&self) -> &[T] {
+        &self.persistent.buffer
+    }
+
+    /// The buffer that is sent from or received into, e.g. to fill in the
+    /// data for the next round.
+    pub fn buffer_mut(&mut self) -> &mut [T] {
+        &mut self.persistent.buffer
+    }
+
+    /// Free the request and retrieve the buffer.
+    pub fn into_buffer(self) -> Box<[T]> {
+        let mut persistent = self.persistent;
+        mem::replace(&mut persistent.buffer, Vec::new().into_boxed_slice())
+    }
+
+    /// Start a round, returning a `Future` that hands the channel back once
+    /// the request has completed.
+    ///
+    /// If the round fails, the request is freed along with the channel.
+    pub fn start(self) -> Round<T, L> {
+        Round(RoundState::Pending { channel: self })
+    }
+}
+
+enum RoundState<T, L> {
+    Pending {
+        channel: PersistentChannel<T, L>,
+    },
+    Started {
+        link: L,
+        receiver: oneshot::Receiver<Returned<T>>,
+        // None once the round has completed
+        token: Option<CancelToken>,
+        // whether dropping the round cancels it (only for receives)
+        cancelable: bool,
+    },
+    Invalid,
+}
+
+type Returned<T> = (Persistent<T>, Result<(), MpiError>);
+
+impl<T: fmt::Debug, L: fmt::Debug> fmt::Debug for RoundState<T, L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &RoundState::Pending { ref channel } =>
+                f.debug_struct("RoundState::Pending")
+                .field("channel", channel)
+                .finish(),
+            &RoundState::Started { ref link, ref receiver, ref token,
+                                   ref cancelable } =>
+                f.debug_struct("RoundState::Started")
+                .field("link", link)
+                .field("receiver", receiver)
+                .field("token", token)
+                .field("cancelable", cancelable)
+                .finish(),
+            &RoundState::Invalid =>
+                f.write_str("RoundState::Invalid"),
+        }
+    }
+}
+
+/// Future returned by `PersistentChannel::start`.
+///
+/// Dropping it before a receive round completes cancels the request, after
+/// which the request is freed.  A send round is left to complete in the
+/// background instead.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Round<T, L = Link<'static>>(RoundState<T, L>);
+
+impl<T, L> Round<T, L> {
+    /// Cancel the round, returning a future that reports whether the
+    /// cancellation took effect.  If the round was never started, it counts
+    /// as cancelled.
+    pub fn cancel(mut self) -> Cancellation {
+        match mem::replace(&mut self.0, RoundState::Invalid) {
+            RoundState::Pending { .. } => Cancellation::done(true),
+            RoundState::Started { token: Some(token), .. } =>
+                token.cancel_with_report(),
+            _ => Cancellation::done(false),
+        }
+    }
+}
+
+impl<T, L> Cancel for Round<T, L> {
+    fn request_cancel(&mut self) {
+        if let RoundState::Started { token: Some(ref token), .. } = self.0 {
+            token.cancel();
+        }
+    }
+}
+
+impl<T, L> Drop for Round<T, L> {
+    fn drop(&mut self) {
+        if let RoundState::Started { token: Some(ref token),
+                                     cancelable: true, .. } = self.0 {
+            token.cancel();
+        }
+    }
+}
+
+/// The `Job` submitted by `Round` to start the persistent request.
+pub struct StartJob<T> {
+    persistent: Persistent<T>,
+    sender: oneshot::Sender<Returned<T>>,
+    token: CancelToken,
+}
+
+impl<'a, T: 'a> Job<'a> for StartJob<T> {
+    fn run(self, request_poll: &mut RequestPoll<'a>) {
+        let StartJob { persistent, sender, token } = self;
+        let request = persistent.request;
+        let op = persistent.op;
+        let cancelable = persistent.cancelable;
+        unsafe {
+            // the callback owns the request from now on
+            request_poll.start_persistent(request, move |result| {
+                let _ = sender.send((persistent, result));
+            }, cancelable, Some(token), op);
+        }
+    }
+}
+
+// the callback only holds on to the (thread-safe) sender and the buffer
+unsafe impl<'a, T: marker::Send + 'a> SyncJob<'a> for StartJob<T> {}
+
+impl<'a, T, L> Future for Round<T, L>
+    where L: Submit<'a, StartJob<T>>,
+{
+    type Item = PersistentChannel<T, L>;
+    type Error = MpiError;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match mem::replace(&mut self.0, RoundState::Invalid) {
+            RoundState::Pending { channel } => {
+                let (sender, receiver) = oneshot::channel();
+                let token = CancelToken::new();
+                let cancelable = channel.persistent.cancelable;
+                channel.link.submit(StartJob {
+                    persistent: channel.persistent,
+                    sender: sender,
+                    token: token.clone(),
+                });
+                self.0 = RoundState::Started {
+                    link: channel.link,
+                    receiver: receiver,
+                    token: Some(token),
+                    cancelable: cancelable,
+                };
+                self.poll()
+            }
+            RoundState::Started { link, mut receiver, token, cancelable } => {
+                match receiver.poll() {
+                    Ok(Async::NotReady) => {
+                        self.0 = RoundState::Started {
+                            link: link,
+                            receiver: receiver,
+                            token: token,
+                            cancelable: cancelable,
+                        };
+                        Ok(Async::NotReady)
+                    }
+                    Ok(Async::Ready((persistent, result))) => {
+                        result?;
+                        Ok(Async::Ready(PersistentChannel {
+                            link: link,
+                            persistent: persistent,
+                        }))
+                    }
+                    // the switch dropped the job without running it
+                    Err(oneshot::Canceled) => Err(switch_gone()),
+                }
+            }
+            // panic loudly so the loop doesn't just silently stall!
+            RoundState::Invalid => panic!("invalid state"),
+        }
+    }
+}
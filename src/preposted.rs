@@ -0,0 +1,317 @@
+//! Incoming messages received into pre-posted buffers.
+//!
+//! Unlike `Incoming`, which probes for every message before receiving it, a
+//! `Preposted` stream keeps a fixed number of `MPI_Irecv` requests posted
+//! into buffers of a fixed size.  Each buffer is handed out along with its
+//! message and posted again once the `Received` handle is dropped.  This
+//! saves a round of overhead per message, but every message must fit into a
+//! buffer.
+//!
+//! Messages are yielded in the order in which their receives were posted,
+//! which is the order in which MPI matches them, so messages from the same
+//! sender never overtake each other.  A completed receive is thus held back
+//! until all receives posted before it have completed too.
+//!
+//! A pre-posted receive can claim a message before any `Incoming` stream
+//! gets to probe it, so the two kinds of streams should not be used for the
+//! same messages.
+
+use std::{cmp, fmt, marker, mem, ops};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use futures::{Async, Poll, Stream};
+use futures::task::{self, Task};
+use mpi::datatype::Equivalence;
+use mpi::point_to_point::{Source, Status};
+use super::buffer::Unanchor;
+use super::cancel::CancelToken;
+use super::dispatch::Filter;
+use super::error::MpiError;
+use super::request_poll::RequestPoll;
+use super::switch::{Job, Link, Submit, SyncJob};
+use super::tag::Tag;
+
+type Completed<T> = Result<(usize, Status, Box<[T]>), MpiError>;
+
+struct Pool<T> {
+    // buffers waiting to be posted, along with their slots
+    free: Vec<(usize, Box<[T]>)>,
+    // completed receives waiting to be taken by the stream, by sequence
+    // number (None if the receive ended without completing)
+    ready: BTreeMap<u64, Option<Completed<T>>>,
+    // sequence number of the next receive to be posted
+    posted: u64,
+    // sequence number of the next receive to be taken by the stream
+    taken: u64,
+    // tokens of the posted receives, by slot
+    tokens: Vec<Option<CancelToken>>,
+    task: Option<Task>,
+    // set by the stream when it goes away
+    closed: bool,
+    // set once the switch stops accepting receives
+    ended: bool,
+}
+
+impl<T> Pool<T> {
+    fn unpark(&mut self) {
+        self.task.take().map(|task| task.unpark());
+    }
+}
+
+impl<T> fmt::Debug for Pool<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pool")
+            .field("free", &self.free.len())
+            .field("ready", &self.ready.len())
+            .field("posted", &self.posted)
+            .field("taken", &self.taken)
+            .field("tokens", &self.tokens)
+            .field("task", &self.task)
+            .field("closed", &self.closed)
+            .field("ended", &self.ended)
+            .finish()
+    }
+}
+
+/// Stream of messages received into pre-posted buffers.
+///
+/// ```ignore
+/// Preposted<T, Source>: Stream<Received<T>>
+/// ```
+///
+/// The receives are first posted when the stream is polled.  Dropping the
+/// stream cancels the receives that are still pending, although messages
+/// that have already arrived by then are lost.
+///
+/// The link type `L` determines which kind of switch the messages are
+/// received through.
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct Preposted<T, S, L = Link<'static>> {
+    link: L,
+    // holds on to the communicator for as long as the stream exists
+    #[allow(dead_code)]
+    source: S,
+    filter: Filter,
+    pool: Arc<Mutex<Pool<T>>>,
+}
+
+impl<T: Equivalence + Clone + Default, S: Source, L> Preposted<T, S, L> {
+    /// Create a stream that keeps `depth` receives of up to `size` elements
+    /// posted for messages from `source` with the given `tag` (or any tag if
+    /// `None`).
+    pub fn new(link: L, source: S, tag: Option<Tag>, depth: usize,
+               size: usize) -> Self {
+        let free = (0..depth)
+            .map(|slot| (slot, vec![T::default(); size].into_boxed_slice()))
+            .collect();
+        Preposted {
+            filter: Filter::new(&source, tag),
+            link: link,
+            source: source,
+            pool: Arc::new(Mutex::new(Pool {
+                free: free,
+                ready: BTreeMap::new(),
+                posted: 0,
+                taken: 0,
+                tokens: vec![None; depth],
+                task: None,
+                closed: false,
+                ended: false,
+            })),
+        }
+    }
+}
+
+impl<T, S, L> Drop for Preposted<T, S, L> {
+    fn drop(&mut self) {
+        let mut pool = self.pool.lock().unwrap();
+        pool.closed = true;
+        for token in pool.tokens.iter().filter_map(|token| token.as_ref()) {
+            token.cancel();
+        }
+    }
+}
+
+impl<'a, T, S, L> Stream for Preposted<T, S, L>
+    where T: Equivalence + 'a,
+          L: Submit<'a, PostJob<T>>,
+{
+    type Item = Received<T>;
+    type Error = MpiError;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        // (re-)post the free buffers, without holding on to the lock since
+        // the job might run right away
+        let free: Vec<_> = {
+            let mut pool = self.pool.lock().unwrap();
+            if pool.ended {
+                Vec::new()
+            } else {
+                let free = mem::replace(&mut pool.free, Vec::new());
+                free.into_iter().map(|(slot, buffer)| {
+                    let token = CancelToken::new();
+                    pool.tokens[slot] = Some(token.clone());
+                    let seq = pool.posted;
+                    pool.posted += 1;
+                    (seq, slot, buffer, token)
+                }).collect()
+            }
+        };
+        for (seq, slot, buffer, token) in free {
+            self.link.submit(PostJob {
+                filter: self.filter,
+                seq: seq,
+                slot: slot,
+                buffer: Some(buffer),
+                token: Some(token),
+                pool: self.pool.clone(),
+            });
+        }
+        let mut pool = self.pool.lock().unwrap();
+        // skip over the receives that ended without completing
+        let mut next = None;
+        while next.is_none() {
+            let taken = pool.taken;
+            match pool.ready.remove(&taken) {
+                Some(completed) => {
+                    pool.taken += 1;
+                    next = completed;
+                }
+                None => break,
+            }
+        }
+        match next {
+            Some(Ok((slot, status, buffer))) => {
+                let count = status.count(T::equivalent_datatype());
+                // MPI_UNDEFINED is negative
+                let len = cmp::min(cmp::max(count, 0) as usize, buffer.len());
+                Ok(Async::Ready(Some(Received {
+                    status: status,
+                    slot: slot,
+                    len: len,
+                    buffer: Some(buffer),
+                    pool: self.pool.clone(),
+                })))
+            }
+            Some(Err(err)) => Err(err),
+            None if pool.ended && pool.taken == pool.posted =>
+                Ok(Async::Ready(None)),
+            None => {
+                pool.task = Some(task::park());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+/// A message received into a pre-posted buffer.
+///
+/// It dereferences to the received elements.  Once dropped, the buffer is
+/// posted again.
+pub struct Received<T> {
+    status: Status,
+    slot: usize,
+    len: usize,
+    buffer: Option<Box<[T]>>,
+    pool: Arc<Mutex<Pool<T>>>,
+}
+
+impl<T: fmt::Debug> fmt::Debug for Received<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Received")
+            .field("status", &self.status)
+            .field("slot", &self.slot)
+            .field("data", &&**self)
+            .finish()
+    }
+}
+
+impl<T> Received<T> {
+    /// The status of the receive.
+    pub fn status(&self) -> Status {
+        self.status
+    }
+}
+
+impl<T> ops::Deref for Received<T> {
+    type Target = [T];
+    fn deref(&self) -> &Self::Target {
+        &self.buffer.as_ref().unwrap()[.. self.len]
+    }
+}
+
+impl<T> ops::DerefMut for Received<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buffer.as_mut().unwrap()[.. self.len]
+    }
+}
+
+impl<T> Drop for Received<T> {
+    fn drop(&mut self) {
+        let mut pool = self.pool.lock().unwrap();
+        if !pool.closed {
+            let buffer = self.buffer.take().unwrap();
+            pool.free.push((self.slot, buffer));
+            pool.unpark();
+        }
+    }
+}
+
+/// The `Job` submitted by `Preposted` to post a single receive.
+pub struct PostJob<T> {
+    filter: Filter,
+    seq: u64,
+    slot: usize,
+    // None once posted
+    buffer: Option<Box<[T]>>,
+    token: Option<CancelToken>,
+    pool: Arc<Mutex<Pool<T>>>,
+}
+
+impl<'a, T: Equivalence + 'a> Job<'a> for PostJob<T> {
+    fn run(mut self, request_poll: &mut RequestPoll<'a>) {
+        let seq = self.seq;
+        let slot = self.slot;
+        let pool = self.pool.clone();
+        let buffer = self.buffer.take().unwrap();
+        self.filter.post(request_poll, buffer, self.token.take(),
+                         move |anchor, result| {
+            let buffer = Box::<[T]>::unanchor(anchor);
+            let mut pool = pool.lock().unwrap();
+            pool.tokens[slot] = None;
+            let completed = match result {
+                Ok(status) => Some(Ok((slot, status, buffer))),
+                Err(ref err) if err.is_cancelled() => {
+                    pool.ended = true;
+                    pool.free.push((slot, buffer));
+                    None
+                }
+                Err(err) => {
+                    pool.free.push((slot, buffer));
+                    Some(Err(err))
+                }
+            };
+            pool.ready.insert(seq, completed);
+            pool.unpark();
+        });
+    }
+}
+
+impl<T> Drop for PostJob<T> {
+    fn drop(&mut self) {
+        // if it never ran, the switch is no longer accepting receives
+        if let Some(buffer) = self.buffer.take() {
+            let mut pool = self.pool.lock().unwrap();
+            pool.tokens[self.slot] = None;
+            pool.free.push((self.slot, buffer));
+            pool.ready.insert(self.seq, None);
+            pool.ended = true;
+            pool.unpark();
+        }
+    }
+}
+
+// the callback only holds on to the pool and the buffer, which are Send
+unsafe impl<'a, T> SyncJob<'a> for PostJob<T>
+    where T: Equivalence + marker::Send + 'a
+{}
@@ -0,0 +1,340 @@
+//! Probing for messages without receiving them right away.
+//!
+//! A `Probe` merely reports the status of a pending message (via
+//! `MPI_Iprobe`) and leaves the message for whoever receives it.  `Matches`
+//! claims messages just like `Incoming` does, but hands out each matched
+//! message as a `PendingMessage`, so that its size and tag can be inspected
+//! before deciding how to receive it, if at all.
+
+use std::{fmt, marker, mem};
+use std::marker::PhantomData;
+use futures::{Async, Future, Poll, Stream};
+use futures::sync::oneshot;
+use mpi;
+use mpi::datatype::Equivalence;
+use mpi::point_to_point::{Source, Status};
+use mpi::raw::AsRaw;
+use super::codec::{Decoder, SyncDecoder};
+use super::dispatch::{self, Filter, Subscribe, Subscription};
+use super::error::{MpiError, switch_gone};
+use super::incoming::{self, WithStatus};
+use super::large;
+use super::request_poll::RequestPoll;
+use super::switch::{Job, Submit, SyncJob};
+use super::tag::Tag;
+
+/// Future returned by `LinkExt::probe`.
+///
+/// ```ignore
+/// Probe<Source>: Future<Status>
+/// ```
+///
+/// The switch probes on its behalf once it is polled, until a matching
+/// message shows up.  The message is not claimed, so it may well be received
+/// by someone else before anything is done about it.
+#[must_use = "futures do nothing unless polled"]
+pub struct Probe<S, L> {
+    link: L,
+    // holds on to the communicator for as long as the probe exists
+    #[allow(dead_code)]
+    source: S,
+    filter: Filter,
+    subscription: Option<Subscription>,
+}
+
+impl<S: fmt::Debug, L: fmt::Debug> fmt::Debug for Probe<S, L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Probe")
+            .field("link", &self.link)
+            .field("source", &self.source)
+            .field("filter", &self.filter)
+            .field("subscription", &self.subscription)
+            .finish()
+    }
+}
+
+impl<S: Source, L> Probe<S, L> {
+    /// Probe for messages from `source` with the given `tag` (or any tag if
+    /// `None`).
+    pub fn new(link: L, source: S, tag: Option<Tag>) -> Self {
+        Probe {
+            filter: Filter::new(&source, tag),
+            link: link,
+            source: source,
+            subscription: None,
+        }
+    }
+}
+
+impl<S, L: Subscribe> Future for Probe<S, L> {
+    type Item = Status;
+    type Error = MpiError;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if self.subscription.is_none() {
+            match self.link.subscribe(self.filter, true) {
+                None => return Err(switch_gone()),
+                subscription => self.subscription = subscription,
+            }
+        }
+        let subscription = self.subscription.as_ref().unwrap();
+        match subscription.take() {
+            Some(Ok((_, status))) => Ok(Async::Ready(status)),
+            Some(Err(err)) => Err(err),
+            None if subscription.is_ended() => Err(switch_gone()),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+// A matched message handle, which can be received on any thread since the
+// thread-safe switches require MPI_THREAD_MULTIPLE anyway.
+#[derive(Debug)]
+pub(crate) struct Handle(pub mpi::ffi::MPI_Message);
+
+unsafe impl marker::Send for Handle {}
+
+/// A message that has been matched but not yet received.
+///
+/// ```ignore
+/// PendingMessage: receive(Decoder) -> Future<(Status, Message)>
+/// ```
+///
+/// Nobody else can receive the message in the meantime, so the decision
+/// can be deferred for as long as the handle is kept.  Note however that a
+/// synchronous send of the message does not complete until it is received.
+/// Dropping the handle discards the message.
+pub struct PendingMessage<'a, L: Submit<'a, DiscardJob>> {
+    link: L,
+    // None once received
+    handle: Option<Handle>,
+    status: Status,
+    phantom: PhantomData<&'a ()>,
+}
+
+impl<'a, L> fmt::Debug for PendingMessage<'a, L>
+    where L: Submit<'a, DiscardJob> + fmt::Debug
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PendingMessage")
+            .field("link", &self.link)
+            .field("handle", &self.handle)
+            .field("status", &self.status)
+            .finish()
+    }
+}
+
+impl<'a, L: Submit<'a, DiscardJob>> PendingMessage<'a, L> {
+    pub(crate) fn new(link: L, handle: Handle, status: Status) -> Self {
+        PendingMessage {
+            link: link,
+            handle: Some(handle),
+            status: status,
+            phantom: PhantomData,
+        }
+    }
+
+    /// The status of the message, which includes its source and tag.
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    /// The number of elements of type `T` in the message.  This fails if
+    /// the message does not consist of whole elements.
+    pub fn len<T: Equivalence>(&self) -> Result<usize, MpiError> {
+        let datatype = T::equivalent_datatype().as_raw();
+        large::message_len(&self.status.as_raw(), datatype)
+    }
+
+    /// Start receiving the message using `decoder`.
+    pub fn receive<C>(mut self, decoder: C) -> Receive<C::FutureMessage>
+        where C: Decoder<'a>,
+              L: Submit<'a, ReceiveJob<'a, C>>,
+    {
+        let (sender, receiver) = oneshot::channel();
+        self.link.submit(ReceiveJob {
+            codec: decoder,
+            handle: self.handle.take().unwrap(),
+            status: self.status,
+            sender: sender,
+        });
+        Receive(ReceiveState::Started { receiver: receiver })
+    }
+
+    /// Receive the message into a scratch buffer and throw it away.  This
+    /// is the same as dropping the handle.
+    pub fn discard(self) {}
+}
+
+impl<'a, L: Submit<'a, DiscardJob>> Drop for PendingMessage<'a, L> {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            self.link.submit(DiscardJob {
+                handle: handle,
+                status: self.status,
+            });
+        }
+    }
+}
+
+enum ReceiveState<F: Future> {
+    Started {
+        receiver: oneshot::Receiver<WithStatus<F>>,
+    },
+    Receiving(WithStatus<F>),
+    Invalid,
+}
+
+/// Future returned by `PendingMessage::receive`.
+///
+/// ```ignore
+/// Receive<Future<Message>>: Future<(Status, Message)>
+/// ```
+///
+/// Dropping it before the receive completes cancels the receive.
+#[must_use = "futures do nothing unless polled"]
+pub struct Receive<F: Future>(ReceiveState<F>);
+
+impl<F: Future> fmt::Debug for Receive<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self.0 {
+            ReceiveState::Started { .. } => "Receive::Started",
+            ReceiveState::Receiving(_) => "Receive::Receiving",
+            ReceiveState::Invalid => "Receive::Invalid",
+        })
+    }
+}
+
+impl<F: Future<Error=MpiError>> Future for Receive<F> {
+    type Item = (Status, F::Item);
+    type Error = MpiError;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match mem::replace(&mut self.0, ReceiveState::Invalid) {
+            ReceiveState::Started { mut receiver } => match receiver.poll() {
+                Ok(Async::NotReady) => {
+                    self.0 = ReceiveState::Started { receiver: receiver };
+                    Ok(Async::NotReady)
+                }
+                Ok(Async::Ready(recv)) => {
+                    self.0 = ReceiveState::Receiving(recv);
+                    self.poll()
+                }
+                // the job was dropped along with the switch
+                Err(oneshot::Canceled) => Err(switch_gone()),
+            },
+            ReceiveState::Receiving(mut recv) => {
+                let poll = recv.poll();
+                self.0 = ReceiveState::Receiving(recv);
+                poll
+            }
+            // panic loudly so the loop doesn't just silently stall!
+            ReceiveState::Invalid => panic!("invalid state"),
+        }
+    }
+}
+
+/// The `Job` submitted by `PendingMessage::receive` to decode the message.
+pub struct ReceiveJob<'a, C: Decoder<'a>> {
+    codec: C,
+    handle: Handle,
+    status: Status,
+    sender: oneshot::Sender<WithStatus<C::FutureMessage>>,
+}
+
+impl<'a, C: Decoder<'a>> Job<'a> for ReceiveJob<'a, C> {
+    fn run(mut self, request_poll: &mut RequestPoll<'a>) {
+        // safe because the handle comes from a matched probe and the status
+        // was obtained along with it
+        let recv = unsafe {
+            incoming::decode_matched(&mut self.codec, self.handle.0,
+                                     self.status, request_poll)
+        };
+        // if the future is gone, dropping this cancels the receive
+        let _ = self.sender.send(recv);
+    }
+}
+
+// the buffers are Send as guaranteed by SyncDecoder
+unsafe impl<'a, C: SyncDecoder<'a>> SyncJob<'a> for ReceiveJob<'a, C> {}
+
+/// The `Job` submitted by a dropped `PendingMessage` to discard the message.
+#[derive(Debug)]
+pub struct DiscardJob {
+    handle: Handle,
+    status: Status,
+}
+
+impl<'a> Job<'a> for DiscardJob {
+    fn run(self, request_poll: &mut RequestPoll<'a>) {
+        dispatch::discard(request_poll, Ok((self.handle.0, self.status)));
+    }
+}
+
+// the scratch buffer is a Vec<u8>
+unsafe impl<'a> SyncJob<'a> for DiscardJob {}
+
+/// Stream returned by `LinkExt::incoming_matched`.
+///
+/// ```ignore
+/// Matches<Source>: Stream<PendingMessage>
+/// ```
+///
+/// Messages are matched on behalf of the stream just as for `Incoming`,
+/// except that receiving them is up to the caller.
+#[must_use = "streams do nothing unless polled"]
+pub struct Matches<'a, S, L> {
+    link: L,
+    // holds on to the communicator for as long as the stream exists
+    #[allow(dead_code)]
+    source: S,
+    filter: Filter,
+    subscription: Option<Subscription>,
+    phantom: PhantomData<&'a ()>,
+}
+
+impl<'a, S: fmt::Debug, L: fmt::Debug> fmt::Debug for Matches<'a, S, L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Matches")
+            .field("link", &self.link)
+            .field("source", &self.source)
+            .field("filter", &self.filter)
+            .field("subscription", &self.subscription)
+            .finish()
+    }
+}
+
+impl<'a, S: Source, L> Matches<'a, S, L> {
+    /// Match messages from `source` with the given `tag` (or any tag if
+    /// `None`).
+    pub fn new(link: L, source: S, tag: Option<Tag>) -> Self {
+        Matches {
+            filter: Filter::new(&source, tag),
+            link: link,
+            source: source,
+            subscription: None,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<'a, S, L> Stream for Matches<'a, S, L>
+    where L: Subscribe + Submit<'a, DiscardJob> + Clone
+{
+    type Item = PendingMessage<'a, L>;
+    type Error = MpiError;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.subscription.is_none() {
+            match self.link.subscribe(self.filter, false) {
+                None => return Ok(Async::Ready(None)),
+                subscription => self.subscription = subscription,
+            }
+        }
+        let subscription = self.subscription.as_ref().unwrap();
+        match subscription.take() {
+            Some(Ok((msg, status))) => Ok(Async::Ready(Some(
+                PendingMessage::new(self.link.clone(), Handle(msg), status)))),
+            Some(Err(err)) => Err(err),
+            None if subscription.is_ended() => Ok(Async::Ready(None)),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
@@ -0,0 +1,387 @@
+//! A mode in which MPI progress is made by a dedicated background thread,
+//! independently of whether the executor gets around to polling anything.
+//!
+//! The thread drives the switch just like polling a `sync_switch::Switch`
+//! would, and idles according to its `IdlePolicy` (`IdlePolicy::Block` by
+//! default) whenever nothing completes.  While blocked in `MPI_Waitsome`, the
+//! thread is woken up by an alarm (a generalized request that a `Link`
+//! completes) whenever new work arrives.  Waiting `Incoming` streams and
+//! pending timeouts cannot wake the thread up by themselves, so as long as
+//! there are any, the thread only ever blocks for a short while.
+//!
+//! This requires MPI to be initialized with `Threading::Multiple`.  Because
+//! the requests are completed on the background thread, codecs and
+//! destinations must be `Send + 'static`.
+
+use std::{cmp, marker, thread};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use futures::Poll;
+use mpi::Threading;
+use mpi::environment;
+use super::alarm::Alarm;
+use super::attach::AttachBuffer;
+use super::codec::SyncDecoder;
+use super::dispatch::{Dispatcher, Filter, Subscribe, Subscription};
+use super::error::{self, MpiError};
+use super::incoming::{self, PollIncoming, WithStatus};
+use super::idle::{Idle, IdlePolicy, IdleStats, Idler};
+use super::link::LinkExt;
+use super::metrics::Metrics;
+use super::request_poll::RequestPoll;
+use super::shutdown::{Drain, Shutdown};
+use super::switch::{Submit, SyncJob};
+use super::timeout::{self, Timer, TimerQueue, Timers};
+
+// the longest the thread blocks while it has to keep probing or checking on
+// timers, since these don't wake it up by themselves
+fn probe_interval() -> Duration {
+    Duration::new(0, 100_000)
+}
+
+// how long the thread may block, given the earliest pending deadline
+fn block_duration(deadline: Option<f64>) -> Duration {
+    let interval = probe_interval();
+    match deadline {
+        None => interval,
+        Some(deadline) => {
+            let left = deadline - timeout::now();
+            if left <= 0.0 {
+                Duration::new(0, 0)
+            } else {
+                let left = Duration::new(left as u64,
+                                         (left.fract() * 1e9) as u32);
+                cmp::min(left, interval)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    request_poll: RequestPoll<'static>,
+    stop: bool,
+    idler: Idler,
+    dispatcher: Dispatcher,
+    timers: TimerQueue,
+    drain: Drain,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // let the waiting streams find out that the switch is gone
+        self.dispatcher.close(&mut self.request_poll);
+        self.timers.close();
+    }
+}
+
+// Safe because the RequestPoll is only ever given callbacks and buffers that
+// are Send (as guaranteed by SyncJob, SyncEncoder, and SyncDecoder) and MPI
+// is known to support MPI_THREAD_MULTIPLE.
+unsafe impl marker::Send for Inner {}
+
+fn run(inner: Arc<Mutex<Inner>>, alarm: Arc<Alarm>) {
+    loop {
+        let (idle, detached, bound) = {
+            let mut inner = inner.lock().unwrap();
+            let inner = &mut *inner;
+            if inner.stop {
+                break;
+            }
+            // once draining, nothing new can arrive, and the alarm would
+            // keep the RequestPoll from ever becoming empty
+            if !inner.drain.is_draining() {
+                alarm.arm(&mut inner.request_poll);
+            }
+            let probing = inner.dispatcher.dispatch(&mut inner.request_poll);
+            let timing = inner.timers.fire();
+            let completed = inner.request_poll.test();
+            if inner.drain.is_done(&inner.request_poll) {
+                inner.stop = true;
+                break;
+            }
+            let pending = !inner.request_poll.is_empty();
+            // probes and timers merely bound how long the thread blocks
+            // rather than making it yield
+            let idle = inner.idler.plan(completed, false, pending);
+            // a stream may start waiting at any time without waking us up
+            let bounded = probing || timing || !inner.dispatcher.is_empty();
+            let detached = match idle {
+                Idle::Block if !bounded =>
+                    Some(inner.request_poll.detach()),
+                _ => None,
+            };
+            (idle, detached, block_duration(inner.timers.next_deadline()))
+        };
+        if idle == Idle::Spin {
+            continue;
+        }
+        // idle without holding the lock so that links can go on submitting
+        // requests, which rings the alarm and unparks the thread
+        let start = Instant::now();
+        let detached = match (idle, detached) {
+            (_, Some(mut detached)) => {
+                detached.wait();
+                Some(detached)
+            }
+            (Idle::Block, None) => {
+                thread::park_timeout(bound);
+                None
+            }
+            (Idle::Sleep(duration), None) => {
+                thread::park_timeout(duration);
+                None
+            }
+            (idle, None) => {
+                idle.pause();
+                None
+            }
+        };
+        let mut inner = inner.lock().unwrap();
+        if let Some(detached) = detached {
+            inner.request_poll.reattach(detached);
+        }
+        inner.idler.record(start.elapsed());
+    }
+    // must not leave the alarm pending or the RequestPoll will wait on it
+    // forever when dropped
+    alarm.ring();
+}
+
+/// Scheduler for MPI communications that runs on a background thread.
+///
+/// Unlike `switch::Switch`, this is not a `Future` and does not need to be
+/// spawned on an executor.  The thread keeps running until `Link::close` is
+/// called, a shutdown finishes, or the `Switch` is dropped, after which
+/// pending requests are cancelled where possible and waited on.  Dropping
+/// the `Switch` blocks until the thread has finished, so it must be dropped
+/// before the `Universe`.
+#[derive(Debug)]
+pub struct Switch {
+    link: Link,
+    thread: Option<thread::JoinHandle<()>>,
+    attach_buffer: Mutex<Option<AttachBuffer>>,
+}
+
+impl Switch {
+    /// Start the progress thread.  If the threading level provided by MPI is
+    /// insufficient, the actual level is returned as an error.
+    pub fn new() -> Result<Self, Threading> {
+        Self::with_idle_policy(IdlePolicy::Block)
+    }
+
+    /// Start the progress thread with the given idle policy.  See `new`.
+    pub fn with_idle_policy(idle_policy: IdlePolicy)
+                            -> Result<Self, Threading> {
+        match environment::threading_support() {
+            Threading::Multiple => {}
+            threading => return Err(threading),
+        }
+        error::set_errors_return_world();
+        let metrics = Arc::new(Mutex::new(Metrics::default()));
+        let alarm = Arc::new(Alarm::default());
+        let mut inner = Inner::default();
+        inner.idler = Idler::new(idle_policy);
+        inner.request_poll.share_metrics(metrics.clone());
+        inner.request_poll.set_alarm(alarm.clone());
+        let inner = Arc::new(Mutex::new(inner));
+        let link = Arc::downgrade(&inner);
+        let thread = {
+            let alarm = alarm.clone();
+            thread::spawn(move || run(inner, alarm))
+        };
+        Ok(Switch {
+            link: Link {
+                inner: link,
+                alarm: alarm,
+                thread: thread.thread().clone(),
+                metrics: metrics,
+            },
+            thread: Some(thread),
+            attach_buffer: Mutex::new(None),
+        })
+    }
+
+    /// Allocate and attach a buffer for buffered-mode sends.  See
+    /// `switch::Switch::attach_buffer`.
+    pub fn attach_buffer(&self, size: usize) -> Result<(), MpiError> {
+        let mut attach_buffer = self.attach_buffer.lock().unwrap();
+        *attach_buffer = None;
+        *attach_buffer = Some(AttachBuffer::attach(size)?);
+        Ok(())
+    }
+
+    /// Acquire a `Link` to this `Switch`.
+    pub fn link(&self) -> Link {
+        self.link.clone()
+    }
+
+    /// Shut down the progress thread and wait for it to finish.
+    pub fn join(mut self) -> thread::Result<()> {
+        self.link.close();
+        self.thread.take().unwrap().join()
+    }
+}
+
+impl Drop for Switch {
+    fn drop(&mut self) {
+        self.link.close();
+        // the thread must be done with MPI before MPI is finalized, and a
+        // panic on the thread has nowhere to go from here
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Used to perform MPI requests through a progress thread.
+///
+/// `Link` can be cloned as many times as you like and sent to other threads.
+/// The requests themselves are made through `LinkExt`.
+#[derive(Debug, Clone)]
+pub struct Link {
+    inner: Weak<Mutex<Inner>>,
+    alarm: Arc<Alarm>,
+    thread: thread::Thread,
+    metrics: Arc<Mutex<Metrics>>,
+}
+
+impl Link {
+    // let the thread know that there may be something new to do, in case it
+    // is blocked
+    fn wake(&self) {
+        self.alarm.ring();
+        self.thread.unpark();
+    }
+
+    /// Gracefully shut down the associated `Switch`.  See
+    /// `switch::Link::close`.
+    pub fn close(&self) {
+        self.inner.upgrade().map(|inner| {
+            inner.lock().unwrap().stop = true;
+        });
+        self.wake();
+    }
+
+    /// Shut down the associated `Switch` gracefully.  See
+    /// `switch::Link::shutdown`.
+    ///
+    /// The progress thread exits once the shutdown has finished.
+    pub fn shutdown(&self) -> Shutdown {
+        let shutdown = match self.inner.upgrade() {
+            None => Shutdown::done(),
+            Some(inner) => {
+                let mut inner = inner.lock().unwrap();
+                let inner = &mut *inner;
+                if inner.stop {
+                    Shutdown::done()
+                } else {
+                    inner.drain.begin(&mut inner.request_poll,
+                                      &mut inner.dispatcher)
+                }
+            }
+        };
+        self.wake();
+        shutdown
+    }
+
+    /// Change the idle policy of the associated `Switch`.
+    pub fn set_idle_policy(&self, idle_policy: IdlePolicy) {
+        self.inner.upgrade().map(|inner| {
+            inner.lock().unwrap().idler.set_policy(idle_policy);
+        });
+        self.wake();
+    }
+
+    /// Obtain the idle counters of the associated `Switch`, if it is still
+    /// alive.
+    pub fn idle_stats(&self) -> Option<IdleStats> {
+        self.inner.upgrade().map(|inner| inner.lock().unwrap().idler.stats())
+    }
+
+    /// Obtain a snapshot of the communication metrics of the associated
+    /// `Switch`, if it is still alive.
+    pub fn metrics(&self) -> Option<Metrics> {
+        self.inner.upgrade().map(|_| self.metrics.lock().unwrap().clone())
+    }
+
+    /// Modify the internal `RequestPoll`, if the `Switch` is still alive.
+    /// This is mostly for internal use.  Nesting calls to this function will
+    /// cause a deadlock.
+    ///
+    /// # Unsafety
+    ///
+    /// Every callback and buffer inserted into the `RequestPoll` must be
+    /// `Send`.  The `RequestPoll` must not be tested or waited on, since the
+    /// progress thread may be waiting on its requests without holding the
+    /// lock.
+    pub unsafe fn modify_request_poll<F, R>(&self, f: F) -> R
+        where F: FnOnce(Option<&mut RequestPoll<'static>>) -> R
+    {
+        let result = match self.inner.upgrade() {
+            None => f(None),
+            Some(inner) =>
+                f(Some(&mut inner.lock().unwrap().request_poll)),
+        };
+        // the thread may be blocked without knowing about the new requests
+        self.wake();
+        result
+    }
+}
+
+impl LinkExt<'static> for Link {}
+
+impl Subscribe for Link {
+    fn subscribe(&self, filter: Filter, peek: bool) -> Option<Subscription> {
+        let subscription = self.inner.upgrade().and_then(|inner| {
+            let mut inner = inner.lock().unwrap();
+            if inner.drain.is_draining() {
+                None
+            } else {
+                Some(inner.dispatcher.subscribe(filter, peek))
+            }
+        });
+        // the thread may be blocked without knowing that it has to probe
+        self.wake();
+        subscription
+    }
+}
+
+impl Timers for Link {
+    fn timer(&self, deadline: f64) -> Option<Timer> {
+        let timer = self.inner.upgrade().map(|inner| {
+            inner.lock().unwrap().timers.add(deadline)
+        });
+        // the thread may be blocked without knowing about the new timer
+        self.wake();
+        timer
+    }
+}
+
+impl<C: SyncDecoder<'static>> PollIncoming<'static, C> for Link {
+    fn poll_incoming(&self, codec: &mut C, subscription: &Subscription)
+                     -> Poll<Option<WithStatus<C::FutureMessage>>, MpiError> {
+        // safe because SyncDecoder ensures the buffers are all Send
+        unsafe {
+            self.modify_request_poll(|request_poll| {
+                incoming::poll_matched(codec, subscription, request_poll)
+            })
+        }
+    }
+}
+
+impl<J: SyncJob<'static>> Submit<'static, J> for Link {
+    fn submit(&self, job: J) {
+        // sound because SyncJob only inserts callbacks that are Send
+        self.inner.upgrade().map(|inner| {
+            let mut inner = inner.lock().unwrap();
+            if inner.drain.is_draining() {
+                return;
+            }
+            inner.idler.poke();
+            job.run(&mut inner.request_poll);
+        });
+        // the thread may be blocked without knowing about the new requests
+        self.wake();
+    }
+}
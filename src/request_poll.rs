@@ -1,22 +1,36 @@
 //! An interface for polling multiple MPI requests simultaneously as well as
 //! managing ownership of the associated buffers.
+//!
+//! Failures of individual requests are passed on to their callbacks.  Only
+//! failures that cannot be attributed to any particular request (which would
+//! indicate a bug) still abort the job.
 
-use std::{self, fmt, mem, ptr};
+use std::{fmt, mem, ptr};
+use std::sync::{Arc, Mutex};
 use conv::ValueInto;
 use libc;
 use mpi;
+use mpi::datatype::{AsDatatype, Pointer, PointerMut};
 use mpi::raw::AsRaw;
-use mpi::point_to_point::{Destination, Message};
-use super::buffer::{OwnedBuffer, OwnedBufferMut};
+use mpi::point_to_point::{Destination, Message, Status};
+use super::alarm::Alarm;
+use super::buffer::{OwnedBuffer, OwnedBufferMut, unbind_buffer};
+use super::cancel::CancelToken;
+use super::dispatch;
+use super::error::{MpiError, OrError};
+use super::large;
+use super::metrics::{self, Metrics, Op};
+use super::tag::Tag;
+use super::timeout;
 
-fn abort(errorcode: libc::c_int) -> ! {
+pub(crate) fn abort(errorcode: libc::c_int) -> ! {
     unsafe {
         mpi::ffi::MPI_Abort(mpi::ffi::RSMPI_COMM_WORLD, errorcode);
         libc::abort();
     }
 }
 
-trait OrAbort {
+pub(crate) trait OrAbort {
     fn or_abort(self);
 }
 
@@ -28,46 +42,143 @@ impl OrAbort for libc::c_int {
     }
 }
 
-unsafe fn unbind_buffer<'a, B: OwnedBuffer>(b: &B) -> &'a B::Buffer {
-    mem::transmute(b.as_buffer())
+/// Communication mode of a send.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SendMode {
+    /// `MPI_Isend`: the send completes once the buffer can be reused.
+    Standard,
+    /// `MPI_Issend`: the send completes only once the receiver has started
+    /// receiving the message.
+    Synchronous,
+    /// `MPI_Irsend`: the send may only be started if the matching receive
+    /// has already been posted, which the protocol must guarantee.
+    Ready,
+    /// `MPI_Ibsend`: the message is copied into the attach buffer owned by
+    /// the switch (see `Switch::attach_buffer`) and the send completes
+    /// immediately.  Fails if the buffer has too little room left.
+    Buffered,
 }
 
-trait Callback {
-    fn callback(self: Box<Self>) {}
+impl Default for SendMode {
+    fn default() -> Self {
+        SendMode::Standard
+    }
+}
+
+impl SendMode {
+    fn start_fn(self) -> unsafe extern "C" fn(*const libc::c_void,
+                                             libc::c_int,
+                                             mpi::ffi::MPI_Datatype,
+                                             libc::c_int,
+                                             libc::c_int,
+                                             mpi::ffi::MPI_Comm,
+                                             *mut mpi::ffi::MPI_Request)
+                                             -> libc::c_int {
+        match self {
+            SendMode::Standard => mpi::ffi::MPI_Isend,
+            SendMode::Synchronous => mpi::ffi::MPI_Issend,
+            SendMode::Ready => mpi::ffi::MPI_Irsend,
+            SendMode::Buffered => mpi::ffi::MPI_Ibsend,
+        }
+    }
+
+    pub(crate) fn init_fn(self)
+                          -> unsafe extern "C" fn(*const libc::c_void,
+                                                 libc::c_int,
+                                                 mpi::ffi::MPI_Datatype,
+                                                 libc::c_int,
+                                                 libc::c_int,
+                                                 mpi::ffi::MPI_Comm,
+                                                 *mut mpi::ffi::MPI_Request)
+                                                 -> libc::c_int {
+        match self {
+            SendMode::Standard => mpi::ffi::MPI_Send_init,
+            SendMode::Synchronous => mpi::ffi::MPI_Ssend_init,
+            SendMode::Ready => mpi::ffi::MPI_Rsend_init,
+            SendMode::Buffered => mpi::ffi::MPI_Bsend_init,
+        }
+    }
+}
+
+trait Callback<'a> {
+    fn callback(self: Box<Self>, _: Result<(), MpiError>,
+                _: &mpi::ffi::MPI_Status, _: &mut RequestPoll<'a>) {}
 }
 
 struct CallbackImpl<F>(F);
 
-impl<F: FnOnce()> Callback for CallbackImpl<F> {
-    fn callback(self: Box<Self>) {
-        self.0()
+impl<'a, F: FnOnce(Result<(), MpiError>)> Callback<'a> for CallbackImpl<F> {
+    fn callback(self: Box<Self>, result: Result<(), MpiError>,
+                _: &mpi::ffi::MPI_Status, _: &mut RequestPoll<'a>) {
+        self.0(result)
     }
 }
 
-struct UnsafeScope;
+// for callbacks that need to know what was received
+struct StatusCallbackImpl<F>(F);
 
-unsafe impl<'a> mpi::request::Scope<'a> for UnsafeScope {
-    fn register(&self) {}
-    unsafe fn unregister(&self) {}
+impl<'a, F> Callback<'a> for StatusCallbackImpl<F>
+    where F: FnOnce(Result<Status, MpiError>)
+{
+    fn callback(self: Box<Self>, result: Result<(), MpiError>,
+                status: &mpi::ffi::MPI_Status, _: &mut RequestPoll<'a>) {
+        self.0(result.map(|()| Status::from_raw(*status)))
+    }
+}
+
+// for callbacks that go on to start further requests
+struct ThenImpl<F>(F);
+
+impl<'a, F> Callback<'a> for ThenImpl<F>
+    where F: FnOnce(Result<(), MpiError>, &mut RequestPoll<'a>)
+{
+    fn callback(self: Box<Self>, result: Result<(), MpiError>,
+                _: &mpi::ffi::MPI_Status, request_poll: &mut RequestPoll<'a>) {
+        self.0(result, request_poll)
+    }
 }
 
+type Completed<'a> =
+    (Box<Callback<'a> + 'a>, Result<(), MpiError>, mpi::ffi::MPI_Status);
+
 /// Manages a collection of requests and keeps their associated buffers alive.
 ///
 /// When `RequestPoll` is dropped, all pending requests will be canceled when
 /// possible and waited on.
 pub struct RequestPoll<'a> {
-    // These three vectors are all synchronized in length and position of
+    // These six vectors are all synchronized in length and position of
     // items.  Every callback must outlive its corresponding MPI_Request,
     // because within the callback's context there is an anchor that is
     // responsible for keeping the buffer alive.
     requests: Vec<mpi::ffi::MPI_Request>,
     cancelables: Vec<bool>,
-    callbacks: Vec<Box<Callback + 'a>>,
+    callbacks: Vec<Box<Callback<'a> + 'a>>,
+    tokens: Vec<Option<CancelToken>>,
+    // the operation and MPI_Wtime at its start, for the metrics
+    ops: Vec<Option<(Op, f64)>>,
+    // persistent requests owned by someone else, which must not be freed
+    borrowed: Vec<bool>,
+    // number of requests with Op::Internal, which don't count as outstanding
+    internal: usize,
+
+    // shared so that it can be read while the poll is in use elsewhere
+    metrics: Arc<Mutex<Metrics>>,
+    // handed to the cancel tokens so that they can wake up the switch
+    alarm: Option<Arc<Alarm>>,
 
-    // Temporary caches for indices from the previous test.  (Don't bother
-    // with Statuses because the information is not useful for sends, and for
-    // receives we're already probing anyway.)
+    // Temporary caches for indices and statuses from the previous test.
+    // The statuses are only used to find out which requests failed.
     indices: Vec<libc::c_int>,
+    statuses: Vec<mpi::ffi::MPI_Status>,
+    failed: bool,
+    // set while the requests are waited on through a Detached copy, in
+    // which case cancel_receives is deferred until it is handed back
+    detached: bool,
+    cancel_deferred: bool,
+    // The callbacks of the requests that completed in the previous test,
+    // which are only called once the other Vecs are in sync again so that
+    // they can insert further requests.
+    completed: Vec<Completed<'a>>,
 }
 
 impl<'a> fmt::Debug for RequestPoll<'a> {
@@ -78,7 +189,15 @@ impl<'a> fmt::Debug for RequestPoll<'a> {
             .field("requests", &self.requests)
             .field("cancelables", &self.cancelables)
             .field("callbacks", &callbacks)
+            .field("tokens", &self.tokens)
+            .field("ops", &self.ops)
+            .field("borrowed", &self.borrowed)
+            .field("metrics", &self.metrics)
+            .field("alarm", &self.alarm)
             .field("indices", &self.indices)
+            .field("failed", &self.failed)
+            .field("detached", &self.detached)
+            .field("cancel_deferred", &self.cancel_deferred)
             .finish()
     }
 }
@@ -89,7 +208,18 @@ impl<'a> Default for RequestPoll<'a> {
             requests: Default::default(),
             cancelables: Default::default(),
             callbacks: Default::default(),
+            tokens: Default::default(),
+            ops: Default::default(),
+            borrowed: Default::default(),
+            internal: 0,
+            metrics: Default::default(),
+            alarm: None,
             indices: Default::default(),
+            statuses: Default::default(),
+            failed: false,
+            detached: false,
+            cancel_deferred: false,
+            completed: Default::default(),
         }
     }
 }
@@ -111,15 +241,86 @@ unsafe fn wait_all(requests: &mut [mpi::ffi::MPI_Request]) {
         mpi::ffi::RSMPI_STATUSES_IGNORE).or_abort();
 }
 
+unsafe fn is_cancelled(status: &mpi::ffi::MPI_Status) -> bool {
+    let mut flag: libc::c_int = 0;
+    mpi::ffi::MPI_Test_cancelled(status, &mut flag).or_abort();
+    flag != 0
+}
+
 /// Note: null requests are allowed.
-unsafe fn free_all(requests: &mut [mpi::ffi::MPI_Request]) {
-    for request in requests {
-        if *request != mpi::ffi::RSMPI_REQUEST_NULL {
+unsafe fn free_all(requests: &mut [mpi::ffi::MPI_Request],
+                   borrowed: &[bool]) {
+    for (request, &borrowed) in requests.iter_mut().zip(borrowed) {
+        if *request != mpi::ffi::RSMPI_REQUEST_NULL && !borrowed {
             mpi::ffi::MPI_Request_free(request).or_abort();
         }
     }
 }
 
+// test or wait on `requests` through `f` (e.g. `MPI_Testsome`), storing
+// the indices and statuses of the completed ones
+fn poll_some<F>(requests: &mut [mpi::ffi::MPI_Request],
+                indices: &mut Vec<libc::c_int>,
+                statuses: &mut Vec<mpi::ffi::MPI_Status>,
+                failed: &mut bool, f: F)
+    where F: FnOnce(libc::c_int, *mut mpi::ffi::MPI_Request,
+                    *mut libc::c_int, *mut libc::c_int,
+                    *mut mpi::ffi::MPI_Status) -> libc::c_int
+{
+    if requests.is_empty() {
+        // MPI does stupid things when the request list is empty
+        return;
+    }
+    let incount = requests.len();
+    indices.reserve(incount);
+    statuses.reserve(incount);
+    let incount = incount.value_into().unwrap(); // may panic
+    unsafe {
+        let mut outcount: libc::c_int = mem::uninitialized();
+        let code = f(incount,
+                     requests.as_mut_ptr(),
+                     &mut outcount,
+                     indices.as_mut_ptr(),
+                     statuses.as_mut_ptr());
+        // MPI_ERR_IN_STATUS means that some of the requests failed, in
+        // which case the error of each request is in its status
+        match code.or_error() {
+            Ok(()) => {}
+            Err(ref err) if err.class() ==
+                mpi::ffi::MPI_ERR_IN_STATUS as libc::c_int =>
+                *failed = true,
+            Err(_) => abort(code),
+        }
+        let outcount = outcount as _;
+        debug_assert!(outcount <= indices.capacity());
+        indices.set_len(outcount);
+        statuses.set_len(outcount);
+    }
+}
+
+/// A copy of the requests of a `RequestPoll` that can be waited on without
+/// access to the poll itself.  See `RequestPoll::detach`.
+pub(crate) struct Detached {
+    requests: Vec<mpi::ffi::MPI_Request>,
+    indices: Vec<libc::c_int>,
+    statuses: Vec<mpi::ffi::MPI_Status>,
+    failed: bool,
+}
+
+// Safe because the requests are merely used as handles, and the thread-safe
+// switches require MPI_THREAD_MULTIPLE anyway.
+unsafe impl Send for Detached {}
+
+impl Detached {
+    /// Block until at least one of the requests has completed.
+    pub fn wait(&mut self) {
+        poll_some(&mut self.requests, &mut self.indices, &mut self.statuses,
+                  &mut self.failed, |n, r, m, i, s| unsafe {
+                      mpi::ffi::MPI_Waitsome(n, r, m, i, s)
+                  });
+    }
+}
+
 impl<'a> Drop for RequestPoll<'a> {
     fn drop(&mut self) {
         unsafe {
@@ -136,8 +337,9 @@ impl<'a> Drop for RequestPoll<'a> {
             }
             // deactivate all requests and free all non-persistent ones
             wait_all(&mut self.requests);
-            // free remaining persistent requests
-            free_all(&mut self.requests);
+            // free remaining persistent requests, except for the borrowed
+            // ones, whose owners are in the callbacks
+            free_all(&mut self.requests, &self.borrowed);
         }
         // (the anchors in self.callbacks will get freed automatically)
     }
@@ -145,28 +347,80 @@ impl<'a> Drop for RequestPoll<'a> {
 
 impl<'a> RequestPoll<'a> {
     fn flush(&mut self) {
-        // first pull out the request data without removing anything: we must
-        // not swap_remove the other Vecs because the ordering of self.indices
-        // is unknown
-        for &i in &self.indices {
-            let i = i as usize;
-            // call the callbacks in the original order of the indices
-            unsafe {
-                ptr::read(&self.callbacks[i]).callback();
+        if self.indices.is_empty() {
+            self.failed = false;
+            return;
+        }
+        let now = timeout::now();
+        let mut completed = mem::replace(&mut self.completed, Vec::new());
+        {
+            let mut metrics = self.metrics.lock().unwrap();
+            // first pull out the request data without removing anything: we
+            // must not swap_remove the other Vecs because the ordering of
+            // self.indices is unknown
+            for (k, &i) in self.indices.iter().enumerate() {
+                let i = i as usize;
+                let mut result = if self.failed {
+                    self.statuses[k].MPI_ERROR.or_error()
+                } else {
+                    Ok(())
+                };
+                // a cancelled receive completes successfully without having
+                // received anything, which must not be mistaken for a
+                // message
+                if result.is_ok() && self.cancelables[i] &&
+                    unsafe { is_cancelled(&self.statuses[k]) } {
+                    result = Err(MpiError::cancelled());
+                }
+                if let (Some((op, start)), true) =
+                    (self.ops[i], result.is_ok()) {
+                    metrics.record(op, now - start, &self.statuses[k]);
+                }
+                // keep the callbacks in the original order of the indices
+                unsafe {
+                    if let Some(ref token) = self.tokens[i] {
+                        token.finish(&self.statuses[k]);
+                    }
+                    completed.push((ptr::read(&self.callbacks[i]), result,
+                                    self.statuses[k]));
+                }
             }
+            self.failed = false;
+            // sort the indices so we can clean up the other Vecs
+            self.indices.sort();
+            for i in self.indices.drain(..).rev() {
+                let i = i as _;
+                self.cancelables.swap_remove(i);
+                self.tokens.swap_remove(i);
+                if let Some((Op::Internal, _)) = self.ops.swap_remove(i) {
+                    self.internal -= 1;
+                }
+                let borrowed = self.borrowed.swap_remove(i);
+                // don't drop it because we've moved it out already!
+                mem::forget(self.callbacks.swap_remove(i));
+                // remove and free the request if it's persistent and ours
+                let mut request = self.requests.swap_remove(i);
+                unsafe {
+                    if request != mpi::ffi::RSMPI_REQUEST_NULL && !borrowed {
+                        mpi::ffi::MPI_Request_free(&mut request).or_abort();
+                    }
+                }
+            }
+            metrics.outstanding = self.requests.len() - self.internal;
         }
-        // sort the indices so we can clean up the other Vecs
-        self.indices.sort();
-        for i in self.indices.drain(..).rev() {
-            let i = i as _;
-            self.cancelables.swap_remove(i);
-            // don't drop it because we already called it!
-            mem::forget(self.callbacks.swap_remove(i));
-            // remove and free the request if it's persistent
-            let mut request = self.requests.swap_remove(i);
-            unsafe {
-                if request != mpi::ffi::RSMPI_REQUEST_NULL {
-                    mpi::ffi::MPI_Request_free(&mut request).or_abort();
+        for (callback, result, status) in completed.drain(..) {
+            callback.callback(result, &status, self);
+        }
+        self.completed = completed;
+    }
+
+    /// Issue `MPI_Cancel` on every request whose cancellation has been
+    /// requested through its `CancelToken`.
+    fn issue_cancels(&mut self) {
+        for (request, token) in self.requests.iter_mut().zip(&self.tokens) {
+            if let Some(ref token) = *token {
+                unsafe {
+                    token.issue(request);
                 }
             }
         }
@@ -177,82 +431,313 @@ impl<'a> RequestPoll<'a> {
                         *mut libc::c_int, *mut libc::c_int,
                         *mut mpi::ffi::MPI_Status) -> libc::c_int
     {
-        if self.requests.is_empty() {
-            // MPI does stupid things when the request list is empty
-            return;
-        }
-        let incount = self.requests.len();
-        self.indices.reserve(incount);
-        let incount = incount.value_into().unwrap(); // may panic
-        unsafe {
-            let mut outcount: libc::c_int = mem::uninitialized();
-            f(incount,
-              self.requests.as_mut_ptr(),
-              &mut outcount,
-              self.indices.as_mut_ptr(),
-              mpi::ffi::RSMPI_STATUSES_IGNORE).or_abort();
-            let outcount = outcount as _;
-            debug_assert!(outcount <= self.indices.capacity());
-            self.indices.set_len(outcount);
-        }
+        poll_some(&mut self.requests, &mut self.indices, &mut self.statuses,
+                  &mut self.failed, f);
     }
 
     /// Non-blocking test to see if some of the requests have completed.  For
     /// any request that is complete, the corresponding callback is called.
-    pub fn test(&mut self) {
+    /// Pending cancellations are issued beforehand.
+    ///
+    /// Returns the number of requests that completed.
+    pub fn test(&mut self) -> usize {
+        self.issue_cancels();
         self.poll_with(|n, r, m, i, s| unsafe {
             mpi::ffi::MPI_Testsome(n, r, m, i, s)
         });
+        let completed = self.indices.len();
         self.flush();
+        {
+            let mut metrics = self.metrics.lock().unwrap();
+            metrics.tests += 1;
+            if completed == 0 {
+                metrics.empty_tests += 1;
+            }
+        }
+        completed
     }
 
     /// Block until at least one request has completed.  Otherwise functions
     /// similar to `test`.
-    pub fn wait(&mut self) {
+    ///
+    /// Cancellations requested while blocked are only issued on the next
+    /// call to `test` or `wait`.
+    pub fn wait(&mut self) -> usize {
+        self.issue_cancels();
         self.poll_with(|n, r, m, i, s| unsafe {
             mpi::ffi::MPI_Waitsome(n, r, m, i, s)
         });
+        let completed = self.indices.len();
+        self.flush();
+        completed
+    }
+
+    /// Copy the requests so that they can be waited on through
+    /// `Detached::wait` while the poll itself remains available to others,
+    /// e.g. for inserting requests from other threads.  Pending
+    /// cancellations are issued beforehand.
+    ///
+    /// Until the copy is handed back to `reattach`, the poll must not be
+    /// tested or waited on, and `cancel_receives` is deferred, because the
+    /// copy may have freed some of the requests already.
+    pub(crate) fn detach(&mut self) -> Detached {
+        self.issue_cancels();
+        self.detached = true;
+        Detached {
+            requests: self.requests.clone(),
+            indices: Vec::new(),
+            statuses: Vec::new(),
+            failed: false,
+        }
+    }
+
+    /// Take back a copy obtained from `detach`, calling the callbacks of
+    /// the requests that completed in the meantime like `wait` does.
+    pub(crate) fn reattach(&mut self, detached: Detached) -> usize {
+        let Detached { requests, indices, statuses, failed } = detached;
+        // requests are only ever appended while detached, so the indices
+        // still refer to the same requests
+        for &i in &indices {
+            self.requests[i as usize] = requests[i as usize];
+        }
+        self.indices = indices;
+        self.statuses = statuses;
+        self.failed = failed;
+        self.detached = false;
+        let completed = self.indices.len();
         self.flush();
+        if mem::replace(&mut self.cancel_deferred, false) {
+            self.cancel_receives();
+        }
+        completed
+    }
+
+    /// Obtain a snapshot of the metrics.
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.lock().unwrap().clone()
+    }
+
+    /// Share the metrics with someone who does not have access to the
+    /// `RequestPoll` itself.
+    pub(crate) fn share_metrics(&mut self, metrics: Arc<Mutex<Metrics>>) {
+        self.metrics = metrics;
+    }
+
+    /// Have the cancel tokens of requests inserted from now on ring `alarm`
+    /// when cancellation is requested.
+    pub(crate) fn set_alarm(&mut self, alarm: Arc<Alarm>) {
+        self.alarm = Some(alarm);
+    }
+
+    /// Issue `MPI_Cancel` on every pending request marked as `cancelable`
+    /// (i.e. receives).  The requests still need to be tested or waited on
+    /// to complete, upon which the cancelled ones fail with an error for
+    /// which `MpiError::is_cancelled` holds.  A request that cannot be
+    /// cancelled simply completes normally.
+    pub fn cancel_receives(&mut self) {
+        if self.detached {
+            self.cancel_deferred = true;
+            return;
+        }
+        for (request, &cancelable) in self.requests.iter_mut()
+                                           .zip(&self.cancelables) {
+            unsafe {
+                if *request != mpi::ffi::RSMPI_REQUEST_NULL && cancelable {
+                    mpi::ffi::MPI_Cancel(request).or_abort();
+                }
+            }
+        }
+    }
+
+    /// Number of requests that have yet to complete.
+    pub fn len(&self) -> usize {
+        self.requests.len()
     }
 
-    /// Perform a matched receive on a message.
-    pub fn mrecv<B, F>(&mut self, msg: Message, buf: B, callback: F)
+    /// Whether there are no requests left to complete.
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Whether any requests other than those used internally by the switch
+    /// are left to complete.
+    pub(crate) fn has_outstanding(&self) -> bool {
+        self.requests.len() > self.internal
+    }
+
+    /// Perform a matched receive on a message, whose `status` was obtained
+    /// along with it from the matched probe.
+    ///
+    /// The callback receives the anchor along with the outcome of the
+    /// receive.  If the receive fails to start, the callback is called
+    /// immediately.  MPI cannot cancel the receive of a message that has
+    /// already been matched, so it always runs to completion.
+    pub fn mrecv<B, F>(&mut self, msg: Message, status: &Status, buf: B,
+                       callback: F)
         where B: OwnedBufferMut,
               B::Anchor: 'a,
-              F: FnOnce(B::Anchor) + 'a,
+              F: FnOnce(B::Anchor, Result<(), MpiError>) + 'a,
     {
-        self.reserve_one();             // may panic
+        let raw = msg.as_raw();
+        // the handle is consumed by the receive
+        mem::forget(msg);
         unsafe {
-            let (anchor, buf) = buf.into_buffer_mut();
-            let request = msg.immediate_matched_receive_into(UnsafeScope, buf);
-            let callback = move || callback(anchor);
-            self.insert(request.as_raw(), callback, true);
-            mem::forget(request);
+            self.mrecv_raw(raw, status, buf, callback);
+        }
+    }
+
+    /// Perform a matched receive on a raw message handle.  See `mrecv`.
+    ///
+    /// If the buffer can't be described to MPI, the message is thrown away
+    /// (see `dispatch::discard`) before the callback gets the error.
+    ///
+    /// # Unsafety
+    ///
+    /// `msg` must be a valid handle obtained from a matched probe (e.g.
+    /// `MPI_Improbe`) that has not been received yet, and `status` must be
+    /// the status obtained along with it.
+    pub unsafe fn mrecv_raw<B, F>(&mut self, mut msg: mpi::ffi::MPI_Message,
+                                  status: &Status, buf: B, callback: F)
+        where B: OwnedBufferMut,
+              B::Anchor: 'a,
+              F: FnOnce(B::Anchor, Result<(), MpiError>) + 'a,
+    {
+        self.reserve_one();             // may panic
+        let (anchor, buf) = buf.into_buffer_mut();
+        let layout = match large::layout(buf) {
+            Ok(layout) => layout,
+            Err(err) => {
+                // the message must be received regardless, or else a
+                // synchronous sender would wait forever
+                dispatch::discard(self, Ok((msg, *status)));
+                return callback(anchor, Err(err));
+            }
+        };
+        let mut request = mem::uninitialized();
+        match mpi::ffi::MPI_Imrecv(buf.pointer_mut(),
+                                   layout.count,
+                                   layout.datatype,
+                                   &mut msg,
+                                   &mut request).or_error() {
+            Err(err) => callback(anchor, Err(err)),
+            Ok(()) => {
+                let op = Op::Recv { datatype: buf.as_datatype().as_raw() };
+                self.insert_op(request, CallbackImpl(move |result| {
+                    callback(anchor, result)
+                }), true, None, Some(op), false)
+            }
+        }
+    }
+
+    /// Receive a message via `MPI_Irecv`.
+    ///
+    /// The callback receives the anchor along with the status of the
+    /// receive.  If the receive fails to start, the callback is called
+    /// immediately.  A cancelled receive fails with an error for which
+    /// `MpiError::is_cancelled` holds.
+    ///
+    /// # Unsafety
+    ///
+    /// `comm` must be a valid communicator and `source` and `tag` must be
+    /// valid for it (or wildcards).
+    pub(crate) unsafe fn irecv_raw<B, F>(&mut self, comm: mpi::ffi::MPI_Comm,
+                                         source: libc::c_int,
+                                         tag: libc::c_int, buf: B,
+                                         token: Option<CancelToken>,
+                                         callback: F)
+        where B: OwnedBufferMut,
+              B::Anchor: 'a,
+              F: FnOnce(B::Anchor, Result<Status, MpiError>) + 'a,
+    {
+        self.reserve_one();             // may panic
+        let (anchor, buf) = buf.into_buffer_mut();
+        let mut request = mem::uninitialized();
+        match large::layout(buf).and_then(|layout| {
+            mpi::ffi::MPI_Irecv(buf.pointer_mut(),
+                                layout.count,
+                                layout.datatype,
+                                source,
+                                tag,
+                                comm,
+                                &mut request).or_error()
+        }) {
+            Err(err) => callback(anchor, Err(err)),
+            Ok(()) => {
+                let op = Op::Recv { datatype: buf.as_datatype().as_raw() };
+                self.insert_op(request, StatusCallbackImpl(move |result| {
+                    callback(anchor, result)
+                }), true, None, Some(op), false)
+            }
         }
     }
 
     /// Send a message.
-    pub fn send<D, B, F>(&mut self, dest: D, buf: B,
-                         tag: u16, callback: F)
+    ///
+    /// The callback receives the buffer along with the outcome of the send.
+    /// If the send fails to start, the callback is called immediately.
+    ///
+    /// The send can be cancelled through `token`, although support for
+    /// cancelling sends varies between MPI implementations.
+    pub fn send<D, B, F>(&mut self, dest: D, buf: B, tag: Tag,
+                         token: Option<CancelToken>, callback: F)
+        where D: Destination,
+              B: OwnedBuffer + 'a,
+              F: FnOnce(B, Result<(), MpiError>) + 'a,
+    {
+        self.send_with_mode(dest, buf, tag, SendMode::Standard, token,
+                            callback)
+    }
+
+    /// Send a message in the given mode.  See `send`.
+    pub fn send_with_mode<D, B, F>(&mut self, dest: D, buf: B, tag: Tag,
+                                   mode: SendMode,
+                                   token: Option<CancelToken>, callback: F)
         where D: Destination,
               B: OwnedBuffer + 'a,
-              F: FnOnce(B) + 'a,
+              F: FnOnce(B, Result<(), MpiError>) + 'a,
     {
         self.reserve_one();             // may panic
-        // u16 is used here to prevent going over MPI_TAG_UB
-        // (the minimum MPI_TAG_UB is 2^15 - 1, but most MPI implementations
-        // support much more than that)
-        let tag = tag.value_into().unwrap();
+        let tag = tag.value();
+        let comm = dest.as_communicator().as_raw();
         unsafe {
             let buf_ref = unbind_buffer(&buf);
-            let request = dest.immediate_send_with_tag(UnsafeScope, buf_ref, tag);
-            let callback = move || callback(buf);
-            self.insert(request.as_raw(), callback, false);
-            std::mem::forget(request);
+            let mut request = mem::uninitialized();
+            let mut bytes = 0;
+            // the datatype may be freed as soon as the send has started
+            match large::layout(buf_ref).and_then(|layout| {
+                bytes = metrics::size_of(layout.count as u64,
+                                         layout.datatype);
+                mode.start_fn()(buf_ref.pointer(),
+                                layout.count,
+                                layout.datatype,
+                                dest.destination_rank(),
+                                tag,
+                                comm,
+                                &mut request).or_error()
+            }) {
+                Err(ref err) if mode == SendMode::Buffered && err.class() ==
+                    mpi::ffi::MPI_ERR_BUFFER as libc::c_int => {
+                    let err = err.clone().context(
+                        "attach buffer is missing or exhausted");
+                    callback(buf, Err(err))
+                }
+                Err(err) => callback(buf, Err(err)),
+                Ok(()) => {
+                    let op = Op::Send {
+                        dest: dest.destination_rank(),
+                        tag: tag,
+                        bytes: bytes,
+                    };
+                    self.insert_op(request, CallbackImpl(move |result| {
+                        callback(buf, result)
+                    }), false, token, Some(op), false)
+                }
+            }
         }
     }
 
-    /// Insert a request to be monitored.
+    /// Insert a request to be monitored.  The callback is called with the
+    /// outcome of the request once it completes.
     ///
     /// `cancelable` indicates whether `MPI_Cancel` will work on the request
     /// (`true` for receiving requests, `false` for all other requests).
@@ -264,11 +749,135 @@ impl<'a> RequestPoll<'a> {
     /// callback remains alive.
     pub unsafe fn insert<F>(&mut self, request: mpi::ffi::MPI_Request,
                             callback: F, cancelable: bool)
-        where F: FnOnce() + 'a
+        where F: FnOnce(Result<(), MpiError>) + 'a
+    {
+        self.insert_with_token(request, callback, cancelable, None)
+    }
+
+    /// Insert a request to be monitored, which can be cancelled individually
+    /// through `token` (regardless of `cancelable`).  See `insert`.
+    pub unsafe fn insert_with_token<F>(&mut self,
+                                       request: mpi::ffi::MPI_Request,
+                                       callback: F, cancelable: bool,
+                                       token: Option<CancelToken>)
+        where F: FnOnce(Result<(), MpiError>) + 'a
+    {
+        self.insert_op(request, CallbackImpl(callback), cancelable, token,
+                       None, false)
+    }
+
+    /// Insert a request used internally by a switch, which is neither
+    /// cancelable nor counted as outstanding in the metrics.  See `insert`.
+    pub(crate) unsafe fn insert_internal<F>(&mut self,
+                                            request: mpi::ffi::MPI_Request,
+                                            callback: F)
+        where F: FnOnce(Result<(), MpiError>) + 'a
+    {
+        self.insert_op(request, CallbackImpl(callback), false, None,
+                       Some(Op::Internal), false)
+    }
+
+    /// Start a persistent request via `MPI_Start` and monitor it until it
+    /// completes.  The request is not freed by the `RequestPoll`: it remains
+    /// owned by the callback, which gets called even if the request fails
+    /// to start.
+    ///
+    /// # Unsafety
+    ///
+    /// The request must be a valid, inactive persistent request.  The
+    /// callback must keep both the request and its buffer alive, and must
+    /// not free the request until it has been called.
+    pub(crate) unsafe fn start_persistent<F>(&mut self,
+                                             request: mpi::ffi::MPI_Request,
+                                             callback: F, cancelable: bool,
+                                             token: Option<CancelToken>,
+                                             op: Op)
+        where F: FnOnce(Result<(), MpiError>) + 'a
+    {
+        self.reserve_one();             // may panic
+        let mut request = request;
+        match mpi::ffi::MPI_Start(&mut request).or_error() {
+            Err(err) => callback(Err(err)),
+            Ok(()) =>
+                self.insert_op(request, CallbackImpl(callback), cancelable,
+                               token, Some(op), true),
+        }
+    }
+
+    /// Start a non-blocking collective operation on `comm` and monitor it
+    /// until it completes.  `start` initiates the operation (e.g. via
+    /// `MPI_Ibarrier`) and stores its request in the given location.  If
+    /// that fails, the callback is called immediately.
+    ///
+    /// Any temporary datatypes may be freed once `start` returns.
+    ///
+    /// The request is not cancelable, as MPI does not support cancelling
+    /// collective operations.
+    ///
+    /// # Unsafety
+    ///
+    /// `comm` must be a valid communicator.  The buffers used by the
+    /// operation must survive so long as the callback remains alive.
+    pub(crate) unsafe fn start_collective<S, F>(&mut self,
+                                                comm: mpi::ffi::MPI_Comm,
+                                                start: S, callback: F)
+        where S: FnOnce(*mut mpi::ffi::MPI_Request) -> Result<(), MpiError>,
+              F: FnOnce(Result<(), MpiError>) + 'a
+    {
+        self.reserve_one();             // may panic
+        let mut request = mem::uninitialized();
+        match start(&mut request) {
+            Err(err) => callback(Err(err)),
+            Ok(()) =>
+                self.insert_op(request, CallbackImpl(callback), false, None,
+                               None, false),
+        }
+    }
+
+    /// Start a non-blocking collective operation on `comm` like
+    /// `start_collective`, but with a callback that can go on to start
+    /// further requests, e.g. the next stage of the same operation.
+    ///
+    /// # Unsafety
+    ///
+    /// See `start_collective`.
+    pub(crate) unsafe fn start_collective_then<S, F>(&mut self,
+                                                     comm: mpi::ffi::MPI_Comm,
+                                                     start: S, callback: F)
+        where S: FnOnce(*mut mpi::ffi::MPI_Request) -> Result<(), MpiError>,
+              F: FnOnce(Result<(), MpiError>, &mut RequestPoll<'a>) + 'a
+    {
+        self.reserve_one();             // may panic
+        let mut request = mem::uninitialized();
+        match start(&mut request) {
+            Err(err) => callback(Err(err), self),
+            Ok(()) =>
+                self.insert_op(request, ThenImpl(callback), false, None,
+                               None, false),
+        }
+    }
+
+    unsafe fn insert_op<C>(&mut self, request: mpi::ffi::MPI_Request,
+                           callback: C, cancelable: bool,
+                           token: Option<CancelToken>, op: Option<Op>,
+                           borrowed: bool)
+        where C: Callback<'a> + 'a
     {
+        if let Some(Op::Internal) = op {
+            self.internal += 1;
+        }
+        if let (&Some(ref token), &Some(ref alarm)) = (&token, &self.alarm) {
+            token.set_alarm(alarm.clone());
+        }
+        let start = op.map(|op| (op, timeout::now()));
         self.requests.push(request);
         self.cancelables.push(cancelable);
-        self.callbacks.push(Box::new(CallbackImpl(callback)));
+        self.callbacks.push(Box::new(callback));
+        self.tokens.push(token);
+        self.ops.push(start);
+        self.borrowed.push(borrowed);
+        self.metrics.lock().unwrap().outstanding =
+            self.requests.len() - self.internal;
     }
 
     /// Allocate room for a single request if necessary.
@@ -280,5 +889,8 @@ impl<'a> RequestPoll<'a> {
         self.requests.reserve(1);
         self.cancelables.reserve(1);
         self.callbacks.reserve(1);
+        self.tokens.reserve(1);
+        self.ops.reserve(1);
+        self.borrowed.reserve(1);
     }
 }
@@ -1,43 +1,52 @@
 use std::{fmt, mem};
 use futures::{Async, Future, Poll};
-use futures::unsync::oneshot;
+use futures::sync::oneshot;
 use mpi::point_to_point::Destination;
-use void::Void;
 use super::buffer::OwnedBuffer;
-use super::codec::{Encoder, SendFrom};
-use super::request_poll::RequestPoll;
-use super::switch::Link;
+use super::cancel::{Cancel, CancelToken, Cancellation};
+use super::codec::{Encoder, SendFrom, SyncEncoder};
+use super::error::{MpiError, switch_gone};
+use super::request_poll::{RequestPoll, SendMode};
+use super::switch::{Job, Link, Submit, SyncJob};
+use super::tag::Tag;
 
-enum State<'a, C: Encoder<'a>, D> {
+enum State<'a, C: Encoder<'a>, D, L> {
     Pending {
-        link: Link<'a>,
+        link: L,
         codec: C,
         dest: D,
         msg: C::Message,
+        mode: SendMode,
     },
     Started {
-        receiver: oneshot::Receiver<()>,
+        receiver: oneshot::Receiver<Result<(), MpiError>>,
+        // None once the send has completed
+        token: Option<CancelToken>,
     },
     Invalid,
 }
 
-impl<'a, C, D> fmt::Debug for State<'a, C, D>
+impl<'a, C, D, L> fmt::Debug for State<'a, C, D, L>
     where C: Encoder<'a> + fmt::Debug,
           C::Message: fmt::Debug,
           D: fmt::Debug,
+          L: fmt::Debug,
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            &State::Pending { ref link, ref codec, ref dest, ref msg } =>
+            &State::Pending { ref link, ref codec, ref dest, ref msg,
+                              ref mode } =>
                 f.debug_struct("State::Pending")
                 .field("link", link)
                 .field("codec", codec)
                 .field("dest", dest)
                 .field("msg", msg)
+                .field("mode", mode)
                 .finish(),
-            &State::Started { ref receiver } =>
+            &State::Started { ref receiver, ref token } =>
                 f.debug_struct("State::Started")
                 .field("receiver", receiver)
+                .field("token", token)
                 .finish(),
             &State::Invalid =>
                 f.write_str("State::Invalid"),
@@ -45,12 +54,21 @@ impl<'a, C, D> fmt::Debug for State<'a, C, D>
     }
 }
 
-pub struct Send<'a, C: Encoder<'a>, D>(State<'a, C, D>);
+/// Future returned by `LinkExt::send`.
+///
+/// The link type `L` determines which kind of switch the message is sent
+/// through.
+///
+/// Dropping it does not cancel the send, which still completes in the
+/// background; use `cancel` or a timeout for that.  (Cancelling sends is
+/// deprecated as of MPI-4.)
+pub struct Send<'a, C: Encoder<'a>, D, L = Link<'a>>(State<'a, C, D, L>);
 
-impl<'a, C, D> fmt::Debug for Send<'a, C, D>
+impl<'a, C, D, L> fmt::Debug for Send<'a, C, D, L>
     where C: Encoder<'a> + fmt::Debug,
           C::Message: fmt::Debug,
           D: fmt::Debug,
+          L: fmt::Debug,
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_tuple("Send")
@@ -59,62 +77,159 @@ impl<'a, C, D> fmt::Debug for Send<'a, C, D>
     }
 }
 
-impl<'a, C: Encoder<'a>, D: Destination> Send<'a, C, D> {
-    pub fn new(link: Link<'a>, codec: C, dest: D, msg: C::Message) -> Self {
+impl<'a, C: Encoder<'a>, D: Destination, L> Send<'a, C, D, L> {
+    pub fn new(link: L, codec: C, dest: D, msg: C::Message) -> Self {
+        Self::with_mode(link, codec, dest, msg, SendMode::Standard)
+    }
+
+    /// Create a `Send` that requests the given mode from the encoder.
+    pub fn with_mode(link: L, codec: C, dest: D, msg: C::Message,
+                     mode: SendMode) -> Self {
         Send(State::Pending {
             link: link,
             codec: codec,
             dest: dest,
             msg: msg,
+            mode: mode,
         })
     }
+
+    /// Cancel the send, returning a future that reports whether the
+    /// cancellation took effect.  If the send was never started, it counts
+    /// as cancelled.
+    pub fn cancel(mut self) -> Cancellation {
+        match mem::replace(&mut self.0, State::Invalid) {
+            State::Pending { .. } => Cancellation::done(true),
+            State::Started { token: Some(token), .. } =>
+                token.cancel_with_report(),
+            _ => Cancellation::done(false),
+        }
+    }
 }
 
+impl<'a, C: Encoder<'a>, D, L> Cancel for Send<'a, C, D, L> {
+    fn request_cancel(&mut self) {
+        // a pending send is never started once dropped, so there is
+        // nothing to cancel yet
+        if let State::Started { token: Some(ref token), .. } = self.0 {
+            token.cancel();
+        }
+    }
+}
+
+/// The `Job` submitted by `Send` to encode and send the message.
+pub struct SendJob<'a, C: Encoder<'a>, D> {
+    codec: C,
+    dest: D,
+    msg: C::Message,
+    mode: SendMode,
+    sender: oneshot::Sender<Result<(), MpiError>>,
+    token: CancelToken,
+}
+
+impl<'a, C: Encoder<'a>, D: Destination> Job<'a> for SendJob<'a, C, D> {
+    fn run(self, request_poll: &mut RequestPoll<'a>) {
+        let send_from = SendFromImpl {
+            request_poll: request_poll,
+            dest: self.dest,
+            mode: self.mode,
+            sender: self.sender,
+            token: self.token,
+        };
+        self.codec.encode(self.msg, send_from);
+    }
+}
+
+// the callbacks only hold on to the (thread-safe) sender and the buffers,
+// which are Send as guaranteed by SyncEncoder
+unsafe impl<'a, C, D> SyncJob<'a> for SendJob<'a, C, D>
+    where C: SyncEncoder<'a>,
+          D: Destination,
+{}
+
 struct SendFromImpl<'b, 'a: 'b, D> {
     request_poll: &'b mut RequestPoll<'a>,
     dest: D,
-    sender: oneshot::Sender<()>,
+    mode: SendMode,
+    sender: oneshot::Sender<Result<(), MpiError>>,
+    token: CancelToken,
 }
 
 impl<'b, 'a, D: Destination> SendFrom<'a> for SendFromImpl<'b, 'a, D> {
     // we don't really use the Output type for anything but we keep it in the
     // trait anyway to enforce some sanity in the implementation of Encoder
     type Output = ();
-    fn send_from<B: OwnedBuffer + 'a>(self, buf: B, tag: u16)
+    fn send_from<B: OwnedBuffer + 'a>(self, buf: B, tag: Tag)
                                       -> Self::Output {
+        let mode = self.mode;
+        self.send_from_with_mode(buf, tag, mode)
+    }
+
+    fn send_from_with_mode<B: OwnedBuffer + 'a>(self, buf: B, tag: Tag,
+                                                mode: SendMode)
+                                                -> Self::Output {
         let sender = self.sender;
-        self.request_poll.send(self.dest, buf, tag, move |_| {
-            let _ = sender.send(());
+        self.request_poll.send_with_mode(self.dest, buf, tag, mode,
+                                         Some(self.token),
+                                         move |_, result| {
+            let _ = sender.send(result);
         });
     }
 }
 
-impl<'a, C: Encoder<'a>, D: Destination> Future for Send<'a, C, D> {
+impl<'a, C, D, L> Future for Send<'a, C, D, L>
+    where C: Encoder<'a>,
+          D: Destination,
+          L: Submit<'a, SendJob<'a, C, D>>,
+{
     type Item = ();
-    type Error = Void;
+    type Error = MpiError;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        fn poll_receiver<F: Future<Item=()>>(receiver: &mut F)
-                                             -> Poll<(), Void> {
-            Ok(receiver.poll().unwrap_or(Async::Ready(())))
-        }
         match mem::replace(&mut self.0, State::Invalid) {
-            State::Pending { link, codec, dest, msg } =>
-                link.modify_request_poll(|request_poll| match request_poll {
-                    None => Ok(Async::Ready(())),
-                    Some(request_poll) => {
-                        let (sender, mut receiver) = oneshot::channel();
-                        let send_from = SendFromImpl {
-                            request_poll: request_poll,
-                            dest: dest,
-                            sender: sender,
+            State::Pending { link, codec, dest, msg, mode } => {
+                // if the switch is gone, the job (and hence the sender) is
+                // dropped, so the receiver will be canceled
+                let (sender, receiver) = oneshot::channel();
+                let token = CancelToken::new();
+                link.submit(SendJob {
+                    codec: codec,
+                    dest: dest,
+                    msg: msg,
+                    mode: mode,
+                    sender: sender,
+                    token: token.clone(),
+                });
+                self.0 = State::Started {
+                    receiver: receiver,
+                    token: Some(token),
+                };
+                self.poll()
+            }
+            State::Started { mut receiver, token } => {
+                // the job was dropped without running, either because the
+                // switch is gone or because it is shutting down
+                let poll = match receiver.poll() {
+                    Ok(poll) => poll,
+                    Err(oneshot::Canceled) =>
+                        Async::Ready(Err(switch_gone())),
+                };
+                match poll {
+                    Async::NotReady => {
+                        self.0 = State::Started {
+                            receiver: receiver,
+                            token: token,
+                        };
+                        Ok(Async::NotReady)
+                    }
+                    Async::Ready(result) => {
+                        self.0 = State::Started {
+                            receiver: receiver,
+                            token: None,
                         };
-                        codec.encode(msg, send_from);
-                        let poll = poll_receiver(&mut receiver);
-                        self.0 = State::Started { receiver: receiver };
-                        poll
+                        result.map(Async::Ready)
                     }
-                }),
-            State::Started { mut receiver } => poll_receiver(&mut receiver),
+                }
+            }
             // panic loudly so the loop doesn't just silently stall!
             State::Invalid => panic!("invalid state"),
         }
@@ -0,0 +1,231 @@
+//! Combined send and receive, e.g. for pairwise exchanges.
+//!
+//! A `SendRecv` drives a send and the receive of a single incoming message
+//! side by side, so neither half waits for the other to finish.  Both halves
+//! go through the switch just like `LinkExt::send` and `LinkExt::incoming`
+//! would.
+
+use std::{fmt, marker};
+use futures::{Async, Future, Poll, Stream};
+use mpi;
+use mpi::datatype::{Buffer, Datatype, Equivalence};
+use mpi::raw::AsRaw;
+use mpi::topology::Communicator;
+use super::buffer::Unanchor;
+use super::codec::{Decoder, Encoder, RecvInto, SendFrom, SyncDecoder,
+                   SyncEncoder};
+use super::error::{MpiError, OrError, switch_gone};
+use super::incoming::FutureBuffer;
+use super::large;
+use super::tag::Tag;
+
+/// Future returned by `LinkExt::sendrecv` and `LinkExt::sendrecv_replace`.
+///
+/// ```ignore
+/// SendRecv<Future<()>, Stream<Future<(Status, Message)>>>
+///     : Future<(Status, Message)>
+/// ```
+///
+/// It resolves once the send has completed and the message has been
+/// received.  If either half fails, the other half is dropped.  A receive
+/// whose message has already been matched still completes in the background
+/// (see `FutureBuffer`).
+#[must_use = "futures do nothing unless polled"]
+pub struct SendRecv<F, I: Stream>
+    where I::Item: Future
+{
+    // None once the send has completed
+    send: Option<F>,
+    // None once a message has been matched
+    incoming: Option<I>,
+    recv: Option<I::Item>,
+    received: Option<<I::Item as Future>::Item>,
+}
+
+impl<F, I> fmt::Debug for SendRecv<F, I>
+    where F: fmt::Debug,
+          I: Stream + fmt::Debug,
+          I::Item: Future,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SendRecv")
+            .field("send", &self.send)
+            .field("incoming", &self.incoming)
+            .field("recv", &self.recv.is_some())
+            .field("received", &self.received.is_some())
+            .finish()
+    }
+}
+
+impl<F, I: Stream> SendRecv<F, I> where I::Item: Future {
+    /// Combine a send with the first message of an incoming stream.
+    pub fn new(send: F, incoming: I) -> Self {
+        SendRecv {
+            send: Some(send),
+            incoming: Some(incoming),
+            recv: None,
+            received: None,
+        }
+    }
+}
+
+impl<F, I> SendRecv<F, I>
+    where I: Stream<Error=MpiError>,
+          I::Item: Future<Error=MpiError>,
+{
+    fn poll_recv(&mut self) -> Poll<(), MpiError> {
+        if self.received.is_some() {
+            return Ok(Async::Ready(()));
+        }
+        if self.recv.is_none() {
+            match self.incoming.as_mut().unwrap().poll()? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(None) => return Err(switch_gone()),
+                Async::Ready(Some(recv)) => {
+                    // the stream is no longer needed
+                    self.incoming = None;
+                    self.recv = Some(recv);
+                }
+            }
+        }
+        match self.recv.as_mut().unwrap().poll()? {
+            Async::NotReady => Ok(Async::NotReady),
+            Async::Ready(item) => {
+                self.recv = None;
+                self.received = Some(item);
+                Ok(Async::Ready(()))
+            }
+        }
+    }
+}
+
+impl<F, I> Future for SendRecv<F, I>
+    where F: Future<Item=(), Error=MpiError>,
+          I: Stream<Error=MpiError>,
+          I::Item: Future<Error=MpiError>,
+{
+    type Item = <I::Item as Future>::Item;
+    type Error = MpiError;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let sent = match self.send.as_mut().map(Future::poll) {
+            None => true,
+            Some(Ok(Async::Ready(()))) => true,
+            Some(Ok(Async::NotReady)) => false,
+            Some(Err(err)) => return Err(err),
+        };
+        if sent {
+            self.send = None;
+        }
+        let received = self.poll_recv()?.is_ready();
+        if sent && received {
+            Ok(Async::Ready(self.received.take().expect("already resolved")))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+// a byte of a buffer packed with MPI_Pack, which is sent as MPI_PACKED
+#[derive(Clone, Copy, Debug)]
+struct PackedByte(u8);
+
+#[derive(Clone, Copy, Debug)]
+struct PackedDatatype;
+
+unsafe impl AsRaw for PackedDatatype {
+    type Raw = mpi::ffi::MPI_Datatype;
+    fn as_raw(&self) -> Self::Raw {
+        unsafe { mpi::ffi::RSMPI_PACKED }
+    }
+}
+
+unsafe impl Datatype for PackedDatatype {}
+
+unsafe impl Equivalence for PackedByte {
+    type Out = PackedDatatype;
+    fn equivalent_datatype() -> Self::Out {
+        PackedDatatype
+    }
+}
+
+/// A copy of the contents of a buffer, packed with `MPI_Pack` and sent as
+/// `MPI_PACKED`, so that it matches a receive of the original datatype.
+#[derive(Clone, Debug)]
+pub struct Packed(Vec<PackedByte>);
+
+impl Packed {
+    /// Pack the contents of `buffer` for sending through `comm`.
+    pub fn new<B, C>(buffer: &B, comm: &C) -> Result<Self, MpiError>
+        where B: Buffer + ?Sized,
+              C: Communicator,
+    {
+        let comm = comm.as_raw();
+        unsafe {
+            // the datatype must survive until the buffer has been packed
+            let layout = large::layout(buffer)?;
+            let mut size = 0;
+            mpi::ffi::MPI_Pack_size(layout.count, layout.datatype, comm,
+                                    &mut size).or_error()?;
+            let mut bytes = vec![PackedByte(0); size as usize];
+            let mut position = 0;
+            mpi::ffi::MPI_Pack(buffer.pointer(),
+                               layout.count,
+                               layout.datatype,
+                               bytes.as_mut_ptr() as *mut _,
+                               size,
+                               &mut position,
+                               comm).or_error()?;
+            bytes.truncate(position as usize);
+            Ok(Packed(bytes))
+        }
+    }
+}
+
+/// `Encoder` used by `LinkExt::sendrecv_replace`, which sends a `Packed`
+/// copy of the buffer with a fixed tag.
+#[derive(Clone, Copy, Debug)]
+pub struct ReplaceEncoder {
+    tag: Tag,
+}
+
+impl ReplaceEncoder {
+    pub fn new(tag: Tag) -> Self {
+        ReplaceEncoder {
+            tag: tag,
+        }
+    }
+}
+
+impl<'a> Encoder<'a> for ReplaceEncoder {
+    type Message = Packed;
+
+    fn encode<S: SendFrom<'a>>(self, msg: Self::Message, s: S) -> S::Output {
+        s.send_from(msg.0, self.tag)
+    }
+}
+
+unsafe impl<'a> SyncEncoder<'a> for ReplaceEncoder {}
+
+/// `Decoder` used by `LinkExt::sendrecv_replace`, which receives the first
+/// message into the given buffer.
+#[derive(Debug)]
+pub struct ReplaceDecoder<B>(Option<B>);
+
+impl<B> ReplaceDecoder<B> {
+    pub fn new(buffer: B) -> Self {
+        ReplaceDecoder(Some(buffer))
+    }
+}
+
+impl<'a, B: Unanchor + 'a> Decoder<'a> for ReplaceDecoder<B> {
+    type FutureMessage = FutureBuffer<B>;
+
+    fn decode<R: RecvInto<'a>>(&mut self, r: R)
+                               -> (R::Output, Self::FutureMessage) {
+        r.recv_into(self.0.take().expect("buffer was already received into"))
+    }
+}
+
+unsafe impl<'a, B> SyncDecoder<'a> for ReplaceDecoder<B>
+    where B: Unanchor + marker::Send + 'a
+{}
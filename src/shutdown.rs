@@ -0,0 +1,74 @@
+//! Graceful shutdown of a switch.
+//!
+//! Once a shutdown begins, the switch stops accepting new work, ends every
+//! `Incoming` stream, and cancels pending receives.  Outstanding sends are
+//! left to finish.  The switch stops once its `RequestPoll` is empty, so
+//! nothing is left for its destructor to wait on.
+
+use futures::{Async, Future, Poll};
+use futures::sync::oneshot;
+use void::Void;
+use super::dispatch::Dispatcher;
+use super::request_poll::RequestPoll;
+
+/// Tracks the progress of a shutdown.
+#[derive(Debug, Default)]
+pub(crate) struct Drain {
+    draining: bool,
+    waiters: Vec<oneshot::Sender<()>>,
+}
+
+impl Drain {
+    /// Whether the switch has stopped accepting new work.
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    /// Begin shutting down, if not already doing so.
+    pub fn begin(&mut self, request_poll: &mut RequestPoll,
+                 dispatcher: &mut Dispatcher) -> Shutdown {
+        if !self.draining {
+            self.draining = true;
+            request_poll.cancel_receives();
+            dispatcher.close(request_poll);
+        }
+        let (sender, receiver) = oneshot::channel();
+        self.waiters.push(sender);
+        Shutdown(receiver)
+    }
+
+    /// Check whether the shutdown has finished, notifying the waiters if so.
+    pub fn is_done(&mut self, request_poll: &RequestPoll) -> bool {
+        if !self.draining || !request_poll.is_empty() {
+            return false;
+        }
+        for waiter in self.waiters.drain(..) {
+            let _ = waiter.send(());
+        }
+        true
+    }
+}
+
+/// Future returned by `Link::shutdown`, which resolves once the switch has
+/// finished shutting down (or is gone).
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Shutdown(oneshot::Receiver<()>);
+
+impl Shutdown {
+    /// A `Shutdown` that has already finished.
+    pub(crate) fn done() -> Self {
+        let (sender, receiver) = oneshot::channel();
+        let _ = sender.send(());
+        Shutdown(receiver)
+    }
+}
+
+impl Future for Shutdown {
+    type Item = ();
+    type Error = Void;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        // if the switch was dropped, it's certainly not running anymore
+        Ok(self.0.poll().unwrap_or(Async::Ready(())))
+    }
+}
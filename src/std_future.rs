@@ -0,0 +1,104 @@
+//! Adapters that expose the futures 0.1-based machinery of this crate through
+//! `std::future::Future` and the 0.3-style `Stream`, so that they can be
+//! `.await`ed or driven by a `Waker`-based executor.
+//!
+//! ```ignore
+//! let switch = Switch::default();
+//! let link = switch.link();
+//! spawn(switch.into_std());
+//! link.send(U8Codec, dest, msg).into_std().await?;
+//! let mut incoming = link.incoming(U8Codec, source).into_std();
+//! let (status, msg) = incoming.next().await.unwrap()?.await?;
+//! ```
+//!
+//! The underlying codec and `RequestPoll` machinery is shared with the
+//! futures 0.1 interface: the adapters merely translate between `Waker`s and
+//! futures 0.1 tasks.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::{Future as Future01, Stream as Stream01};
+use futures03::Stream;
+use futures03::compat::Compat01As03;
+
+/// A futures 0.1 `Future` wrapped as a `std::future::Future`.
+///
+/// ```ignore
+/// StdFuture<Future<T, E>>: std::future::Future<Output=Result<T, E>>
+/// ```
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct StdFuture<F>(Compat01As03<F>);
+
+impl<F> StdFuture<F> {
+    pub fn new(future: F) -> Self {
+        StdFuture(Compat01As03::new(future))
+    }
+
+    /// Recover the original futures 0.1 `Future`.
+    pub fn into_inner(self) -> F {
+        self.0.into_inner()
+    }
+}
+
+impl<F: Future01> Future for StdFuture<F> {
+    type Output = Result<F::Item, F::Error>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+/// A futures 0.1 `Stream` of futures wrapped as a 0.3-style `Stream` of
+/// `std::future::Future`s.
+///
+/// ```ignore
+/// StdStream<Stream<Future<T, E>, E2>>: Stream<Result<StdFuture<..>, E2>>
+/// ```
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct StdStream<S>(Compat01As03<S>);
+
+impl<S> StdStream<S> {
+    pub fn new(stream: S) -> Self {
+        StdStream(Compat01As03::new(stream))
+    }
+
+    /// Recover the original futures 0.1 `Stream`.
+    pub fn into_inner(self) -> S {
+        self.0.into_inner()
+    }
+}
+
+impl<S> Stream for StdStream<S>
+    where S: Stream01,
+          S::Item: Future01,
+{
+    type Item = Result<StdFuture<S::Item>, S::Error>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context)
+                 -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.0).poll_next(cx).map(|item| {
+            item.map(|result| result.map(StdFuture::new))
+        })
+    }
+}
+
+/// Convert a futures 0.1 `Future` (such as `Switch` or `Send`) into a
+/// `std::future::Future`.
+pub trait IntoStdFuture: Future01 + Sized {
+    fn into_std(self) -> StdFuture<Self> {
+        StdFuture::new(self)
+    }
+}
+
+impl<F: Future01> IntoStdFuture for F {}
+
+/// Convert a futures 0.1 `Stream` of futures (such as `Incoming`) into a
+/// 0.3-style `Stream` of `std::future::Future`s.
+pub trait IntoStdStream: Stream01 + Sized {
+    fn into_std(self) -> StdStream<Self> {
+        StdStream::new(self)
+    }
+}
+
+impl<S: Stream01> IntoStdStream for S {}
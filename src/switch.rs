@@ -3,21 +3,43 @@ use std::marker::PhantomData;
 use std::rc::{Rc, Weak};
 use futures::{Async, Future, Poll};
 use futures::task;
-use mpi::point_to_point::{Destination, Source};
+use super::attach::AttachBuffer;
+use super::codec::Decoder;
+use super::dispatch::{Dispatcher, Filter, Subscribe, Subscription};
+use super::idle::{IdlePolicy, IdleStats, Idler};
+use super::error::{self, MpiError};
+use super::incoming::{self, PollIncoming, WithStatus};
+use super::link::LinkExt;
+use super::metrics::Metrics;
 use super::request_poll::RequestPoll;
-use super::codec::{Decoder, Encoder};
-use super::incoming::Incoming;
-use super::send::Send;
+use super::shutdown::{Drain, Shutdown};
+use super::timeout::{Timer, TimerQueue, Timers};
 
 #[derive(Debug, Default)]
 struct Inner<'a> {
     request_poll: RequestPoll<'a>,
     stop: bool,
+    idler: Idler,
+    dispatcher: Dispatcher,
+    timers: TimerQueue,
+    drain: Drain,
+    // must come after the request poll so it outlives buffered sends
+    attach_buffer: Option<AttachBuffer>,
+}
+
+impl<'a> Drop for Inner<'a> {
+    fn drop(&mut self) {
+        // let the waiting streams find out that the switch is gone
+        self.dispatcher.close(&mut self.request_poll);
+        self.timers.close();
+    }
 }
 
 /// Scheduler for MPI communications.
 ///
-/// It can be constructed via `Switch::default()`.
+/// It can be constructed via `Switch::default()`, or via
+/// `Switch::with_idle_policy` to control what happens when there is nothing
+/// to do.
 ///
 /// A `Switch` is responsible for managing send and receive requests and
 /// notifying tasks whenever they are ready.
@@ -31,11 +53,37 @@ pub struct Switch<'a, E>(Rc<RefCell<Inner<'a>>>, PhantomData<E>);
 
 impl<'a, E> Default for Switch<'a, E> {
     fn default() -> Self {
+        error::set_errors_return_world();
         Switch(Default::default(), Default::default())
     }
 }
 
 impl<'a, E> Switch<'a, E> {
+    /// Create a `Switch` with the given idle policy.  (The default is
+    /// `IdlePolicy::Spin`.)
+    pub fn with_idle_policy(idle_policy: IdlePolicy) -> Self {
+        let switch = Self::default();
+        switch.0.borrow_mut().idler = Idler::new(idle_policy);
+        switch
+    }
+
+    /// Allocate a buffer of `size` bytes for buffered-mode sends
+    /// (`SendMode::Buffered`) and attach it via `MPI_Buffer_attach`,
+    /// replacing any buffer previously attached by this switch.  The buffer
+    /// is detached once the switch is dropped.
+    ///
+    /// Each buffered message occupies its packed size plus
+    /// `MPI_BSEND_OVERHEAD` bytes until it has been transmitted.  Since MPI
+    /// allows only one attached buffer per process, this fails if another
+    /// switch already has one.
+    pub fn attach_buffer(&self, size: usize) -> Result<(), MpiError> {
+        let mut inner = self.0.borrow_mut();
+        // detach the old one first, waiting for its messages to go out
+        inner.attach_buffer = None;
+        inner.attach_buffer = Some(AttachBuffer::attach(size)?);
+        Ok(())
+    }
+
     /// Acquire a `Link` to this `Switch`.  A `Link` acts as a clonable
     /// delegate for the switch and allows performing MPI requests.
     pub fn link(&self) -> Link<'a> {
@@ -48,20 +96,50 @@ impl<'a, E> Future for Switch<'a, E> {
     type Error = E;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         let mut inner = self.0.borrow_mut();
+        let inner = &mut *inner;
         if inner.stop {
             Ok(Async::Ready(()))
         } else {
-            inner.request_poll.test();
+            let probing = inner.dispatcher.dispatch(&mut inner.request_poll);
+            let timing = inner.timers.fire();
+            let completed = inner.request_poll.test();
+            if inner.drain.is_done(&inner.request_poll) {
+                inner.stop = true;
+                return Ok(Async::Ready(()));
+            }
+            inner.idler.after_test(completed, probing || timing,
+                                   &mut inner.request_poll);
             task::park().unpark();
             Ok(Async::NotReady)
         }
     }
 }
 
+/// A unit of work to be performed on the `RequestPoll` of a switch.
+pub trait Job<'a> {
+    fn run(self, request_poll: &mut RequestPoll<'a>);
+}
+
+/// A `Job` that can be run against a `RequestPoll` shared between threads.
+///
+/// # Unsafe invariant
+///
+/// Every callback and buffer that the job inserts into the `RequestPoll`
+/// must be `Send`, because the request may complete on a different thread.
+pub unsafe trait SyncJob<'a>: Job<'a> {}
+
+/// Ability to submit jobs of type `J` to a switch.
+pub trait Submit<'a, J> {
+    /// Run the job on the `RequestPoll` of the switch.  If the switch is no
+    /// longer alive or is shutting down, the job is dropped without running.
+    fn submit(&self, job: J);
+}
+
 /// Used to perform MPI requests through a `Switch`.
 ///
 /// Unlike `Switch`, which can't be cloned, `Link` can be cloned as many times
 /// as you like and is always linked to same switch it originally came from.
+/// The requests themselves are made through `LinkExt`.
 #[derive(Debug, Clone)]
 pub struct Link<'a>(Weak<RefCell<Inner<'a>>>);
 
@@ -78,36 +156,50 @@ impl<'a> Link<'a> {
         });
     }
 
-    /// Obtain a `Stream` of future incoming messages from the given `source`.
-    /// Each message is decoded using the given codec.
+    /// Shut down the associated `Switch` gracefully, returning a future that
+    /// resolves once it has stopped.
     ///
     /// ```ignore
-    /// fn incoming(&self, Source) -> Stream<Future<Message>>;
+    /// fn shutdown(&self) -> Future<()>;
     /// ```
     ///
-    /// The stream will keep running until the `Switch` is `close`d, but you
-    /// can stop the `Stream` at any time if you aren't expecting to receive
-    /// messages.  You can even create a new `incoming` stream every time you
-    /// want to receive a message.
-    ///
-    /// Just try to avoid running multiple overlapping `incoming` streams
-    /// simultaneously, as that could cause messages to be split between the
-    /// streams in a non-deterministic manner.
-    pub fn incoming<D: Decoder<'a>, S: Source>(&self, decoder: D, source: S)
-                                               -> Incoming<'a, D, S> {
-        Incoming::new(self.clone(), decoder, source)
+    /// Unlike `close`, this lets outstanding sends finish and cancels pending
+    /// receives, and the `Switch` only stops once all of its requests have
+    /// completed, so dropping it never blocks.  In the meantime, new sends
+    /// fail with the same error as if the switch were gone and all
+    /// `Incoming` streams end.
+    pub fn shutdown(&self) -> Shutdown {
+        match self.0.upgrade() {
+            None => Shutdown::done(),
+            Some(inner) => {
+                let mut inner = inner.borrow_mut();
+                let inner = &mut *inner;
+                if inner.stop {
+                    return Shutdown::done();
+                }
+                inner.drain.begin(&mut inner.request_poll,
+                                  &mut inner.dispatcher)
+            }
+        }
     }
 
-    /// Send a message asynchronously, returning a `Future` that completes
-    /// when the send does.
-    ///
-    /// ```ignore
-    /// fn send(&self, Destination, Message) -> Future<()>;
-    /// ```
-    pub fn send<E: Encoder<'a>, D: Destination>(&self, encoder: E, dest: D,
-                                                msg: E::Message)
-                                                -> Send<'a, E, D> {
-        Send::new(self.clone(), encoder, dest, msg)
+    /// Change the idle policy of the associated `Switch`.
+    pub fn set_idle_policy(&self, idle_policy: IdlePolicy) {
+        self.0.upgrade().map(|inner| {
+            inner.borrow_mut().idler.set_policy(idle_policy);
+        });
+    }
+
+    /// Obtain the idle counters of the associated `Switch`, if it is still
+    /// alive.
+    pub fn idle_stats(&self) -> Option<IdleStats> {
+        self.0.upgrade().map(|inner| inner.borrow().idler.stats())
+    }
+
+    /// Obtain a snapshot of the communication metrics of the associated
+    /// `Switch`, if it is still alive.
+    pub fn metrics(&self) -> Option<Metrics> {
+        self.0.upgrade().map(|inner| inner.borrow().request_poll.metrics())
     }
 
     /// Modify the internal `RequestPoll`, if the `Switch` is still alive.
@@ -122,3 +214,46 @@ impl<'a> Link<'a> {
         }
     }
 }
+
+impl<'a> LinkExt<'a> for Link<'a> {}
+
+impl<'a> Subscribe for Link<'a> {
+    fn subscribe(&self, filter: Filter, peek: bool) -> Option<Subscription> {
+        self.0.upgrade().and_then(|inner| {
+            let mut inner = inner.borrow_mut();
+            if inner.drain.is_draining() {
+                None
+            } else {
+                Some(inner.dispatcher.subscribe(filter, peek))
+            }
+        })
+    }
+}
+
+impl<'a> Timers for Link<'a> {
+    fn timer(&self, deadline: f64) -> Option<Timer> {
+        self.0.upgrade().map(|inner| inner.borrow_mut().timers.add(deadline))
+    }
+}
+
+impl<'a, C: Decoder<'a>> PollIncoming<'a, C> for Link<'a> {
+    fn poll_incoming(&self, codec: &mut C, subscription: &Subscription)
+                     -> Poll<Option<WithStatus<C::FutureMessage>>, MpiError> {
+        self.modify_request_poll(|request_poll| {
+            incoming::poll_matched(codec, subscription, request_poll)
+        })
+    }
+}
+
+impl<'a, J: Job<'a>> Submit<'a, J> for Link<'a> {
+    fn submit(&self, job: J) {
+        self.0.upgrade().map(|inner| {
+            let mut inner = inner.borrow_mut();
+            if inner.drain.is_draining() {
+                return;
+            }
+            inner.idler.poke();
+            job.run(&mut inner.request_poll);
+        });
+    }
+}
@@ -0,0 +1,337 @@
+//! Thread-safe variant of `Switch` and `Link`, for use with multithreaded
+//! (e.g. work-stealing) executors.
+//!
+//! This requires MPI to be initialized with `Threading::Multiple`.  Only
+//! codecs that implement `SyncEncoder` and `SyncDecoder` can be used through
+//! this switch.
+//!
+//! When it blocks in `MPI_Waitsome` (`IdlePolicy::Block`), the switch is
+//! woken up by an alarm that its links ring whenever they hand it something
+//! new to do, such as requests, timers, cancellations, or a shutdown.
+
+use std::marker::{self, PhantomData};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
+use futures::{Async, Future, Poll};
+use futures::task;
+use mpi::Threading;
+use mpi::environment;
+use super::alarm::Alarm;
+use super::attach::AttachBuffer;
+use super::codec::SyncDecoder;
+use super::dispatch::{Dispatcher, Filter, Subscribe, Subscription};
+use super::idle::{Idle, IdlePolicy, IdleStats, Idler};
+use super::error::{self, MpiError};
+use super::incoming::{self, PollIncoming, WithStatus};
+use super::link::LinkExt;
+use super::metrics::Metrics;
+use super::request_poll::RequestPoll;
+use super::shutdown::{Drain, Shutdown};
+use super::timeout::{Timer, TimerQueue, Timers};
+use super::switch::{Submit, SyncJob};
+
+#[derive(Debug, Default)]
+struct Inner<'a> {
+    request_poll: RequestPoll<'a>,
+    stop: bool,
+    idler: Idler,
+    dispatcher: Dispatcher,
+    timers: TimerQueue,
+    drain: Drain,
+    // must come after the request poll so it outlives buffered sends
+    attach_buffer: Option<AttachBuffer>,
+}
+
+impl<'a> Drop for Inner<'a> {
+    fn drop(&mut self) {
+        // let the waiting streams find out that the switch is gone
+        self.dispatcher.close(&mut self.request_poll);
+        self.timers.close();
+    }
+}
+
+// Safe because the RequestPoll is only ever given callbacks and buffers that
+// are Send (as guaranteed by SyncJob, SyncEncoder, and SyncDecoder) and MPI
+// is known to support MPI_THREAD_MULTIPLE.
+unsafe impl<'a> marker::Send for Inner<'a> {}
+
+/// Thread-safe scheduler for MPI communications.
+///
+/// It can be constructed via `Switch::new()`, which fails if MPI was not
+/// initialized with `Threading::Multiple`.
+///
+/// Apart from being `Send` and `Sync`, it behaves just like
+/// `switch::Switch`.
+#[derive(Debug)]
+pub struct Switch<'a, E> {
+    inner: Arc<Mutex<Inner<'a>>>,
+    alarm: Arc<Alarm>,
+    phantom: PhantomData<E>,
+}
+
+impl<'a, E> Switch<'a, E> {
+    /// Create a new `Switch`.  If the threading level provided by MPI is
+    /// insufficient, the actual level is returned as an error.
+    pub fn new() -> Result<Self, Threading> {
+        match environment::threading_support() {
+            Threading::Multiple => {}
+            threading => return Err(threading),
+        }
+        error::set_errors_return_world();
+        let alarm = Arc::new(Alarm::default());
+        let mut inner = Inner::default();
+        inner.request_poll.set_alarm(alarm.clone());
+        Ok(Switch {
+            inner: Arc::new(Mutex::new(inner)),
+            alarm: alarm,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Create a new `Switch` with the given idle policy.  See `new`.
+    pub fn with_idle_policy(idle_policy: IdlePolicy)
+                            -> Result<Self, Threading> {
+        Self::new().map(|switch| {
+            switch.inner.lock().unwrap().idler = Idler::new(idle_policy);
+            switch
+        })
+    }
+
+    /// Allocate a buffer of `size` bytes for buffered-mode sends
+    /// (`SendMode::Buffered`) and attach it via `MPI_Buffer_attach`,
+    /// replacing any buffer previously attached by this switch.  The buffer
+    /// is detached once the switch is dropped.
+    ///
+    /// Each buffered message occupies its packed size plus
+    /// `MPI_BSEND_OVERHEAD` bytes until it has been transmitted.  Since MPI
+    /// allows only one attached buffer per process, this fails if another
+    /// switch already has one.
+    pub fn attach_buffer(&self, size: usize) -> Result<(), MpiError> {
+        let mut inner = self.inner.lock().unwrap();
+        // detach the old one first, waiting for its messages to go out
+        inner.attach_buffer = None;
+        inner.attach_buffer = Some(AttachBuffer::attach(size)?);
+        Ok(())
+    }
+
+    /// Acquire a `Link` to this `Switch`.  A `Link` acts as a clonable
+    /// delegate for the switch and allows performing MPI requests from any
+    /// thread.
+    pub fn link(&self) -> Link<'a> {
+        Link {
+            inner: Arc::downgrade(&self.inner),
+            alarm: self.alarm.clone(),
+        }
+    }
+}
+
+impl<'a, E> Future for Switch<'a, E> {
+    type Item = ();
+    type Error = E;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let (idle, detached) = {
+            let mut inner = self.inner.lock().unwrap();
+            let inner = &mut *inner;
+            if inner.stop {
+                return Ok(Async::Ready(()));
+            }
+            let probing = inner.dispatcher.dispatch(&mut inner.request_poll);
+            let timing = inner.timers.fire();
+            let completed = inner.request_poll.test();
+            if inner.drain.is_done(&inner.request_poll) {
+                inner.stop = true;
+                return Ok(Async::Ready(()));
+            }
+            // blocking on nothing but the alarm could wait on a task that
+            // runs on this very thread
+            let pending = inner.request_poll.has_outstanding();
+            // a stream may start waiting at any time without ringing the
+            // alarm, so blocking is out as long as there are any
+            let busy = probing || timing || !inner.dispatcher.is_empty();
+            let idle = inner.idler.plan(completed, busy, pending);
+            let detached = match idle {
+                Idle::Block => {
+                    // lets the links wake us up when they hand us something
+                    // new; once draining, it would keep the RequestPoll from
+                    // ever becoming empty
+                    if !inner.drain.is_draining() {
+                        self.alarm.arm(&mut inner.request_poll);
+                    }
+                    Some(inner.request_poll.detach())
+                }
+                _ => None,
+            };
+            (idle, detached)
+        };
+        // idle without holding the lock so that other threads can go on
+        // submitting requests in the meantime, which rings the alarm
+        if idle != Idle::Spin {
+            let start = Instant::now();
+            idle.pause();
+            let detached = detached.map(|mut detached| {
+                detached.wait();
+                detached
+            });
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(detached) = detached {
+                inner.request_poll.reattach(detached);
+            }
+            inner.idler.record(start.elapsed());
+        }
+        task::park().unpark();
+        Ok(Async::NotReady)
+    }
+}
+
+impl<'a, E> Drop for Switch<'a, E> {
+    fn drop(&mut self) {
+        // must not leave the alarm pending or the RequestPoll will wait on
+        // it forever when dropped
+        self.alarm.ring();
+    }
+}
+
+/// Used to perform MPI requests through a thread-safe `Switch`.
+///
+/// `Link` can be cloned as many times as you like and sent to other threads.
+/// The requests themselves are made through `LinkExt`.
+#[derive(Debug, Clone)]
+pub struct Link<'a> {
+    inner: Weak<Mutex<Inner<'a>>>,
+    alarm: Arc<Alarm>,
+}
+
+impl<'a> Link<'a> {
+    /// Gracefully shut down the associated `Switch`.  See
+    /// `switch::Link::close`.
+    pub fn close(&self) {
+        self.inner.upgrade().map(|inner| {
+            inner.lock().unwrap().stop = true;
+        });
+        // the switch may be blocked without knowing that it has to stop
+        self.alarm.ring();
+    }
+
+    /// Shut down the associated `Switch` gracefully.  See
+    /// `switch::Link::shutdown`.
+    pub fn shutdown(&self) -> Shutdown {
+        let shutdown = match self.inner.upgrade() {
+            None => Shutdown::done(),
+            Some(inner) => {
+                let mut inner = inner.lock().unwrap();
+                let inner = &mut *inner;
+                if inner.stop {
+                    Shutdown::done()
+                } else {
+                    inner.drain.begin(&mut inner.request_poll,
+                                      &mut inner.dispatcher)
+                }
+            }
+        };
+        // the cancellation of the pending receives is deferred until the
+        // switch stops blocking
+        self.alarm.ring();
+        shutdown
+    }
+
+    /// Change the idle policy of the associated `Switch`.
+    pub fn set_idle_policy(&self, idle_policy: IdlePolicy) {
+        self.inner.upgrade().map(|inner| {
+            inner.lock().unwrap().idler.set_policy(idle_policy);
+        });
+        self.alarm.ring();
+    }
+
+    /// Obtain the idle counters of the associated `Switch`, if it is still
+    /// alive.
+    pub fn idle_stats(&self) -> Option<IdleStats> {
+        self.inner.upgrade().map(|inner| inner.lock().unwrap().idler.stats())
+    }
+
+    /// Obtain a snapshot of the communication metrics of the associated
+    /// `Switch`, if it is still alive.
+    pub fn metrics(&self) -> Option<Metrics> {
+        self.inner.upgrade().map(|inner| {
+            inner.lock().unwrap().request_poll.metrics()
+        })
+    }
+
+    /// Modify the internal `RequestPoll`, if the `Switch` is still alive.
+    /// This is mostly for internal use.  Nesting calls to this function will
+    /// cause a deadlock.
+    ///
+    /// # Unsafety
+    ///
+    /// Every callback and buffer inserted into the `RequestPoll` must be
+    /// `Send`.  The `RequestPoll` must not be tested or waited on, since the
+    /// switch may be waiting on its requests without holding the lock.
+    pub unsafe fn modify_request_poll<F, R>(&self, f: F) -> R
+        where F: FnOnce(Option<&mut RequestPoll<'a>>) -> R
+    {
+        let result = match self.inner.upgrade() {
+            None => f(None),
+            Some(inner) =>
+                f(Some(&mut inner.lock().unwrap().request_poll)),
+        };
+        // the switch may be blocked without knowing about the new requests
+        self.alarm.ring();
+        result
+    }
+}
+
+impl<'a> LinkExt<'a> for Link<'a> {}
+
+impl<'a> Subscribe for Link<'a> {
+    fn subscribe(&self, filter: Filter, peek: bool) -> Option<Subscription> {
+        let subscription = self.inner.upgrade().and_then(|inner| {
+            let mut inner = inner.lock().unwrap();
+            if inner.drain.is_draining() {
+                None
+            } else {
+                Some(inner.dispatcher.subscribe(filter, peek))
+            }
+        });
+        // the switch may be blocked without knowing that it has to probe
+        self.alarm.ring();
+        subscription
+    }
+}
+
+impl<'a> Timers for Link<'a> {
+    fn timer(&self, deadline: f64) -> Option<Timer> {
+        let timer = self.inner.upgrade().map(|inner| {
+            inner.lock().unwrap().timers.add(deadline)
+        });
+        // the switch may be blocked without knowing about the new timer
+        self.alarm.ring();
+        timer
+    }
+}
+
+impl<'a, C: SyncDecoder<'a>> PollIncoming<'a, C> for Link<'a> {
+    fn poll_incoming(&self, codec: &mut C, subscription: &Subscription)
+                     -> Poll<Option<WithStatus<C::FutureMessage>>, MpiError> {
+        // safe because SyncDecoder ensures the buffers are all Send
+        unsafe {
+            self.modify_request_poll(|request_poll| {
+                incoming::poll_matched(codec, subscription, request_poll)
+            })
+        }
+    }
+}
+
+impl<'a, J: SyncJob<'a>> Submit<'a, J> for Link<'a> {
+    fn submit(&self, job: J) {
+        // sound because SyncJob only inserts callbacks that are Send
+        self.inner.upgrade().map(|inner| {
+            let mut inner = inner.lock().unwrap();
+            if inner.drain.is_draining() {
+                return;
+            }
+            inner.idler.poke();
+            job.run(&mut inner.request_poll);
+        });
+        // the switch may be blocked without knowing about the new requests
+        self.alarm.ring();
+    }
+}
@@ -0,0 +1,99 @@
+//! Message tags.
+//!
+//! The largest valid tag is given by the `MPI_TAG_UB` attribute, which the
+//! standard only guarantees to be at least 32767 but is usually much larger.
+//! It is queried once at runtime and every `Tag` is checked against it, so
+//! the full range supported by the implementation can be used.
+
+use std::{fmt, ptr};
+use std::sync::atomic::{AtomicIsize, Ordering};
+use libc;
+use mpi;
+use super::error::{MpiError, OrError};
+
+// zero means that MPI_TAG_UB hasn't been queried yet
+static TAG_UB: AtomicIsize = AtomicIsize::new(0);
+
+fn in_range(tag: mpi::Tag, upper_bound: mpi::Tag) -> bool {
+    0 <= tag && tag <= upper_bound
+}
+
+/// A message tag within `0 ..= Tag::upper_bound()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Tag(mpi::Tag);
+
+impl Tag {
+    /// The smallest `MPI_TAG_UB` permitted by the standard.  Tags up to this
+    /// value are valid on every MPI implementation.
+    pub const MIN_UPPER_BOUND: mpi::Tag = 32767;
+
+    /// Create a tag, failing with `MPI_ERR_TAG` if it is negative or exceeds
+    /// `MPI_TAG_UB`.
+    pub fn new(tag: mpi::Tag) -> Result<Self, MpiError> {
+        if !in_range(tag, Self::upper_bound()) {
+            let code = mpi::ffi::MPI_ERR_TAG as libc::c_int;
+            let context = format!("tag {} is outside the range 0 to {}",
+                                  tag, Self::upper_bound());
+            return Err(MpiError::from_code(code).context(&context));
+        }
+        Ok(Tag(tag))
+    }
+
+    /// The value of the `MPI_TAG_UB` attribute, i.e. the largest valid tag.
+    ///
+    /// The attribute belongs to `MPI_COMM_WORLD` and thus applies to every
+    /// communicator.  It is only queried on the first call.
+    pub fn upper_bound() -> mpi::Tag {
+        let cached = TAG_UB.load(Ordering::Relaxed);
+        if cached != 0 {
+            return cached as mpi::Tag;
+        }
+        let mut value: *mut libc::c_int = ptr::null_mut();
+        let mut flag: libc::c_int = 0;
+        let ub = unsafe {
+            match mpi::ffi::MPI_Comm_get_attr(
+                mpi::ffi::RSMPI_COMM_WORLD,
+                mpi::ffi::RSMPI_TAG_UB,
+                &mut value as *mut *mut libc::c_int as *mut libc::c_void,
+                &mut flag).or_error() {
+                Ok(()) if flag != 0 && !value.is_null() => *value,
+                // fall back to what the standard guarantees
+                _ => Self::MIN_UPPER_BOUND,
+            }
+        };
+        TAG_UB.store(ub as isize, Ordering::Relaxed);
+        ub
+    }
+
+    /// The raw value of the tag.
+    pub fn value(self) -> mpi::Tag {
+        self.0
+    }
+}
+
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl From<Tag> for mpi::Tag {
+    fn from(tag: Tag) -> Self {
+        tag.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{in_range, Tag};
+
+    #[test]
+    fn range() {
+        let ub = Tag::MIN_UPPER_BOUND;
+        assert!(in_range(0, ub));
+        assert!(in_range(ub, ub));
+        assert!(!in_range(-1, ub));
+        assert!(!in_range(ub + 1, ub));
+        assert!(!in_range(0, -1));
+    }
+}
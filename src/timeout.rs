@@ -0,0 +1,316 @@
+//! Deadlines for futures and streams.
+//!
+//! Deadlines are measured using `MPI_Wtime`.  The switch keeps track of
+//! every pending deadline and notifies the waiting task once it passes, at
+//! which point the request of the timed-out future is cancelled (see
+//! `cancel::Cancel`) and the future is dropped.
+
+use std::{error, fmt};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use futures::{Async, Future, Poll, Stream};
+use futures::task::{self, Task};
+use mpi;
+use super::cancel::Cancel;
+
+/// The current time according to `MPI_Wtime`, in seconds.
+pub fn now() -> f64 {
+    unsafe { mpi::ffi::MPI_Wtime() }
+}
+
+/// The `MPI_Wtime` at which the given duration from now elapses.
+pub fn deadline_after(timeout: Duration) -> f64 {
+    now() + timeout.as_secs() as f64 + timeout.subsec_nanos() as f64 * 1e-9
+}
+
+/// Error returned by `Timeout` and `TimeoutStream`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TimeoutError<E> {
+    /// The deadline passed before the operation completed.
+    Elapsed,
+    /// The operation itself failed.
+    Inner(E),
+}
+
+impl<E: fmt::Display> fmt::Display for TimeoutError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &TimeoutError::Elapsed => f.write_str("deadline has elapsed"),
+            &TimeoutError::Inner(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl<E: error::Error> error::Error for TimeoutError<E> {
+    fn description(&self) -> &str {
+        match self {
+            &TimeoutError::Elapsed => "deadline has elapsed",
+            &TimeoutError::Inner(ref err) => err.description(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    deadline: f64,
+    task: Option<Task>,
+    // set once the switch has found the deadline to have passed
+    expired: bool,
+    closed: bool,
+}
+
+/// A deadline registered with a switch.
+#[derive(Debug)]
+pub struct Timer(Arc<Mutex<Entry>>);
+
+impl Timer {
+    /// The `MPI_Wtime` at which the timer expires.
+    pub fn deadline(&self) -> f64 {
+        self.0.lock().unwrap().deadline
+    }
+
+    /// Whether the deadline has passed.  If not, arrange for the current
+    /// task to be notified once it does.
+    pub fn poll_expired(&self) -> bool {
+        let mut entry = self.0.lock().unwrap();
+        if entry.expired || now() >= entry.deadline {
+            true
+        } else {
+            entry.task = Some(task::park());
+            false
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        // the switch will clean up on its next round
+        self.0.lock().unwrap().closed = true;
+    }
+}
+
+/// Keeps track of the timers of a switch.
+#[derive(Debug, Default)]
+pub(crate) struct TimerQueue {
+    entries: Vec<Arc<Mutex<Entry>>>,
+}
+
+impl TimerQueue {
+    pub fn add(&mut self, deadline: f64) -> Timer {
+        let entry = Arc::new(Mutex::new(Entry {
+            deadline: deadline,
+            task: None,
+            expired: false,
+            closed: false,
+        }));
+        self.entries.push(entry.clone());
+        Timer(entry)
+    }
+
+    /// The earliest deadline of the pending timers, if any.
+    pub fn next_deadline(&self) -> Option<f64> {
+        self.entries.iter()
+            .map(|entry| entry.lock().unwrap().deadline)
+            .fold(None, |next, deadline| match next {
+                Some(next) if next <= deadline => Some(next),
+                _ => Some(deadline),
+            })
+    }
+
+    /// Notify the tasks of every expired timer.  Returns whether any timers
+    /// are still pending.
+    pub fn fire(&mut self) -> bool {
+        if self.entries.is_empty() {
+            return false;
+        }
+        self.fire_at(now())
+    }
+
+    // like fire, but as if MPI_Wtime were now
+    pub fn fire_at(&mut self, now: f64) -> bool {
+        self.entries.retain(|entry| {
+            let mut entry = entry.lock().unwrap();
+            if entry.closed {
+                false
+            } else if now >= entry.deadline {
+                entry.expired = true;
+                entry.task.take().map(|task| task.unpark());
+                false
+            } else {
+                true
+            }
+        });
+        !self.entries.is_empty()
+    }
+
+    /// Notify every waiting task, e.g. because the switch is going away.
+    pub fn close(&mut self) {
+        for entry in self.entries.drain(..) {
+            entry.lock().unwrap().task.take().map(|task| task.unpark());
+        }
+    }
+}
+
+/// Ability to register timers with a switch.
+pub trait Timers {
+    /// Register a timer that expires at the given `MPI_Wtime`, or return
+    /// `None` if the switch is no longer alive.
+    fn timer(&self, deadline: f64) -> Option<Timer>;
+}
+
+/// Future that fails with `TimeoutError::Elapsed` if the inner future does
+/// not complete before the deadline.  Upon expiry, the cancellation of the
+/// inner future's request is requested and the inner future is dropped.
+///
+/// If the switch is gone, the deadline is only checked when the inner future
+/// gets polled.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Timeout<F> {
+    future: Option<F>,
+    deadline: f64,
+    timer: Option<Timer>,
+}
+
+impl<F> Timeout<F> {
+    pub fn new<T: Timers>(timers: &T, future: F, deadline: f64) -> Self {
+        Timeout {
+            future: Some(future),
+            deadline: deadline,
+            timer: timers.timer(deadline),
+        }
+    }
+}
+
+impl<F: Future + Cancel> Future for Timeout<F> {
+    type Item = F::Item;
+    type Error = TimeoutError<F::Error>;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.future.as_mut().expect("polled after timeout").poll() {
+            Ok(Async::NotReady) => {}
+            Ok(Async::Ready(item)) => return Ok(Async::Ready(item)),
+            Err(err) => return Err(TimeoutError::Inner(err)),
+        }
+        let expired = match self.timer {
+            None => now() >= self.deadline,
+            Some(ref timer) => timer.poll_expired(),
+        };
+        if expired {
+            // the request stays in the poll until the cancellation completes
+            if let Some(mut future) = self.future.take() {
+                future.request_cancel();
+            }
+            Err(TimeoutError::Elapsed)
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Stream that fails with `TimeoutError::Elapsed` whenever the inner stream
+/// does not produce an item within the given duration of being polled for
+/// it.  The stream can continue to be polled after an error, in which case
+/// the duration starts over.
+///
+/// If the switch is gone, the stream ends as soon as the inner stream has
+/// nothing ready.
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct TimeoutStream<S, T> {
+    stream: S,
+    timers: T,
+    timeout: Duration,
+    timer: Option<Timer>,
+}
+
+impl<S, T> TimeoutStream<S, T> {
+    pub fn new(timers: T, stream: S, timeout: Duration) -> Self {
+        TimeoutStream {
+            stream: stream,
+            timers: timers,
+            timeout: timeout,
+            timer: None,
+        }
+    }
+
+    /// Retrieve the inner stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: Stream, T: Timers> Stream for TimeoutStream<S, T> {
+    type Item = S::Item;
+    type Error = TimeoutError<S::Error>;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.stream.poll() {
+            Ok(Async::NotReady) => {}
+            Ok(Async::Ready(item)) => {
+                self.timer = None;
+                return Ok(Async::Ready(item));
+            }
+            Err(err) => {
+                self.timer = None;
+                return Err(TimeoutError::Inner(err));
+            }
+        }
+        if self.timer.is_none() {
+            match self.timers.timer(deadline_after(self.timeout)) {
+                // the switch is gone, so nothing would ever wake us up
+                None => return Ok(Async::Ready(None)),
+                timer => self.timer = timer,
+            }
+        }
+        if self.timer.as_ref().unwrap().poll_expired() {
+            self.timer = None;
+            Err(TimeoutError::Elapsed)
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TimerQueue;
+
+    #[test]
+    fn expiry() {
+        let mut timers = TimerQueue::default();
+        assert_eq!(timers.next_deadline(), None);
+        assert!(!timers.fire());
+        let early = timers.add(1.0);
+        let _late = timers.add(3.0);
+        assert_eq!(early.deadline(), 1.0);
+        assert_eq!(timers.next_deadline(), Some(1.0));
+        assert!(timers.fire_at(0.5));
+        assert_eq!(timers.entries.len(), 2);
+        assert!(timers.fire_at(1.0));
+        assert_eq!(timers.entries.len(), 1);
+        assert_eq!(timers.next_deadline(), Some(3.0));
+        assert!(!timers.fire_at(3.0));
+        assert_eq!(timers.next_deadline(), None);
+    }
+
+    #[test]
+    fn cleanup() {
+        let mut timers = TimerQueue::default();
+        let first = timers.add(2.0);
+        let second = timers.add(1.0);
+        drop(second);
+        assert!(timers.fire_at(0.0));
+        assert_eq!(timers.entries.len(), 1);
+        assert_eq!(timers.next_deadline(), Some(2.0));
+        drop(first);
+        assert!(!timers.fire_at(0.0));
+        assert!(timers.entries.is_empty());
+    }
+
+    #[test]
+    fn close() {
+        let mut timers = TimerQueue::default();
+        let _timer = timers.add(1.0);
+        timers.close();
+        assert!(!timers.fire_at(2.0));
+    }
+}